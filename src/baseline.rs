@@ -0,0 +1,514 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+
+use path_slash::PathExt;
+
+use crate::config::Repository;
+use crate::fs::{self, Directory, File, Symlink};
+use crate::scan::DirectoryScanList;
+
+/// Marks the format of a baseline file.
+const FORMAT: &[u8] = b"git-repo-sync baseline 3";
+
+/// Marks the previous format of a baseline file, which lacks the permission bits of files and
+/// symlinks.
+const FORMAT_2: &[u8] = b"git-repo-sync baseline 2";
+
+/// Files larger than this (in bytes) are not stored for merging.
+const MAX_BLOB_SIZE: u64 = 1024 * 1024;
+
+/// Number of bytes at the start of a file that are checked for NUL bytes, which mark binary
+/// files (the same heuristic that git uses).
+const BINARY_CHECK_LEN: u64 = 8000;
+
+/// Snapshot of both ends of a two-way sync as they were right after the previous sync.
+///
+/// The snapshot is used as the common ancestor in the three-way comparison, to tell which end
+/// changed a file since the previous sync.
+#[derive(Debug, Clone, Default)]
+pub struct Baseline {
+    pub local: DirectoryScanList,
+    pub remote: DirectoryScanList,
+
+    /// Git object IDs of the file contents, used as common ancestor when merging conflicts.
+    pub blobs: std::collections::BTreeMap<std::path::PathBuf, String>,
+}
+
+impl Baseline {
+    pub fn new(local: DirectoryScanList, remote: DirectoryScanList) -> Self {
+        Baseline {
+            local,
+            remote,
+            blobs: Default::default(),
+        }
+    }
+
+    /// Replace the entries of a file on both ends, for example after it was merged.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - File as it is now present on both ends.
+    pub fn set_file(&mut self, file: File) {
+        for scan in [&mut self.local, &mut self.remote] {
+            let symlinks = scan.symlinks().to_vec();
+            let (directories, mut files) = std::mem::take(scan).into_parts();
+            files.retain(|other| other.path != file.path);
+            files.push(file.clone());
+            *scan = DirectoryScanList::new(directories, files).with_symlinks(symlinks);
+        }
+    }
+
+    /// Store the contents of the local files in the git object database, so that they can be
+    /// used as common ancestor for merging conflicts in the next sync.
+    ///
+    /// Only text files up to [`MAX_BLOB_SIZE`] are stored, since other files cannot be merged
+    /// anyway. Files that did not change since the previous baseline keep their object ID, so only
+    /// new and changed files are hashed. The objects are added to a private index next to the
+    /// baseline, and the tree of that index is kept in `refs/repo-sync/<name>`. That ref keeps the
+    /// objects of the latest sync alive, so `git gc` never prunes them (until the ref is removed
+    /// with `git update-ref -d`), while objects of earlier syncs become unreachable.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Path of local git directory.
+    /// * `path` - Path of baseline file.
+    /// * `previous` - Baseline of the previous sync.
+    pub fn record_blobs(
+        &mut self,
+        local_dir: &std::path::Path,
+        path: &std::path::Path,
+        previous: &Baseline,
+    ) -> Result<()> {
+        let Some(repository) = Repository::find(local_dir)? else {
+            return Err(anyhow::anyhow!(
+                "not a git repository: {}",
+                local_dir.display()
+            ));
+        };
+        // The index holds paths relative to the root of the repository.
+        let prefix = std::path::absolute(local_dir)
+            .context("failed to resolve local directory")?
+            .strip_prefix(&repository.root)
+            .unwrap()
+            .to_path_buf();
+        let previous_files = previous
+            .local
+            .files()
+            .iter()
+            .map(|file| (&file.path, file))
+            .collect::<std::collections::HashMap<_, _>>();
+        let mut index_info = Vec::new();
+        let mut unhashed = Vec::new();
+        for file in self.local.files() {
+            let previous_blob = previous_files
+                .get(&file.path)
+                .filter(|previous_file| !file.differs_from(previous_file))
+                .and_then(|_| previous.blobs.get(&file.path));
+            let repo_path = fs::path_to_bytes(&prefix.join(&file.path)).into_owned();
+            match previous_blob {
+                Some(blob) => {
+                    index_info.extend_from_slice(format!("100644 {blob}\t").as_bytes());
+                    index_info.extend_from_slice(&repo_path);
+                    index_info.push(0);
+                }
+                None if is_mergeable(&local_dir.join(&file.path), file.size) => {
+                    unhashed.extend_from_slice(&repo_path);
+                    unhashed.push(0);
+                }
+                None => {}
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("failed to create baseline directory")?;
+        }
+        let index_path = path.with_extension("index");
+        let _ = std::fs::remove_file(&index_path);
+        let git = |args: &[&str], stdin: Vec<u8>| {
+            git_with_index(&repository.root, &index_path, args, stdin)
+        };
+        let result = (|| -> Result<()> {
+            // Paths are separated by NUL, so that any file name can be passed.
+            git(&["update-index", "-z", "--index-info"], index_info)?;
+            git(&["update-index", "-z", "--add", "--stdin"], unhashed)?;
+            let entries = git(&["ls-files", "--stage", "-z"], Vec::new())?;
+            let tree = git(&["write-tree"], Vec::new())?;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            git(
+                &[
+                    "update-ref",
+                    &format!("refs/repo-sync/{name}"),
+                    String::from_utf8_lossy(&tree).trim(),
+                ],
+                Vec::new(),
+            )?;
+
+            // Every entry is `<mode> <object> <stage>\t<path>`.
+            let mut blobs = std::collections::BTreeMap::new();
+            for entry in entries.split(|&byte| byte == 0) {
+                let Some(tab) = entry.iter().position(|&byte| byte == b'\t') else {
+                    continue;
+                };
+                let blob = entry[..tab]
+                    .split(|&byte| byte == b' ')
+                    .nth(1)
+                    .ok_or_else(|| anyhow::anyhow!("malformed git ls-files output"))?;
+                if let Ok(path) = fs::path_from_bytes(&entry[tab + 1..]).strip_prefix(&prefix) {
+                    blobs.insert(
+                        path.to_path_buf(),
+                        String::from_utf8_lossy(blob).into_owned(),
+                    );
+                }
+            }
+            self.blobs = blobs;
+            Ok(())
+        })();
+        let _ = std::fs::remove_file(&index_path);
+        result.context("failed to store file contents for merging")
+    }
+
+    /// Get the path where the baseline for a remote is stored.
+    ///
+    /// Baselines are stored inside the git directory of the local repository, so they are never
+    /// synchronized themselves.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Path of local git directory.
+    /// * `remote` - Remote that the baseline belongs to.
+    pub fn path(
+        local_dir: &std::path::Path,
+        remote: impl std::fmt::Display,
+    ) -> Result<std::path::PathBuf> {
+        let output = std::process::Command::new("git")
+            .args([
+                "-C",
+                &local_dir.to_slash_lossy(),
+                "rev-parse",
+                "--absolute-git-dir",
+            ])
+            .stderr(std::process::Stdio::null())
+            .output()
+            .context("failed to run git command")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "two-way sync requires the local directory to be a git repository"
+            ));
+        }
+        let git_dir = std::path::PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+        let name = remote
+            .to_string()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        Ok(git_dir.join("repo-sync").join(name))
+    }
+
+    /// Load a baseline from disk. If there is no baseline yet (first sync), an empty baseline is
+    /// returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of baseline file.
+    pub fn load(path: &std::path::Path) -> Result<Baseline> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Baseline::default())
+            }
+            Err(err) => return Err(err).context("failed to read baseline"),
+        };
+        let mut rest = contents.as_slice();
+        let has_modes = match next_field(&mut rest).ok() {
+            Some(FORMAT) => true,
+            Some(FORMAT_2) => false,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "unknown baseline format (remove {} to start over)",
+                    path.display()
+                ))
+            }
+        };
+        let mut local = (Vec::new(), Vec::new(), Vec::new());
+        let mut remote = (Vec::new(), Vec::new(), Vec::new());
+        let mut blobs = std::collections::BTreeMap::new();
+        while !rest.is_empty() {
+            let (directories, files, symlinks) = match next_field(&mut rest)? {
+                b"b" => {
+                    let blob = String::from_utf8_lossy(next_field(&mut rest)?).into_owned();
+                    blobs.insert(fs::path_from_bytes(next_field(&mut rest)?), blob);
+                    continue;
+                }
+                b"l" => (&mut local.0, &mut local.1, &mut local.2),
+                b"r" => (&mut remote.0, &mut remote.1, &mut remote.2),
+                side => return Err(malformed(side)),
+            };
+            match next_field(&mut rest)? {
+                b"d" => {
+                    directories.push(Directory::new(fs::path_from_bytes(next_field(&mut rest)?)))
+                }
+                b"f" => {
+                    let size = next_field(&mut rest)?;
+                    let mtime = next_field(&mut rest)?;
+                    let checksum = next_field(&mut rest)?;
+                    let mode = if has_modes {
+                        next_field(&mut rest)?
+                    } else {
+                        b"-"
+                    };
+                    let mut file = File::new(
+                        fs::path_from_bytes(next_field(&mut rest)?),
+                        parse_number(size)?,
+                        parse_number(mtime)?,
+                    );
+                    if checksum != b"-" {
+                        file.checksum = Some(String::from_utf8_lossy(checksum).into_owned());
+                    }
+                    if mode != b"-" {
+                        file.mode = Some(
+                            std::str::from_utf8(mode)
+                                .ok()
+                                .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+                                .ok_or_else(|| malformed(mode))?,
+                        );
+                    }
+                    files.push(file);
+                }
+                b"s" => {
+                    let target = fs::path_from_bytes(next_field(&mut rest)?);
+                    symlinks.push(Symlink::new(
+                        fs::path_from_bytes(next_field(&mut rest)?),
+                        target,
+                    ));
+                }
+                kind => return Err(malformed(kind)),
+            }
+        }
+        Ok(Baseline {
+            local: DirectoryScanList::new(local.0, local.1).with_symlinks(local.2),
+            remote: DirectoryScanList::new(remote.0, remote.1).with_symlinks(remote.2),
+            blobs,
+        })
+    }
+
+    /// Save the baseline to disk.
+    ///
+    /// The baseline is a sequence of NUL-terminated fields, so that paths are stored as raw bytes
+    /// and any file name can be stored. After the format marker, every entry starts with a field
+    /// for the end it belongs to (`l` or `r`) and its type (`d` for a directory, `f` for a file
+    /// with its size, modification time, checksum and permission bits, and `s` for a symlink with
+    /// its target), or with `b` for the object ID of a file, and ends with the path.
+    ///
+    /// The baseline is first written to a temporary file that is then moved into place, so that
+    /// an interrupted save never leaves a truncated baseline behind.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of baseline file.
+    pub fn save(&self, path: &std::path::Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("failed to create baseline directory")?;
+        }
+        let temporary_path = path.with_extension("tmp");
+        let mut writer = std::io::BufWriter::new(
+            std::fs::File::create(&temporary_path).context("failed to create baseline")?,
+        );
+        write_fields(&mut writer, &[FORMAT])?;
+        for (side, scan) in [(b"l", &self.local), (b"r", &self.remote)] {
+            for directory in scan.directories() {
+                write_fields(
+                    &mut writer,
+                    &[side, b"d", &fs::path_to_bytes(&directory.path)],
+                )?;
+            }
+            for file in scan.files() {
+                write_fields(
+                    &mut writer,
+                    &[
+                        side,
+                        b"f",
+                        file.size.to_string().as_bytes(),
+                        file.mtime.to_string().as_bytes(),
+                        file.checksum.as_deref().unwrap_or("-").as_bytes(),
+                        file.mode
+                            .map(|mode| format!("{mode:o}"))
+                            .as_deref()
+                            .unwrap_or("-")
+                            .as_bytes(),
+                        &fs::path_to_bytes(&file.path),
+                    ],
+                )?;
+            }
+            for symlink in scan.symlinks() {
+                write_fields(
+                    &mut writer,
+                    &[
+                        side,
+                        b"s",
+                        &fs::path_to_bytes(&symlink.target),
+                        &fs::path_to_bytes(&symlink.path),
+                    ],
+                )?;
+            }
+        }
+        for (path, blob) in &self.blobs {
+            write_fields(
+                &mut writer,
+                &[b"b", blob.as_bytes(), &fs::path_to_bytes(path)],
+            )?;
+        }
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("failed to write baseline")?;
+        std::fs::rename(&temporary_path, path).context("failed to save baseline")
+    }
+}
+
+/// Run a git command in a repository with a private index, and return its output.
+///
+/// # Arguments
+///
+/// * `root` - Path of root of repository.
+/// * `index_path` - Path of index file.
+/// * `args` - Arguments of git command.
+/// * `stdin` - Input for git command.
+fn git_with_index(
+    root: &std::path::Path,
+    index_path: &std::path::Path,
+    args: &[&str],
+    stdin: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let mut process = std::process::Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(args)
+        .env("GIT_INDEX_FILE", index_path)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("failed to spawn git command")?;
+    // The input is written on a separate thread, so that neither pipe can fill up and block git.
+    let mut process_stdin = process.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || process_stdin.write_all(&stdin));
+    let output = process
+        .wait_with_output()
+        .context("failed to run git command")?;
+    writer
+        .join()
+        .unwrap()
+        .context("failed to write to git command")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Write NUL-terminated fields of a baseline entry.
+fn write_fields(writer: &mut impl Write, fields: &[&[u8]]) -> Result<()> {
+    for field in fields {
+        writer
+            .write_all(field)
+            .and_then(|()| writer.write_all(&[0]))
+            .context("failed to write baseline")?;
+    }
+    Ok(())
+}
+
+/// Split the next NUL-terminated field off a baseline.
+fn next_field<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let end = rest
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or_else(|| anyhow::anyhow!("malformed baseline (missing field)"))?;
+    let field = &rest[..end];
+    *rest = &rest[end + 1..];
+    Ok(field)
+}
+
+/// Whether or not a file is small enough and looks like text, so that it can be merged.
+///
+/// # Arguments
+///
+/// * `path` - Path of file.
+/// * `size` - Size of file in bytes.
+fn is_mergeable(path: &std::path::Path, size: u64) -> bool {
+    if size > MAX_BLOB_SIZE {
+        return false;
+    }
+    let mut head = Vec::new();
+    match std::fs::File::open(path)
+        .and_then(|file| file.take(BINARY_CHECK_LEN).read_to_end(&mut head))
+    {
+        Ok(_) => !head.contains(&0),
+        // Files that cannot be read are left to `git update-index`, which reports the error.
+        Err(_) => true,
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(field: &[u8]) -> Result<T> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| malformed(field))
+}
+
+fn malformed(field: &[u8]) -> anyhow::Error {
+    anyhow::anyhow!(
+        "malformed baseline field: {}",
+        String::from_utf8_lossy(field)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!(
+            "git-repo-sync-baseline-test-{}",
+            std::process::id()
+        ));
+        let mut file = File::new("dir/line\nbreak \\ file".into(), 12, -1);
+        file.checksum = Some("0123".to_string());
+        file.mode = Some(0o755);
+        let symlink = Symlink::new("dir/link".into(), "../a b".into());
+        let mut baseline = Baseline::new(
+            DirectoryScanList::new(vec![Directory::new("dir".into())], vec![file.clone()])
+                .with_symlinks(vec![symlink.clone()]),
+            DirectoryScanList::new(Vec::new(), vec![File::new("a b".into(), 0, 1700000000)]),
+        );
+        baseline
+            .blobs
+            .insert("dir/line\nbreak \\ file".into(), "abcd".to_string());
+        baseline.save(&path).unwrap();
+        let loaded = Baseline::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.local.directories(), baseline.local.directories());
+        assert_eq!(loaded.local.files(), &[file]);
+        assert_eq!(loaded.local.symlinks(), &[symlink]);
+        assert_eq!(loaded.remote.files(), baseline.remote.files());
+        assert_eq!(loaded.blobs, baseline.blobs);
+    }
+
+    #[test]
+    fn test_is_mergeable() {
+        let path = std::env::temp_dir().join(format!(
+            "git-repo-sync-mergeable-test-{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, "text\n").unwrap();
+        assert!(is_mergeable(&path, 5));
+        assert!(!is_mergeable(&path, MAX_BLOB_SIZE + 1));
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        assert!(!is_mergeable(&path, 16));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
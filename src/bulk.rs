@@ -0,0 +1,242 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+
+use path_slash::PathExt;
+
+use crate::config::Repository;
+use crate::transport::ssh::shell_quote;
+use crate::transport::Transport;
+
+/// Prefix of the name of the staging directory that an archive is unpacked in.
+const STAGING_PREFIX: &str = ".repo-sync-bulk.";
+
+/// Options for bulk transfers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BulkOptions {
+    /// Files are transferred in bulk when more than this number of files must be copied.
+    pub threshold: usize,
+
+    /// Whether or not to compress the stream with zstd.
+    pub compress: bool,
+}
+
+impl BulkOptions {
+    /// Whether or not a number of files should be transferred in bulk.
+    ///
+    /// # Arguments
+    ///
+    /// * `count` - Number of files to copy.
+    pub fn applies_to(&self, count: usize) -> bool {
+        count > self.threshold
+    }
+
+    /// Extra `tar` arguments for the compression options.
+    fn tar_args(&self) -> &'static [&'static str] {
+        if self.compress {
+            &["--zstd"]
+        } else {
+            &[]
+        }
+    }
+}
+
+/// Upload files as a single `tar` stream, which is unpacked on the other end.
+///
+/// Modification times and permissions are preserved. The directories of the files must already exist on the other
+/// end.
+///
+/// As with single file transfers, the files are replaced atomically: the archive is unpacked in a
+/// staging directory inside the directory on the other end, and every file is then moved into
+/// place.
+///
+/// # Arguments
+///
+/// * `transport` - Transport to the other end.
+/// * `options` - Bulk transfer options.
+/// * `local_dir` - Path of local directory that the files are relative to.
+/// * `dir` - Path of directory on the other end to unpack the files in.
+/// * `files` - Relative paths of files to upload.
+pub fn put(
+    transport: &mut dyn Transport,
+    options: &BulkOptions,
+    local_dir: &std::path::Path,
+    dir: &std::path::Path,
+    files: &[&std::path::Path],
+) -> Result<()> {
+    let mut tar_process = std::process::Command::new("tar")
+        // Symlinks are only copied as files when they are followed, so they are dereferenced.
+        .args(["-c", "-h", "-f", "-"])
+        .args(options.tar_args())
+        .arg("-C")
+        .arg(local_dir)
+        .args(["--null", "-T", "-"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("failed to spawn tar command")?;
+    let stderr = read_stderr(&mut tar_process);
+    let writer = write_file_list(tar_process.stdin.take().unwrap(), files);
+    let mut tar_stdout = tar_process.stdout.take().unwrap();
+    // The staging directory is removed when the command exits, whether or not it succeeded.
+    let result = transport.exec(
+        &format!(
+            "cd {} && staging=$(mktemp -d \"$PWD/{STAGING_PREFIX}XXXXXX\") && trap 'rm -rf \"$staging\"' EXIT && tar -x -p -f - {} --no-same-owner -C \"$staging\" && cd \"$staging\" && find . -type f -exec sh -c 'for file; do mv -f \"$file\" \"../$file\" || exit 1; done' sh {{}} +",
+            shell_quote(&dir.to_slash_lossy()),
+            options.tar_args().join(" "),
+        ),
+        &mut tar_stdout,
+        &mut std::io::sink(),
+    );
+    // Closing stdout makes the process stop if the command on the other end failed early.
+    drop(tar_stdout);
+    let written = writer
+        .join()
+        .unwrap()
+        .context("failed to write to tar process");
+    result.and(finish_tar(tar_process, stderr)).and(written)
+}
+
+/// Download files as a single `tar` stream, which is unpacked locally.
+///
+/// Modification times and permissions are preserved. The directories of the files must already exist locally.
+///
+/// As with single file transfers, the files are replaced atomically: the archive is unpacked in a
+/// staging directory, and every file is then moved into place. The staging directory is inside
+/// the git directory (on the same file system as the local directory), so that it is never
+/// synchronized itself when a run is interrupted before it is removed.
+///
+/// # Arguments
+///
+/// * `transport` - Transport to the other end.
+/// * `options` - Bulk transfer options.
+/// * `dir` - Path of directory on the other end that the files are relative to.
+/// * `local_dir` - Path of local directory to unpack the files in.
+/// * `files` - Relative paths of files to download.
+pub fn get(
+    transport: &mut dyn Transport,
+    options: &BulkOptions,
+    dir: &std::path::Path,
+    local_dir: &std::path::Path,
+    files: &[&std::path::Path],
+) -> Result<()> {
+    let staging_parent = match Repository::find(local_dir)? {
+        Some(repository) => repository.git_dir.join("repo-sync"),
+        None => local_dir.to_path_buf(),
+    };
+    let staging_dir = staging_parent.join(format!("{STAGING_PREFIX}{}", std::process::id()));
+    // A previous run with the same process ID might have been interrupted.
+    let _ = std::fs::remove_dir_all(&staging_dir);
+    std::fs::create_dir_all(&staging_dir).context("failed to create staging directory")?;
+    let result = get_staged(transport, options, dir, &staging_dir, files).and_then(|()| {
+        for file in files {
+            std::fs::rename(staging_dir.join(file), local_dir.join(file))
+                .context("failed to rename file")?;
+        }
+        Ok(())
+    });
+    let _ = std::fs::remove_dir_all(&staging_dir);
+    result
+}
+
+/// Download files as a single `tar` stream, and unpack them in a staging directory.
+///
+/// # Arguments
+///
+/// * `transport` - Transport to the other end.
+/// * `options` - Bulk transfer options.
+/// * `dir` - Path of directory on the other end that the files are relative to.
+/// * `staging_dir` - Path of local directory to unpack the files in.
+/// * `files` - Relative paths of files to download.
+fn get_staged(
+    transport: &mut dyn Transport,
+    options: &BulkOptions,
+    dir: &std::path::Path,
+    staging_dir: &std::path::Path,
+    files: &[&std::path::Path],
+) -> Result<()> {
+    let mut tar_process = std::process::Command::new("tar")
+        .args(["-x", "-p", "-f", "-"])
+        .args(options.tar_args())
+        .arg("--no-same-owner")
+        .arg("-C")
+        .arg(staging_dir)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("failed to spawn tar command")?;
+    let stderr = read_stderr(&mut tar_process);
+    let mut file_list = Vec::new();
+    for file in files {
+        file_list.extend_from_slice(&crate::fs::path_to_bytes(file));
+        file_list.push(0);
+    }
+    let mut tar_stdin = tar_process.stdin.take().unwrap();
+    let result = transport.exec(
+        &format!(
+            "tar -c -h -f - {} -C {} --null -T -",
+            options.tar_args().join(" "),
+            shell_quote(&dir.to_slash_lossy()),
+        ),
+        &mut file_list.as_slice(),
+        &mut tar_stdin,
+    );
+    // Closing stdin ends the archive.
+    drop(tar_stdin);
+    result.and(finish_tar(tar_process, stderr))
+}
+
+/// Write a NUL-separated list of paths to the stdin of a `tar` process on a separate thread, so
+/// that the list and the archive can be streamed at the same time.
+fn write_file_list(
+    mut stdin: std::process::ChildStdin,
+    files: &[&std::path::Path],
+) -> std::thread::JoinHandle<std::io::Result<()>> {
+    let files = files
+        .iter()
+        .map(|file| crate::fs::path_to_bytes(file).into_owned())
+        .collect::<Vec<_>>();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::BufWriter::new(&mut stdin);
+        for file in files {
+            stdin.write_all(&file)?;
+            stdin.write_all(&[0])?;
+        }
+        stdin.flush()
+    })
+}
+
+/// Read the error output of a local `tar` process on a separate thread, so that the process never
+/// blocks on a full stderr pipe while the archive is streamed.
+fn read_stderr(tar_process: &mut std::process::Child) -> std::thread::JoinHandle<String> {
+    let mut tar_stderr = tar_process.stderr.take().unwrap();
+    std::thread::spawn(move || {
+        let mut stderr = String::new();
+        let _ = tar_stderr.read_to_string(&mut stderr);
+        stderr
+    })
+}
+
+/// Wait for a local `tar` process to exit, and turn a failure into an error.
+///
+/// # Arguments
+///
+/// * `tar_process` - Process to wait for.
+/// * `stderr` - Thread that reads the error output of the process (see [`read_stderr`]).
+fn finish_tar(
+    mut tar_process: std::process::Child,
+    stderr: std::thread::JoinHandle<String>,
+) -> Result<()> {
+    let exit_status = tar_process.wait().context("failed to run tar command")?;
+    let stderr = stderr.join().unwrap_or_default();
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "tar failed: {exit_status}: {}",
+            stderr.trim()
+        ))
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+
+/// Remote defined by name in the git config, in a `[repo-sync "<name>"]` section:
+///
+/// ```text
+/// [repo-sync "build"]
+///     remote = user@build-box-03:work/project
+///     checksum = true
+/// ```
+///
+/// The options in the section are enabled in addition to the flags on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct NamedRemote {
+    /// Remote as it would be given on the command line (`host:dir`, or a local directory).
+    pub remote: String,
+    pub checksum: bool,
+    pub follow_symlinks: bool,
+    pub tracked_only: bool,
+    pub delta: bool,
+    pub no_bulk: bool,
+    pub compress: bool,
+    pub native_ssh: bool,
+    pub no_remote_helper: bool,
+}
+
+impl NamedRemote {
+    /// Look up a named remote in the git config of the local directory (which includes the global
+    /// git config). Returns `None` if there is no remote with the name.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Local directory.
+    /// * `name` - Name of remote.
+    pub fn load(local_dir: &std::path::Path, name: &str) -> Result<Option<NamedRemote>> {
+        let entries = git_config_entries(local_dir)?;
+
+        let mut found = false;
+        let mut remote = None;
+        let mut named_remote = NamedRemote::default();
+        for (key, value) in &entries {
+            let value = value.as_deref();
+            // The section and variable names are case-insensitive (and converted to lowercase), but
+            // the name of the remote is not.
+            let Some((section_name, variable)) = key
+                .strip_prefix("repo-sync.")
+                .and_then(|key| key.rsplit_once('.'))
+            else {
+                continue;
+            };
+            if section_name != name {
+                continue;
+            }
+            found = true;
+            let flag = match variable {
+                "remote" => {
+                    remote = value.map(str::to_string);
+                    continue;
+                }
+                "checksum" => &mut named_remote.checksum,
+                "followsymlinks" => &mut named_remote.follow_symlinks,
+                "trackedonly" => &mut named_remote.tracked_only,
+                "delta" => &mut named_remote.delta,
+                "nobulk" => &mut named_remote.no_bulk,
+                "compress" => &mut named_remote.compress,
+                "nativessh" => &mut named_remote.native_ssh,
+                "noremotehelper" => &mut named_remote.no_remote_helper,
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "unknown option in git config: repo-sync.{name}.{variable}"
+                    ))
+                }
+            };
+            *flag = parse_bool(value).with_context(|| {
+                format!("invalid value in git config: repo-sync.{name}.{variable}")
+            })?;
+        }
+
+        match remote {
+            Some(remote) => {
+                named_remote.remote = remote;
+                Ok(Some(named_remote))
+            }
+            None if !found => Ok(None),
+            None => Err(anyhow::anyhow!(
+                "missing remote in git config: repo-sync.{name}.remote"
+            )),
+        }
+    }
+}
+
+/// Read the `repo-sync` variables of the git config of a local directory with `git config`.
+///
+/// # Arguments
+///
+/// * `local_dir` - Local directory.
+fn git_config_entries(local_dir: &std::path::Path) -> Result<Vec<(String, Option<String>)>> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(local_dir)
+        .args([
+            "config",
+            // Separate entries by NUL, and keys from values by a newline, so that any value can be
+            // read.
+            "-z",
+            "--get-regexp",
+            r"^repo-sync\.",
+        ])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .context("failed to run git command")?;
+    // XXX: `git config` exits with 1 when there are no matching entries, which is not an error
+    // here.
+    if !output.status.success() && output.status.code() != Some(1) {
+        return Err(anyhow::anyhow!("failed to read git config"));
+    }
+    Ok(output
+        .stdout
+        .split(|&c| c == b'\0')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            // An entry without a value (such as `checksum` on a line of its own) is a boolean that
+            // is set to true.
+            match entry.split_once('\n') {
+                Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                None => (entry.into_owned(), None),
+            }
+        })
+        .collect())
+}
+
+/// Read a path variable of the git config of a local directory with `git config`, which expands
+/// `~/` to the home directory. Returns `None` if the variable is not set.
+///
+/// # Arguments
+///
+/// * `local_dir` - Local directory.
+/// * `key` - Key of variable, such as `core.excludesFile`.
+pub fn git_config_path(
+    local_dir: &std::path::Path,
+    key: &str,
+) -> Result<Option<std::path::PathBuf>> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(local_dir)
+        .args(["config", "-z", "--path", "--get", key])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .context("failed to run git command")?;
+    // XXX: `git config` exits with 1 when the variable is not set, which is not an error here.
+    if output.status.code() == Some(1) {
+        return Ok(None);
+    }
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("failed to read git config: {key}"));
+    }
+    let value = output.stdout.strip_suffix(b"\0").unwrap_or(&output.stdout);
+    Ok(Some(crate::fs::path_from_bytes(value)))
+}
+
+/// Parse a boolean value the way git does.
+///
+/// # Arguments
+///
+/// * `value` - Value, or `None` for a variable without a value.
+fn parse_bool(value: Option<&str>) -> Result<bool> {
+    match value.map(str::to_ascii_lowercase).as_deref() {
+        None | Some("true" | "yes" | "on" | "1") => Ok(true),
+        Some("false" | "no" | "off" | "0" | "") => Ok(false),
+        Some(value) => Err(anyhow::anyhow!("not a boolean: {value}")),
+    }
+}
+
+/// Location of the git repository that a local directory is in.
+#[derive(Debug, Clone)]
+pub struct Repository {
+    /// Root of the working tree.
+    pub root: std::path::PathBuf,
+    /// Git directory of the working tree.
+    pub git_dir: std::path::PathBuf,
+    /// Directory with the parts that are shared by all worktrees (such as `config` and
+    /// `info/exclude`), which is the git directory unless the working tree is a linked worktree.
+    pub common_dir: std::path::PathBuf,
+}
+
+impl Repository {
+    /// Find the repository that a local directory is in, by looking for `.git` in the directory
+    /// and its parent directories. Returns `None` if the directory is not in a git repository.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Path of local directory.
+    pub fn find(local_dir: &std::path::Path) -> Result<Option<Repository>> {
+        let absolute_local_dir =
+            std::path::absolute(local_dir).context("failed to resolve local directory")?;
+        let Some(root) = absolute_local_dir
+            .ancestors()
+            .find(|ancestor| ancestor.join(".git").exists())
+        else {
+            return Ok(None);
+        };
+        let dot_git = root.join(".git");
+        if dot_git.is_dir() {
+            return Ok(Some(Repository {
+                root: root.to_path_buf(),
+                git_dir: dot_git.clone(),
+                common_dir: dot_git,
+            }));
+        }
+        // Worktrees and submodules have a `.git` file that points to the git directory instead.
+        let contents = std::fs::read_to_string(&dot_git).context("failed to read .git file")?;
+        let Some(git_dir) = contents.trim_end().strip_prefix("gitdir: ") else {
+            return Err(anyhow::anyhow!("malformed .git file"));
+        };
+        let git_dir = root.join(git_dir);
+        let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common_dir) => git_dir.join(common_dir.trim_end()),
+            Err(_) => git_dir.clone(),
+        };
+        Ok(Some(Repository {
+            root: root.to_path_buf(),
+            git_dir,
+            common_dir,
+        }))
+    }
+}
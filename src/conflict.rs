@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+
+use crate::transport::Transport;
+
+/// How to resolve a file that changed on both ends since the previous two-way sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Leave both versions untouched and report the conflict.
+    Skip,
+    /// Overwrite the remote version with the local version.
+    PreferLocal,
+    /// Overwrite the local version with the remote version.
+    PreferRemote,
+    /// Keep the local version, and keep the remote version as `<file>.conflict-<host>` on both
+    /// ends.
+    KeepBoth,
+    /// Abort the sync without changing anything.
+    Abort,
+    /// Three-way merge text files with `git merge-file`, and skip files that cannot be merged.
+    Merge,
+}
+
+impl std::fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::PreferLocal => "prefer local",
+            ConflictPolicy::PreferRemote => "prefer remote",
+            ConflictPolicy::KeepBoth => "keep both",
+            ConflictPolicy::Abort => "abort",
+            ConflictPolicy::Merge => "merge",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// File that changed on both ends since the previous two-way sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// Relative path.
+    pub path: std::path::PathBuf,
+
+    /// Policy that resolves the conflict.
+    pub policy: ConflictPolicy,
+}
+
+impl Conflict {
+    pub fn new(path: std::path::PathBuf, policy: ConflictPolicy) -> Self {
+        Conflict { path, policy }
+    }
+}
+
+/// Outcome of a three-way merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// Both versions were merged without conflicts.
+    Clean,
+    /// Both versions were merged, but the result contains conflict markers for the given number of
+    /// conflicting hunks.
+    Conflicted(usize),
+    /// The file could not be merged (for example, because it is binary) and was left untouched.
+    Unmergeable,
+}
+
+/// Three-way merge the remote version of a file into the local version, and upload the result.
+///
+/// The common ancestor is read from the git object database, where it was stored by
+/// [`crate::baseline::Baseline::record_blobs`] after the previous sync.
+///
+/// # Arguments
+///
+/// * `local_dir` - Path of local git directory.
+/// * `remote_dir` - Path of directory on the remote end.
+/// * `scratch_dir` - Local directory for temporary files.
+/// * `path` - Relative path of conflicting file.
+/// * `base_blob` - Git object ID of the common ancestor.
+/// * `remote_name` - Name of the remote end to use in conflict markers.
+/// * `transport` - Transport to the remote end.
+pub fn merge(
+    local_dir: &std::path::Path,
+    remote_dir: &std::path::Path,
+    scratch_dir: &std::path::Path,
+    path: &std::path::Path,
+    base_blob: &str,
+    remote_name: &str,
+    transport: &mut dyn Transport,
+) -> Result<MergeOutcome> {
+    std::fs::create_dir_all(scratch_dir).context("failed to create scratch directory")?;
+    let base_path = scratch_dir.join("base");
+    let remote_path = scratch_dir.join("remote");
+
+    let base = std::process::Command::new("git")
+        .arg("-C")
+        .arg(local_dir)
+        .args(["cat-file", "blob", base_blob])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .context("failed to run git command")?;
+    if !base.status.success() {
+        return Ok(MergeOutcome::Unmergeable);
+    }
+    std::fs::write(&base_path, base.stdout).context("failed to write merge base")?;
+    transport.get(&remote_dir.join(path), &remote_path)?;
+    transport.flush()?;
+
+    let local_path = local_dir.join(path);
+    let exit_status = std::process::Command::new("git")
+        .arg("merge-file")
+        .args(["-L", "local", "-L", "base", "-L", remote_name])
+        .args([&local_path, &base_path, &remote_path])
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .context("failed to run git merge-file")?;
+    let outcome = match exit_status.code() {
+        Some(0) => MergeOutcome::Clean,
+        // `git merge-file` returns the number of conflicts, or a negative number (which shows up
+        // as 255 or more) on errors such as binary input.
+        Some(conflicts @ 1..=127) => MergeOutcome::Conflicted(conflicts as usize),
+        _ => MergeOutcome::Unmergeable,
+    };
+    if outcome != MergeOutcome::Unmergeable {
+        transport.put(&local_path, &remote_dir.join(path))?;
+        transport.flush()?;
+    }
+    Ok(outcome)
+}
@@ -0,0 +1,710 @@
+use std::io::{Read, Seek, Write};
+
+use anyhow::{Context, Result};
+
+use sha2::Digest;
+
+use crate::transport::Transport;
+
+/// Identifies a serialized signature.
+const SIGNATURE_MAGIC: &[u8; 4] = b"GRSS";
+
+/// Identifies a serialized delta.
+const DELTA_MAGIC: &[u8; 4] = b"GRSD";
+
+/// Delta operation: copy a block from the basis file.
+const OP_COPY: u8 = 1;
+
+/// Delta operation: literal data.
+const OP_DATA: u8 = 2;
+
+/// Delta operation: end of delta, followed by the digest, modification time and permission bits of
+/// the result.
+const OP_END: u8 = 0;
+
+/// Maximum amount of literal data that is buffered before it is written out.
+const MAX_LITERAL: usize = 1024 * 1024;
+
+/// Permission bits in a delta when the permissions of the new file are unknown.
+const UNKNOWN_MODE: u32 = u32::MAX;
+
+/// Length of the strong block checksum (a truncated SHA-256 digest).
+const STRONG_LEN: usize = 16;
+
+/// Options for delta transfers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaOptions {
+    /// Files of at least this size (in bytes) are transferred as a delta.
+    pub threshold: u64,
+
+    /// Command that runs `git-repo-sync` on the other end.
+    pub helper: String,
+}
+
+impl DeltaOptions {
+    /// Whether or not a file should be transferred as a delta, which is the case when the local
+    /// version (the source of an upload or the basis of a download) is large enough.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_path` - Path of local file.
+    pub fn applies_to(&self, local_path: &std::path::Path) -> bool {
+        // XXX: The path is passed to the helper on the other end in a command, which must be valid
+        // UTF-8.
+        if local_path.to_str().is_none() {
+            return false;
+        }
+        std::fs::metadata(local_path)
+            .map(|metadata| metadata.len() >= self.threshold)
+            .unwrap_or(false)
+    }
+}
+
+/// Block checksums of a basis file, which the other end uses to find the blocks that it does not
+/// need to send.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    block_size: usize,
+    blocks: Vec<(u32, [u8; STRONG_LEN])>,
+}
+
+impl Signature {
+    /// Compute the signature of a basis file. A missing file has an empty signature.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of basis file.
+    pub fn from_file(path: &std::path::Path) -> Result<Signature> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Signature {
+                    block_size: block_size(0),
+                    blocks: Vec::new(),
+                })
+            }
+            Err(err) => return Err(err).context("failed to open file"),
+        };
+        let len = file
+            .metadata()
+            .context("failed to fetch file metadata")?
+            .len();
+        let block_size = block_size(len);
+        let mut reader = std::io::BufReader::new(file);
+        let mut blocks = Vec::new();
+        let mut block = vec![0; block_size];
+        loop {
+            match reader.read_exact(&mut block) {
+                Ok(()) => blocks.push((weak_checksum(&block), strong_checksum(&block))),
+                // The trailing partial block is never matched, so it is left out.
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err).context("failed to read file"),
+            }
+        }
+        Ok(Signature { block_size, blocks })
+    }
+
+    /// Read a signature that was written with [`Signature::write`].
+    pub fn read(reader: &mut impl Read) -> Result<Signature> {
+        let mut magic = [0; 4];
+        reader
+            .read_exact(&mut magic)
+            .context("failed to read signature")?;
+        if &magic != SIGNATURE_MAGIC {
+            return Err(anyhow::anyhow!("malformed signature"));
+        }
+        let block_size = read_u32(reader)? as usize;
+        let count = read_u32(reader)?;
+        let mut blocks = Vec::new();
+        for _ in 0..count {
+            let weak = read_u32(reader)?;
+            let mut strong = [0; STRONG_LEN];
+            reader
+                .read_exact(&mut strong)
+                .context("failed to read signature")?;
+            blocks.push((weak, strong));
+        }
+        Ok(Signature { block_size, blocks })
+    }
+
+    /// Write the signature in binary form.
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(SIGNATURE_MAGIC)?;
+        writer.write_all(&(self.block_size as u32).to_be_bytes())?;
+        writer.write_all(&(self.blocks.len() as u32).to_be_bytes())?;
+        for (weak, strong) in &self.blocks {
+            writer.write_all(&weak.to_be_bytes())?;
+            writer.write_all(strong)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compute the delta that turns the basis file (described by its signature) into a new file.
+///
+/// The delta ends with the SHA-256 digest of the new file, which is verified when the delta is
+/// applied, and the modification time and permissions that the result should get.
+///
+/// # Arguments
+///
+/// * `signature` - Signature of the basis file.
+/// * `path` - Path of new file.
+/// * `writer` - Writer to write the delta to.
+pub fn compute(
+    signature: &Signature,
+    path: &std::path::Path,
+    writer: &mut impl Write,
+) -> Result<()> {
+    let mut file = std::fs::File::open(path).context("failed to open file")?;
+    let metadata = file.metadata().context("failed to fetch file metadata")?;
+    let mtime = crate::fs::mtime(&metadata)?;
+    let mode = crate::fs::mode(&metadata).unwrap_or(UNKNOWN_MODE);
+    let mut lookup = std::collections::HashMap::<u32, Vec<usize>>::new();
+    for (index, (weak, _)) in signature.blocks.iter().enumerate() {
+        lookup.entry(*weak).or_default().push(index);
+    }
+    let block_size = signature.block_size;
+
+    writer.write_all(DELTA_MAGIC)?;
+    writer.write_all(&(block_size as u32).to_be_bytes())?;
+    let mut hasher = sha2::Sha256::new();
+    let mut chunk = vec![0; 64 * 1024];
+    let mut read_chunk = |buffer: &mut Vec<u8>| -> Result<bool> {
+        let n = file.read(&mut chunk).context("failed to read file")?;
+        hasher.update(&chunk[..n]);
+        buffer.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    };
+    // Everything in the buffer before the window is literal data that has not been written yet.
+    let mut buffer = Vec::new();
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    while !signature.blocks.is_empty() {
+        // Make sure the window and the byte after it are available.
+        while buffer.len() <= pos + block_size && !eof {
+            eof = !read_chunk(&mut buffer)?;
+        }
+        if buffer.len() < pos + block_size {
+            break;
+        }
+        let window = &buffer[pos..pos + block_size];
+        let checksum = rolling.get_or_insert_with(|| Rolling::new(window));
+        let matched = lookup.get(&checksum.digest()).and_then(|indices| {
+            let strong = strong_checksum(window);
+            indices
+                .iter()
+                .find(|&&index| signature.blocks[index].1 == strong)
+        });
+        if let Some(&index) = matched {
+            write_data(writer, &buffer[..pos])?;
+            writer.write_all(&[OP_COPY])?;
+            writer.write_all(&(index as u32).to_be_bytes())?;
+            buffer.drain(..pos + block_size);
+            pos = 0;
+            rolling = None;
+        } else if buffer.len() > pos + block_size {
+            checksum.roll(buffer[pos], buffer[pos + block_size], block_size);
+            pos += 1;
+            if pos >= MAX_LITERAL {
+                write_data(writer, &buffer[..pos])?;
+                buffer.drain(..pos);
+                pos = 0;
+            }
+        } else {
+            break;
+        }
+    }
+    // No more blocks can match, so the rest of the file is literal data.
+    loop {
+        for data in buffer.chunks(MAX_LITERAL) {
+            write_data(writer, data)?;
+        }
+        buffer.clear();
+        if eof {
+            break;
+        }
+        while buffer.len() < MAX_LITERAL && !eof {
+            eof = !read_chunk(&mut buffer)?;
+        }
+    }
+    writer.write_all(&[OP_END])?;
+    writer.write_all(&hasher.finalize())?;
+    writer.write_all(&mtime.to_be_bytes())?;
+    writer.write_all(&mode.to_be_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Apply a delta to a basis file, and replace the basis file with the result.
+///
+/// The result is written to a temporary file next to the basis file first, and only moved into
+/// place after its digest was verified.
+///
+/// # Arguments
+///
+/// * `path` - Path of basis file (which may not exist).
+/// * `reader` - Reader to read the delta from.
+pub fn apply(path: &std::path::Path, reader: &mut impl Read) -> Result<()> {
+    let mut magic = [0; 4];
+    reader
+        .read_exact(&mut magic)
+        .context("failed to read delta")?;
+    if &magic != DELTA_MAGIC {
+        return Err(anyhow::anyhow!("malformed delta"));
+    }
+    let block_size = read_u32(reader)? as usize;
+    let mut basis = match std::fs::File::open(path) {
+        Ok(file) => Some(file),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err).context("failed to open file"),
+    };
+    let temporary_path = crate::fs::temporary_path(path);
+    let output = std::fs::File::create(&temporary_path).context("failed to create file")?;
+    if let Some(basis) = &basis {
+        // The result replaces the basis file, so it keeps its permissions unless the delta
+        // specifies other permissions.
+        let permissions = basis
+            .metadata()
+            .context("failed to fetch file metadata")?
+            .permissions();
+        output
+            .set_permissions(permissions)
+            .context("failed to set file permissions")?;
+    }
+    let mut output = std::io::BufWriter::new(output);
+    let mut hasher = sha2::Sha256::new();
+    let mut block = vec![0; block_size];
+    let result = (|| -> Result<(i64, u32)> {
+        loop {
+            let mut op = [0];
+            reader.read_exact(&mut op).context("failed to read delta")?;
+            match op[0] {
+                OP_COPY => {
+                    let index = read_u32(reader)? as u64;
+                    let basis = basis
+                        .as_mut()
+                        .ok_or_else(|| anyhow::anyhow!("delta refers to missing basis file"))?;
+                    basis
+                        .seek(std::io::SeekFrom::Start(index * block_size as u64))
+                        .context("failed to seek in file")?;
+                    basis
+                        .read_exact(&mut block)
+                        .context("failed to read file")?;
+                    hasher.update(&block);
+                    output.write_all(&block).context("failed to write file")?;
+                }
+                OP_DATA => {
+                    let len = read_u32(reader)? as usize;
+                    if len > MAX_LITERAL {
+                        return Err(anyhow::anyhow!("malformed delta"));
+                    }
+                    let mut data = vec![0; len];
+                    reader
+                        .read_exact(&mut data)
+                        .context("failed to read delta")?;
+                    hasher.update(&data);
+                    output.write_all(&data).context("failed to write file")?;
+                }
+                OP_END => {
+                    let mut digest = [0; 32];
+                    reader
+                        .read_exact(&mut digest)
+                        .context("failed to read delta")?;
+                    let mut mtime = [0; 8];
+                    reader
+                        .read_exact(&mut mtime)
+                        .context("failed to read delta")?;
+                    let mode = read_u32(reader)?;
+                    if hasher.finalize().as_slice() != digest {
+                        return Err(anyhow::anyhow!("delta result does not match digest"));
+                    }
+                    return Ok((i64::from_be_bytes(mtime), mode));
+                }
+                _ => return Err(anyhow::anyhow!("malformed delta")),
+            }
+        }
+    })();
+    let result = result.and_then(|(mtime, mode)| {
+        let output = output
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("failed to write file")?;
+        output
+            .set_modified(crate::fs::system_time(mtime)?)
+            .context("failed to set file modification time")?;
+        drop(output);
+        if mode != UNKNOWN_MODE {
+            crate::fs::set_mode(&temporary_path, mode)?;
+        }
+        std::fs::rename(&temporary_path, path).context("failed to rename file")
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+/// Upload a file as a delta against the version on the other end.
+///
+/// The other end must be able to run `git-repo-sync` (the helper).
+///
+/// # Arguments
+///
+/// * `transport` - Transport to the other end.
+/// * `helper` - Command that runs `git-repo-sync` on the other end, unless the transport provides
+///   one.
+/// * `local_path` - Path of local file.
+/// * `path` - Path of file on the other end.
+pub fn put(
+    transport: &mut dyn Transport,
+    helper: &str,
+    local_path: &std::path::Path,
+    path: &std::path::Path,
+) -> Result<()> {
+    let helper = transport.helper().unwrap_or(helper).to_string();
+    let path = crate::transport::ssh::shell_quote(&path.to_string_lossy());
+    let mut signature = Vec::new();
+    transport.exec(
+        &format!("{helper} delta-signature {path}"),
+        &mut std::io::empty(),
+        &mut signature,
+    )?;
+    let signature = Signature::read(&mut signature.as_slice())?;
+    let mut delta = tempfile()?;
+    compute(
+        &signature,
+        local_path,
+        &mut std::io::BufWriter::new(&mut delta),
+    )?;
+    delta
+        .seek(std::io::SeekFrom::Start(0))
+        .context("failed to seek in file")?;
+    transport.exec(
+        &format!("{helper} delta-patch {path}"),
+        &mut delta,
+        &mut std::io::sink(),
+    )
+}
+
+/// Download a file as a delta against the local version.
+///
+/// The other end must be able to run `git-repo-sync` (the helper).
+///
+/// # Arguments
+///
+/// * `transport` - Transport to the other end.
+/// * `helper` - Command that runs `git-repo-sync` on the other end, unless the transport provides
+///   one.
+/// * `path` - Path of file on the other end.
+/// * `local_path` - Path of local file.
+pub fn get(
+    transport: &mut dyn Transport,
+    helper: &str,
+    path: &std::path::Path,
+    local_path: &std::path::Path,
+) -> Result<()> {
+    let helper = transport.helper().unwrap_or(helper).to_string();
+    let path = crate::transport::ssh::shell_quote(&path.to_string_lossy());
+    let mut signature = Vec::new();
+    Signature::from_file(local_path)?.write(&mut signature)?;
+    let mut delta = tempfile()?;
+    transport.exec(
+        &format!("{helper} delta-compute {path}"),
+        &mut signature.as_slice(),
+        &mut delta,
+    )?;
+    delta
+        .seek(std::io::SeekFrom::Start(0))
+        .context("failed to seek in file")?;
+    apply(local_path, &mut std::io::BufReader::new(delta))
+}
+
+/// Rolling checksum over a window of bytes (as used by `rsync`), which can be moved forward by one
+/// byte in constant time.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add(((window.len() - i) as u32).wrapping_mul(byte as u32));
+        }
+        Rolling { a, b }
+    }
+
+    fn roll(&mut self, out: u8, into: u8, window_len: usize) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self
+            .b
+            .wrapping_sub((window_len as u32).wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn weak_checksum(block: &[u8]) -> u32 {
+    Rolling::new(block).digest()
+}
+
+fn strong_checksum(block: &[u8]) -> [u8; STRONG_LEN] {
+    let mut strong = [0; STRONG_LEN];
+    strong.copy_from_slice(&sha2::Sha256::digest(block)[..STRONG_LEN]);
+    strong
+}
+
+/// Block size for a basis file of the given size: the square root of the size (like `rsync`),
+/// but at least 700 bytes and at most 128 KiB, rounded up to a multiple of 8 bytes.
+fn block_size(len: u64) -> usize {
+    (((len as f64).sqrt() as usize).clamp(700, 128 * 1024) + 7) & !7
+}
+
+fn write_data(writer: &mut impl Write, data: &[u8]) -> Result<()> {
+    if !data.is_empty() {
+        writer.write_all(&[OP_DATA])?;
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(data)?;
+    }
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader
+        .read_exact(&mut bytes)
+        .context("unexpected end of data")?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Create an anonymous temporary file.
+fn tempfile() -> Result<std::fs::File> {
+    let path = std::env::temp_dir().join(format!(
+        "git-repo-sync-delta-{}-{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    ));
+    let file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .context("failed to create temporary file")?;
+    // The file stays accessible through the handle on Unix. On Windows, removing an open file
+    // fails, and the file is left behind in the temporary directory.
+    let _ = std::fs::remove_file(&path);
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random test data.
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Transfer a new file over a basis file (or no basis file) as a delta, check that the result
+    /// matches the new file, and return the number of copied blocks and literal bytes.
+    fn transfer(basis: Option<&[u8]>, new: &[u8]) -> (usize, usize) {
+        static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "git-repo-sync-delta-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        let basis_path = dir.join("basis");
+        let new_path = dir.join("new");
+        if let Some(basis) = basis {
+            std::fs::write(&basis_path, basis).unwrap();
+        }
+        std::fs::write(&new_path, new).unwrap();
+
+        let mut signature = Vec::new();
+        Signature::from_file(&basis_path)
+            .unwrap()
+            .write(&mut signature)
+            .unwrap();
+        let signature = Signature::read(&mut signature.as_slice()).unwrap();
+        let mut delta = Vec::new();
+        compute(&signature, &new_path, &mut delta).unwrap();
+        apply(&basis_path, &mut delta.as_slice()).unwrap();
+        assert_eq!(std::fs::read(&basis_path).unwrap(), new);
+        std::fs::remove_dir_all(&dir).unwrap();
+        count_ops(&delta)
+    }
+
+    /// Count the copied blocks and literal bytes in a delta.
+    fn count_ops(delta: &[u8]) -> (usize, usize) {
+        let mut reader = &delta[8..];
+        let (mut copies, mut literal) = (0, 0);
+        loop {
+            let op = reader[0];
+            reader = &reader[1..];
+            match op {
+                OP_COPY => {
+                    read_u32(&mut reader).unwrap();
+                    copies += 1;
+                }
+                OP_DATA => {
+                    let len = read_u32(&mut reader).unwrap() as usize;
+                    reader = &reader[len..];
+                    literal += len;
+                }
+                _ => return (copies, literal),
+            }
+        }
+    }
+
+    #[test]
+    fn test_identical() {
+        let basis = data(100_000, 1);
+        let block_size = block_size(basis.len() as u64);
+        assert_eq!(
+            transfer(Some(&basis), &basis),
+            (basis.len() / block_size, basis.len() % block_size)
+        );
+    }
+
+    #[test]
+    fn test_shifted_insertion() {
+        let basis = data(100_000, 2);
+        let block_size = block_size(basis.len() as u64);
+        let mut new = basis.clone();
+        new.splice(5000..5000, b"inserted".iter().copied());
+        let (copies, literal) = transfer(Some(&basis), &new);
+        // Only the block with the insertion is sent, and the blocks after it are found at their
+        // shifted offsets.
+        assert_eq!(copies, basis.len() / block_size - 1);
+        assert_eq!(literal, block_size + 8 + basis.len() % block_size);
+    }
+
+    #[test]
+    fn test_truncation() {
+        let basis = data(100_000, 3);
+        let block_size = block_size(basis.len() as u64);
+        let new = &basis[..50_000];
+        assert_eq!(
+            transfer(Some(&basis), new),
+            (new.len() / block_size, new.len() % block_size)
+        );
+        assert_eq!(transfer(Some(&basis), &[]), (0, 0));
+    }
+
+    #[test]
+    fn test_empty_basis() {
+        let new = data(10_000, 4);
+        assert_eq!(transfer(Some(&[]), &new), (0, new.len()));
+        assert_eq!(transfer(None, &new), (0, new.len()));
+    }
+
+    #[test]
+    fn test_block_size() {
+        assert_eq!(block_size(0), 704);
+        assert_eq!(block_size(700 * 700), 704);
+        assert_eq!(block_size(704 * 704), 704);
+        assert_eq!(block_size(705 * 705), 712);
+        assert_eq!(block_size(10_000 * 10_000), 10_000);
+        assert_eq!(block_size(1 << 40), 128 * 1024);
+    }
+
+    #[test]
+    fn test_block_size_boundary() {
+        // A basis file of exactly 10 blocks, which has no partial block at the end.
+        let size = block_size(10 * 700);
+        let basis = data(10 * size, 5);
+        assert_eq!(block_size(basis.len() as u64), size);
+        assert_eq!(transfer(Some(&basis), &basis), (10, 0));
+        let mut new = basis.clone();
+        new.push(0);
+        assert_eq!(transfer(Some(&basis), &new), (10, 1));
+        assert_eq!(
+            transfer(Some(&basis), &basis[..basis.len() - 1]),
+            (9, size - 1)
+        );
+    }
+
+    #[test]
+    fn test_mtime_before_epoch() {
+        let dir = std::env::temp_dir().join(format!(
+            "git-repo-sync-delta-test-{}-mtime",
+            std::process::id()
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        let basis_path = dir.join("basis");
+        let new_path = dir.join("new");
+        std::fs::write(&new_path, data(1000, 7)).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&new_path)
+            .unwrap()
+            .set_modified(crate::fs::system_time(-1000).unwrap())
+            .unwrap();
+
+        let mut delta = Vec::new();
+        compute(
+            &Signature::from_file(&basis_path).unwrap(),
+            &new_path,
+            &mut delta,
+        )
+        .unwrap();
+        apply(&basis_path, &mut delta.as_slice()).unwrap();
+        let metadata = std::fs::metadata(&basis_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(crate::fs::mtime(&metadata).unwrap(), -1000);
+    }
+
+    #[test]
+    fn test_digest_mismatch() {
+        let dir = std::env::temp_dir().join(format!(
+            "git-repo-sync-delta-test-{}-digest",
+            std::process::id()
+        ));
+        std::fs::create_dir(&dir).unwrap();
+        let basis_path = dir.join("basis");
+        let new_path = dir.join("new");
+        let basis = data(100_000, 6);
+        let mut new = basis.clone();
+        new[50_000] ^= 1;
+        std::fs::write(&basis_path, &basis).unwrap();
+        std::fs::write(&new_path, &new).unwrap();
+
+        let mut delta = Vec::new();
+        compute(
+            &Signature::from_file(&basis_path).unwrap(),
+            &new_path,
+            &mut delta,
+        )
+        .unwrap();
+        // The digest is followed by the modification time and permission bits.
+        let digest_pos = delta.len() - 32 - 8 - 4;
+        delta[digest_pos] ^= 1;
+        let err = apply(&basis_path, &mut delta.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("does not match digest"));
+        // The basis file is left alone, and the temporary file is removed.
+        assert_eq!(std::fs::read(&basis_path).unwrap(), basis);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
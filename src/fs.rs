@@ -1,5 +1,11 @@
+use std::io::Read;
+
+use anyhow::{Context, Result};
+
 use path_slash::PathExt;
 
+use sha2::Digest;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct File {
     /// Relative path.
@@ -7,11 +13,51 @@ pub struct File {
 
     /// File size in bytes.
     pub size: u64,
+
+    /// Last modification time in whole seconds since the epoch.
+    pub mtime: i64,
+
+    /// Hex-encoded SHA-256 digest of the file contents (only available when scanned in checksum
+    /// mode).
+    pub checksum: Option<String>,
+
+    /// Unix permission bits (not available on Windows).
+    pub mode: Option<u32>,
 }
 
 impl File {
-    pub fn new(path: std::path::PathBuf, size: u64) -> Self {
-        File { path, size }
+    pub fn new(path: std::path::PathBuf, size: u64, mtime: i64) -> Self {
+        File {
+            path,
+            size,
+            mtime,
+            checksum: None,
+            mode: None,
+        }
+    }
+
+    /// Whether or not the contents of this file differ from the contents of `other`.
+    ///
+    /// Files of different sizes are always different. If both files carry a checksum, the
+    /// checksums decide. Otherwise, the files are considered different when their modification
+    /// times differ (like the `rsync` quick check).
+    pub fn differs_from(&self, other: &File) -> bool {
+        if self.size != other.size {
+            return true;
+        }
+        match (&self.checksum, &other.checksum) {
+            (Some(checksum), Some(other_checksum)) => checksum != other_checksum,
+            _ => self.mtime != other.mtime,
+        }
+    }
+
+    /// Whether or not the permission bits of this file differ from those of `other`. Unknown
+    /// permission bits never differ.
+    pub fn mode_differs_from(&self, other: &File) -> bool {
+        match (self.mode, other.mode) {
+            (Some(mode), Some(other_mode)) => mode != other_mode,
+            _ => false,
+        }
     }
 }
 
@@ -38,3 +84,246 @@ impl std::fmt::Display for Directory {
         write!(f, "{}", self.path.to_slash_lossy())
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symlink {
+    /// Relative path.
+    pub path: std::path::PathBuf,
+
+    /// Path that the link points to, as stored in the link (usually relative to its directory).
+    pub target: std::path::PathBuf,
+}
+
+impl Symlink {
+    pub fn new(path: std::path::PathBuf, target: std::path::PathBuf) -> Self {
+        Symlink { path, target }
+    }
+}
+
+impl std::fmt::Display for Symlink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} -> {}",
+            self.path.to_slash_lossy(),
+            self.target.to_slash_lossy()
+        )
+    }
+}
+
+/// Create a symlink on the local file system.
+///
+/// # Arguments
+///
+/// * `target` - Path that the link points to.
+/// * `path` - Path of link to create.
+pub fn symlink(target: &std::path::Path, path: &std::path::Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, path)
+            .with_context(|| format!("failed to create symlink: {}", path.display()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (target, path);
+        Err(anyhow::anyhow!(
+            "symlinks are not supported on this platform (use --follow-symlinks)"
+        ))
+    }
+}
+
+/// Get the bytes of a path with `/` as separator, as used on remote hosts.
+///
+/// On Unix, the bytes of the path are used as is, so that file names that are not valid UTF-8 are
+/// kept intact.
+///
+/// # Arguments
+///
+/// * `path` - Path.
+pub fn path_to_bytes(path: &std::path::Path) -> std::borrow::Cow<'_, [u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        std::borrow::Cow::Borrowed(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        match path.to_slash_lossy() {
+            std::borrow::Cow::Borrowed(path) => std::borrow::Cow::Borrowed(path.as_bytes()),
+            std::borrow::Cow::Owned(path) => std::borrow::Cow::Owned(path.into_bytes()),
+        }
+    }
+}
+
+/// Create a path from bytes with `/` as separator, as printed by remote hosts.
+///
+/// # Arguments
+///
+/// * `bytes` - Bytes of path.
+pub fn path_from_bytes(bytes: &[u8]) -> std::path::PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        std::path::PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        use path_slash::PathBufExt;
+        std::path::PathBuf::from_slash(String::from_utf8_lossy(bytes))
+    }
+}
+
+/// Create an OS string from bytes, such as a command line built by [`path_to_bytes`].
+///
+/// # Arguments
+///
+/// * `bytes` - Bytes of string.
+pub fn os_string_from_bytes(bytes: Vec<u8>) -> std::ffi::OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        std::ffi::OsString::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    {
+        std::ffi::OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// Get the last modification time from file metadata in whole seconds since the epoch.
+///
+/// # Arguments
+///
+/// * `metadata` - File metadata.
+pub fn mtime(metadata: &std::fs::Metadata) -> Result<i64> {
+    let modified = metadata.modified()?;
+    Ok(match modified.duration_since(std::time::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(err) => -(err.duration().as_secs_f64().ceil() as i64),
+    })
+}
+
+/// Convert a modification time in whole seconds since the epoch (negative before the epoch) to a
+/// system time. Fails if the time cannot be represented on this platform.
+///
+/// # Arguments
+///
+/// * `mtime` - Modification time in whole seconds since the epoch.
+pub fn system_time(mtime: i64) -> Result<std::time::SystemTime> {
+    let offset = std::time::Duration::from_secs(mtime.unsigned_abs());
+    if mtime >= 0 {
+        std::time::UNIX_EPOCH.checked_add(offset)
+    } else {
+        std::time::UNIX_EPOCH.checked_sub(offset)
+    }
+    .ok_or_else(|| anyhow::anyhow!("modification time out of range: {mtime}"))
+}
+
+/// Get the Unix permission bits from file metadata, or `None` on platforms without them.
+///
+/// # Arguments
+///
+/// * `metadata` - File metadata.
+pub fn mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Set the Unix permission bits of a local file. Does nothing on platforms without them.
+///
+/// # Arguments
+///
+/// * `path` - Path of file.
+/// * `mode` - Permission bits.
+pub fn set_mode(path: &std::path::Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set file permissions: {}", path.display()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+        Ok(())
+    }
+}
+
+/// Get the path of the temporary sibling that a file is written to before it is moved into place,
+/// so that readers never see a partially written file.
+///
+/// # Arguments
+///
+/// * `path` - Path of file.
+pub fn temporary_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut file_name = std::ffi::OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(".repo-sync-tmp");
+    path.with_file_name(file_name)
+}
+
+/// Compute the hex-encoded SHA-256 digest of a local file.
+///
+/// The digest is equal to the output of `sha256sum`, which is used to compute the digest of remote
+/// files.
+///
+/// # Arguments
+///
+/// * `path` - Path of file to hash.
+pub fn checksum(path: &std::path::Path) -> Result<String> {
+    let mut file = std::fs::File::open(path).context("failed to open file for hashing")?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file
+            .read(&mut buffer)
+            .context("failed to read file for hashing")?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Compute the hex-encoded SHA-256 digests of local files (see [`checksum`]), hashing several files
+/// in parallel.
+///
+/// # Arguments
+///
+/// * `root` - Path of directory that holds the files.
+/// * `paths` - Paths of files to hash, relative to the directory.
+pub fn checksums(root: &std::path::Path, paths: &[std::path::PathBuf]) -> Result<Vec<String>> {
+    let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    let chunk_size = paths.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let workers = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| checksum(&root.join(path)))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut checksums = Vec::with_capacity(paths.len());
+        for worker in workers {
+            checksums.extend(worker.join().unwrap()?);
+        }
+        Ok(checksums)
+    })
+}
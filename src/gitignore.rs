@@ -0,0 +1,318 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{Context, Result};
+
+use crate::config::{self, Repository};
+use crate::fs;
+
+/// Decides which items are ignored by the gitignore rules of a local git directory.
+pub trait IgnoreCheck {
+    /// Whether or not an item is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of item, relative to the local directory.
+    /// * `is_dir` - Whether or not the item is a directory.
+    fn is_ignored(&mut self, path: &std::path::Path, is_dir: bool) -> Result<bool>;
+
+    /// Finishes checking, and reports any error that occurred in the meantime.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Create the gitignore checker for a local directory.
+///
+/// The built-in matcher is used unless `git check-ignore` is requested.
+///
+/// # Arguments
+///
+/// * `local_dir` - Path of local git directory.
+/// * `git_check_ignore` - Whether or not to run `git check-ignore` instead of the built-in matcher.
+pub fn checker(
+    local_dir: &std::path::Path,
+    git_check_ignore: bool,
+) -> Result<Box<dyn IgnoreCheck>> {
+    if git_check_ignore {
+        Ok(Box::new(GitCheckIgnore::spawn(local_dir)?))
+    } else {
+        Ok(Box::new(GitignoreMatcher::new(local_dir)?))
+    }
+}
+
+/// Evaluates gitignore rules in-process, the way git does.
+///
+/// The rules come from (from highest to lowest precedence) the `.gitignore` files in the item's
+/// directory and its parent directories up to the root of the repository, `.git/info/exclude`,
+/// and the file that `core.excludesFile` points to (which defaults to `~/.config/git/ignore`). An
+/// item inside an ignored directory is ignored as well.
+///
+/// As with git, tracked files are never ignored. They are listed once with `git ls-files`, which is
+/// skipped when `git` is not available.
+pub struct GitignoreMatcher {
+    /// Path of local directory relative to the root of the repository.
+    prefix: std::path::PathBuf,
+    repo_dir: std::path::PathBuf,
+    global: ignore::gitignore::Gitignore,
+    exclude: ignore::gitignore::Gitignore,
+    /// Rules of the `.gitignore` file in every directory (relative to the root of the repository)
+    /// that has been visited, if it has one.
+    gitignores: std::collections::HashMap<std::path::PathBuf, Option<ignore::gitignore::Gitignore>>,
+    /// Whether or not every directory (relative to the local directory) that has been checked is
+    /// ignored.
+    ignored_directories: std::collections::HashMap<std::path::PathBuf, bool>,
+    /// Tracked files (relative to the local directory) that match an ignore rule.
+    tracked: std::collections::HashSet<std::path::PathBuf>,
+    /// Parent directories of the tracked files that match an ignore rule.
+    tracked_directories: std::collections::HashSet<std::path::PathBuf>,
+}
+
+impl GitignoreMatcher {
+    /// Load the gitignore rules that apply to a local directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Path of local git directory.
+    pub fn new(local_dir: &std::path::Path) -> Result<GitignoreMatcher> {
+        let Some(repository) = Repository::find(local_dir)? else {
+            return Err(anyhow::anyhow!(
+                "not a git repository: {}",
+                local_dir.display()
+            ));
+        };
+        let repo_dir = repository.root;
+        let prefix = std::path::absolute(local_dir)
+            .context("failed to resolve local directory")?
+            .strip_prefix(&repo_dir)
+            .unwrap()
+            .to_path_buf();
+
+        let mut exclude = ignore::gitignore::GitignoreBuilder::new(&repo_dir);
+        let exclude_path = repository.common_dir.join("info").join("exclude");
+        if exclude_path.is_file() {
+            // XXX: As with git, invalid patterns are skipped instead of reported.
+            let _ = exclude.add(&exclude_path);
+        }
+        let mut global = ignore::gitignore::GitignoreBuilder::new(&repo_dir);
+        // As with git, the global excludes file defaults to `$XDG_CONFIG_HOME/git/ignore`.
+        let excludes_file =
+            config::git_config_path(local_dir, "core.excludesFile")?.or_else(|| {
+                match std::env::var_os("XDG_CONFIG_HOME").filter(|xdg| !xdg.is_empty()) {
+                    Some(xdg_config_home) => {
+                        Some(std::path::PathBuf::from(xdg_config_home).join("git/ignore"))
+                    }
+                    None => Some(
+                        std::path::PathBuf::from(std::env::var_os("HOME")?)
+                            .join(".config/git/ignore"),
+                    ),
+                }
+            });
+        if let Some(excludes_file) = excludes_file {
+            if excludes_file.is_file() {
+                let _ = global.add(&excludes_file);
+            }
+        }
+
+        let tracked = tracked_ignored_files(local_dir);
+        let tracked_directories = tracked
+            .iter()
+            .flat_map(|path| path.ancestors().skip(1))
+            .map(|path| path.to_path_buf())
+            .collect();
+        Ok(GitignoreMatcher {
+            prefix,
+            global: global.build().context("failed to build gitignore rules")?,
+            exclude: exclude.build().context("failed to build gitignore rules")?,
+            gitignores: std::collections::HashMap::new(),
+            ignored_directories: std::collections::HashMap::new(),
+            tracked,
+            tracked_directories,
+            repo_dir,
+        })
+    }
+
+    /// Whether or not nothing inside a directory can be synchronized, which is the case when it is
+    /// ignored and holds no tracked files.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of directory, relative to the local directory.
+    pub fn is_prunable(&mut self, path: &std::path::Path) -> bool {
+        !self.tracked_directories.contains(path) && self.is_ignored(path, true).unwrap_or(false)
+    }
+
+    /// Whether or not an item is ignored by the rules, without considering its parent directories.
+    fn is_matched(&mut self, path: &std::path::Path, is_dir: bool) -> bool {
+        let repo_path = self.prefix.join(path);
+        for directory in repo_path.ancestors().skip(1) {
+            let gitignore = self
+                .gitignores
+                .entry(directory.to_path_buf())
+                .or_insert_with(|| {
+                    let gitignore_path = self.repo_dir.join(directory).join(".gitignore");
+                    gitignore_path.is_file().then(|| {
+                        // XXX: As with git, invalid patterns are skipped instead of reported.
+                        ignore::gitignore::Gitignore::new(&gitignore_path).0
+                    })
+                });
+            if let Some(gitignore) = gitignore {
+                match gitignore.matched(repo_path.strip_prefix(directory).unwrap(), is_dir) {
+                    ignore::Match::None => {}
+                    matched => return matched.is_ignore(),
+                }
+            }
+        }
+        self.exclude
+            .matched(&repo_path, is_dir)
+            .or(self.global.matched(&repo_path, is_dir))
+            .is_ignore()
+    }
+}
+
+impl IgnoreCheck for GitignoreMatcher {
+    fn is_ignored(&mut self, path: &std::path::Path, is_dir: bool) -> Result<bool> {
+        if self.tracked.contains(path) {
+            return Ok(false);
+        }
+        // Parent directories are checked from the top down, so that every directory is only
+        // matched once.
+        for directory in path
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| ancestor.components().count() > 0)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            let is_ignored = match self.ignored_directories.get(directory) {
+                Some(&is_ignored) => is_ignored,
+                None => {
+                    let is_ignored = self.is_matched(directory, true);
+                    self.ignored_directories
+                        .insert(directory.to_path_buf(), is_ignored);
+                    is_ignored
+                }
+            };
+            if is_ignored {
+                return Ok(true);
+            }
+        }
+        Ok(self.is_matched(path, is_dir))
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Evaluates gitignore rules with a `git check-ignore` subprocess, which exactly matches git but
+/// takes a round trip for every item.
+pub struct GitCheckIgnore {
+    process: std::process::Child,
+    stdin: std::process::ChildStdin,
+    stdout: std::io::BufReader<std::process::ChildStdout>,
+}
+
+impl GitCheckIgnore {
+    /// Start `git check-ignore` in a local directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Path of local git directory.
+    pub fn spawn(local_dir: &std::path::Path) -> Result<GitCheckIgnore> {
+        let mut process = std::process::Command::new("git")
+            .args([
+                // Execute from local directory context.
+                "-C".as_ref(),
+                local_dir.as_os_str(),
+                // Git subcommand to check gitignore matching.
+                "check-ignore".as_ref(),
+                // By default `check-ignore` only returns the paths of ignored files. We also want
+                // to see any paths that were matched.
+                "--non-matching".as_ref(),
+                // Take input via stdin.
+                "--stdin".as_ref(),
+                // Include some extra information such as the line that actually matched. We use
+                // this to figure out if git included or excluded the file.
+                "--verbose".as_ref(),
+                // Separate paths and output fields by NUL, so that any file name can be passed.
+                "-z".as_ref(),
+            ])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .context("failed to spawn git command")?;
+        Ok(GitCheckIgnore {
+            stdin: process.stdin.take().unwrap(),
+            stdout: std::io::BufReader::new(process.stdout.take().unwrap()),
+            process,
+        })
+    }
+}
+
+impl IgnoreCheck for GitCheckIgnore {
+    fn is_ignored(&mut self, path: &std::path::Path, _is_dir: bool) -> Result<bool> {
+        self.stdin
+            .write_all(&fs::path_to_bytes(path))
+            .and_then(|()| self.stdin.write_all(&[0]))
+            .context("failed to write to git check-ignore")?;
+        let mut read_field = |name: &str| -> Result<Vec<u8>> {
+            let mut field = Vec::new();
+            self.stdout.read_until(0, &mut field)?;
+            if field.pop() != Some(0) {
+                return Err(anyhow::anyhow!("git check-ignore output missing {name}"));
+            }
+            Ok(field)
+        };
+        let _source = read_field("source")?;
+        let _linenum = read_field("linenum")?;
+        let pattern = read_field("pattern")?;
+        let _path = read_field("path")?;
+        Ok(!pattern.is_empty() && !pattern.starts_with(b"!"))
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let GitCheckIgnore { process, stdin, .. } = *self;
+        drop(stdin);
+        let exit_status = process
+            .wait_with_output()
+            .context("failed to run git command")?
+            .status;
+        match exit_status.code() {
+            // XXX: `git-check-ignore` returns 1 sometimes as part of normal operation
+            Some(0 | 1) => Ok(()),
+            _ => Err(anyhow::anyhow!("git check-ignore failed: {exit_status}")),
+        }
+    }
+}
+
+/// List the tracked files (relative to the local directory) that match an ignore rule. Returns
+/// an empty list if `git` is not available.
+///
+/// # Arguments
+///
+/// * `local_dir` - Path of local git directory.
+fn tracked_ignored_files(
+    local_dir: &std::path::Path,
+) -> std::collections::HashSet<std::path::PathBuf> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(local_dir)
+        .args([
+            "ls-files",
+            "--cached",
+            "--ignored",
+            "--exclude-standard",
+            "-z",
+        ])
+        .stderr(std::process::Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => output
+            .stdout
+            .split(|&byte| byte == 0)
+            .filter(|path| !path.is_empty())
+            .map(fs::path_from_bytes)
+            .collect(),
+        _ => std::collections::HashSet::new(),
+    }
+}
@@ -1,11 +1,47 @@
+/// Operating system family of a host, which decides the commands that run on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Platform {
+    /// Linux, macOS, BSD and other hosts with a POSIX shell.
+    #[default]
+    Unix,
+    /// Windows hosts with the OpenSSH server, where the shell is `cmd` or PowerShell.
+    Windows,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Host {
     name: String,
+    platform: Platform,
 }
 
 impl Host {
     pub fn new(name: impl Into<String>) -> Self {
-        Self { name: name.into() }
+        Self {
+            name: name.into(),
+            platform: Platform::default(),
+        }
+    }
+
+    /// Set the operating system family of the host.
+    ///
+    /// # Arguments
+    ///
+    /// * `platform` - Operating system family.
+    pub fn with_platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// Host name without the user part (if any).
+    pub fn hostname(&self) -> &str {
+        match self.name.split_once('@') {
+            Some((_, hostname)) => hostname,
+            None => &self.name,
+        }
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
     }
 }
 
@@ -0,0 +1,19 @@
+//! Synchronizes the files of a local git directory with a directory on a remote host (or on the
+//! local host), as used by the `git-repo-sync` command line interface.
+
+pub mod baseline;
+pub mod bulk;
+pub mod config;
+pub mod conflict;
+pub mod delta;
+pub mod fs;
+pub mod gitignore;
+pub mod host;
+pub mod protocol;
+pub mod revision;
+pub mod rules;
+pub mod scan;
+pub mod server;
+pub mod sync;
+pub mod transport;
+pub mod watch;
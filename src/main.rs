@@ -1,17 +1,30 @@
-mod fs;
-mod host;
-mod scan;
-mod sync;
+use std::io::Write;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use path_slash::PathExt;
 
 use clap::{Parser, Subcommand};
 
-use host::Host;
-use scan::DirectoryScanList;
-use sync::Sync;
+use git_repo_sync::baseline::Baseline;
+use git_repo_sync::bulk::BulkOptions;
+use git_repo_sync::config::NamedRemote;
+use git_repo_sync::conflict::{self, Conflict, ConflictPolicy, MergeOutcome};
+use git_repo_sync::delta::{self, DeltaOptions};
+use git_repo_sync::host::{Host, Platform};
+use git_repo_sync::revision::Revision;
+use git_repo_sync::rules::SyncRules;
+use git_repo_sync::scan::{self, DirectoryScanList, ScanOptions};
+use git_repo_sync::sync::{Sync, TransferOptions};
+use git_repo_sync::transport::{self, LocalTransport, SshTransport, Transport};
+use git_repo_sync::watch::{Changes, Watcher};
+use git_repo_sync::{fs, server};
+
+/// Delay before the first retry after an error in watch mode.
+const WATCH_MIN_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Maximum delay between retries after repeated errors in watch mode.
+const WATCH_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(60);
 
 #[derive(Parser, Debug)]
 #[command(name = "git-repo-sync", about = "Git repo sync utility", long_about = None)]
@@ -30,58 +43,268 @@ struct Cli {
     /// Whether to perform a dry-run.
     #[arg(short, long)]
     dry: bool,
+
+    /// Compare file contents by checksum instead of file sizes and modification times.
+    #[arg(short, long)]
+    checksum: bool,
+
+    /// Copy the files and directories that symlinks point to, instead of the symlinks themselves.
+    #[arg(short = 'L', long)]
+    follow_symlinks: bool,
+
+    /// Never synchronize items matching the pattern (in gitignore syntax), even if git tracks them.
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Always synchronize items matching the pattern (in gitignore syntax), even if git ignores
+    /// them. Takes precedence over `--exclude` and `.reposyncignore`.
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+
+    /// Only synchronize the files that are tracked by git. Downloads only touch tracked files as
+    /// well.
+    #[arg(long)]
+    tracked_only: bool,
+
+    /// Evaluate gitignore rules with `git check-ignore` instead of the built-in matcher, which is
+    /// slower but exactly matches git.
+    #[arg(long)]
+    git_check_ignore: bool,
+
+    /// Transfer large changed files as a delta, which requires `git-repo-sync` on the remote (the
+    /// remote helper, or the command given by `--delta-helper`).
+    #[arg(long)]
+    delta: bool,
+
+    /// Minimum file size (in bytes) for delta transfers.
+    #[arg(long, default_value_t = 1024 * 1024)]
+    delta_threshold: u64,
+
+    /// Command that runs `git-repo-sync` on the remote for delta transfers, when the remote helper
+    /// is not used.
+    #[arg(long, default_value = "git-repo-sync")]
+    delta_helper: String,
+
+    /// Copy files as a single `tar` stream when more than this number of files must be copied.
+    #[arg(long, default_value_t = 100)]
+    bulk_threshold: usize,
+
+    /// Never copy files as a single `tar` stream.
+    #[arg(long)]
+    no_bulk: bool,
+
+    /// Compress `tar` streams with zstd, which requires zstd support in `tar` on both ends.
+    #[arg(long)]
+    compress: bool,
+
+    /// Use the built-in SSH client instead of the `ssh` and `sftp` commands.
+    #[cfg(feature = "native-ssh")]
+    #[arg(long)]
+    native_ssh: bool,
+
+    /// Run `find` and `sftp` on SSH hosts instead of a helper. The helper is a copy of this
+    /// executable, which is uploaded when it is missing.
+    #[arg(long)]
+    no_remote_helper: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    /// Upload code to remote.
-    Up { remote: Remote },
-    /// Download code from remote.
-    Down { remote: Remote },
+    /// Upload code to remote (`host:dir`, a local directory, or the name of a remote in the git
+    /// config).
+    Up {
+        remote: String,
+        /// Upload a revision (such as `HEAD`, a tag or a commit) instead of the working tree.
+        #[arg(long)]
+        rev: Option<String>,
+        /// Upload only what changed since a revision (such as `HEAD` or a tag), including
+        /// uncommitted changes and untracked files. The remote must match the revision.
+        #[arg(long, conflicts_with = "rev")]
+        since: Option<String>,
+    },
+    /// Download code from remote (`host:dir`, a local directory, or the name of a remote in the git
+    /// config).
+    Down { remote: String },
+    /// Upload code to remote, and keep uploading local changes as they happen.
+    Watch {
+        remote: String,
+        /// How long to wait for more changes before uploading, in milliseconds.
+        #[arg(long, default_value_t = 200)]
+        debounce: u64,
+    },
+    /// Synchronize changes in both directions since the previous sync.
+    Sync {
+        remote: String,
+        /// How to resolve files that changed on both ends.
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::Skip)]
+        conflict: ConflictPolicy,
+    },
+    /// Write the signature of a file to stdout (used by delta transfers).
+    #[command(hide = true)]
+    DeltaSignature { path: std::path::PathBuf },
+    /// Read a signature from stdin, and write the delta of a file against it to stdout (used by
+    /// delta transfers).
+    #[command(hide = true)]
+    DeltaCompute { path: std::path::PathBuf },
+    /// Read a delta from stdin, and apply it to a file (used by delta transfers).
+    #[command(hide = true)]
+    DeltaPatch { path: std::path::PathBuf },
 }
 
 #[derive(Clone, Debug)]
 struct Remote {
-    host: Host,
+    /// SSH host, or `None` if the remote is a directory on the local host.
+    host: Option<Host>,
     dir: std::path::PathBuf,
 }
 
+impl Remote {
+    /// Create a transport to the remote.
+    ///
+    /// # Arguments
+    ///
+    /// * `native_ssh` - Whether or not to use the built-in SSH client for SSH hosts.
+    /// * `remote_helper` - Whether or not to run a helper on SSH hosts (where it can run).
+    /// * `verbose` - Whether or not to print why the helper cannot run.
+    #[cfg_attr(not(feature = "native-ssh"), allow(unused_variables))]
+    fn transport(
+        &self,
+        native_ssh: bool,
+        remote_helper: bool,
+        verbose: bool,
+    ) -> Result<Box<dyn Transport>> {
+        match &self.host {
+            #[cfg(feature = "native-ssh")]
+            Some(host) if native_ssh => Ok(Box::new(transport::NativeSshTransport::connect(host)?)),
+            Some(host) if remote_helper => {
+                // Hosts that cannot run the helper fall back to shell commands and `sftp`.
+                match transport::HelperTransport::connect(host) {
+                    Ok(transport) => Ok(Box::new(transport)),
+                    Err(err) => {
+                        if verbose {
+                            eprintln!("warning: cannot run remote helper on {host}, using shell commands and sftp instead: {err:#}");
+                        }
+                        Ok(Box::new(SshTransport::new(host.clone())))
+                    }
+                }
+            }
+            Some(host) => Ok(Box::new(SshTransport::new(host.clone()))),
+            None => Ok(Box::new(LocalTransport::new())),
+        }
+    }
+}
+
+/// Parse a remote given on the command line, or the remote that it names in the git config.
+///
+/// # Arguments
+///
+/// * `remote` - Remote given on the command line.
+/// * `named_remote` - Remote in the git config with the given name (if any).
+fn resolve_remote(remote: &str, named_remote: Option<&NamedRemote>) -> Result<Remote> {
+    match named_remote {
+        Some(named_remote) => named_remote.remote.parse(),
+        // A mistyped name must not be taken for something else, since syncing to (or from) the
+        // wrong place removes files.
+        None if is_remote_name(remote) => Err(anyhow::anyhow!(
+            "unknown remote: {remote} (there is no `[repo-sync \"{remote}\"]` section in the git \
+             config, and a local directory must be given as `./{remote}`)"
+        )),
+        None => remote.parse(),
+    }
+}
+
+/// Whether or not a remote given on the command line is the name of a remote in the git config,
+/// which is the case when it is neither `host:dir` nor an explicit local directory.
+fn is_remote_name(remote: &str) -> bool {
+    !remote.contains(':') && !is_explicit_local_path(remote)
+}
+
 impl std::str::FromStr for Remote {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        if let Some((host, dir)) = s.split_once(':') {
-            let dir = strip_path_trailing_sep(std::path::PathBuf::from(dir));
-            // XXX: Prefixing with ~ to designate home does not work with SFTP, but just using a
-            // relative path already will start from home, so stripping it here has the same effect
-            // and works fine.
-            let dir = if let Ok(stripped_dir) = dir.strip_prefix("~/") {
-                stripped_dir.to_path_buf()
-            } else {
-                dir
-            };
-            Ok(Remote {
-                host: Host::new(host),
-                dir,
-            })
-        } else {
-            Err(anyhow::anyhow!("invalid remote: {s}"))
+        // A local directory can be given as a `file:` URL as well, which takes precedence over a
+        // host named `file`.
+        if let Some(dir) = s
+            .strip_prefix("file://")
+            .or_else(|| s.strip_prefix("file:"))
+        {
+            if dir.is_empty() {
+                return Err(anyhow::anyhow!("invalid remote: {s}"));
+            }
+            return Ok(Remote {
+                host: None,
+                dir: strip_path_trailing_sep(std::path::PathBuf::from(dir)),
+            });
+        }
+        match s.split_once(':') {
+            // On Windows, `C:\dir` is a local path and not a directory on host `C`.
+            Some((host, _)) if cfg!(windows) && host.len() == 1 => Ok(Remote {
+                host: None,
+                dir: strip_path_trailing_sep(std::path::PathBuf::from(s)),
+            }),
+            // A directory with a drive letter (such as `C:\build` or `C:/build`) is on a Windows
+            // host. Its separators are converted, since the remote commands take `/` as well.
+            Some((host, dir)) if has_drive_letter(dir) => {
+                let dir = dir.replace('\\', "/");
+                let dir = match dir.strip_suffix('/') {
+                    Some(stripped_dir) if stripped_dir.len() > 2 => stripped_dir.to_string(),
+                    _ => dir,
+                };
+                Ok(Remote {
+                    host: Some(Host::new(host).with_platform(Platform::Windows)),
+                    dir: std::path::PathBuf::from(dir),
+                })
+            }
+            Some((host, dir)) => {
+                let dir = strip_path_trailing_sep(std::path::PathBuf::from(dir));
+                // XXX: Prefixing with ~ to designate home does not work with SFTP, but just using a
+                // relative path already will start from home, so stripping it here has the same effect
+                // and works fine.
+                let dir = if let Ok(stripped_dir) = dir.strip_prefix("~/") {
+                    stripped_dir.to_path_buf()
+                } else {
+                    dir
+                };
+                Ok(Remote {
+                    host: Some(Host::new(host)),
+                    dir,
+                })
+            }
+            // A mistyped `host:dir` or name of a remote must not be taken for a local directory,
+            // since syncing to (or from) the wrong directory removes files. Local directories must
+            // be given explicitly instead.
+            None if is_explicit_local_path(s) => Ok(Remote {
+                host: None,
+                dir: strip_path_trailing_sep(std::path::PathBuf::from(s)),
+            }),
+            None => Err(anyhow::anyhow!(
+                "invalid remote: {s} (expected `host:dir`, a local directory such as `./dir` or \
+                 `/dir`, or the name of a remote in the git config)"
+            )),
         }
     }
 }
 
 impl std::fmt::Display for Remote {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}:{}", self.host, self.dir.to_slash_lossy())
+        match &self.host {
+            Some(host) => write!(f, "{}:{}", host, self.dir.to_slash_lossy()),
+            None => write!(f, "{}", self.dir.to_slash_lossy()),
+        }
     }
 }
 
 fn main() {
-    match run() {
-        Ok(()) => {}
-        Err(err) => {
-            eprintln!("error: {:#}", err);
-        }
+    // The remote helper has no command line interface of its own.
+    let result = if std::env::args_os().nth(1).as_deref() == Some(server::SERVER_ARG.as_ref()) {
+        server::run()
+    } else {
+        run()
+    };
+    if let Err(err) = result {
+        eprintln!("error: {err:#}");
+        std::process::exit(1);
     }
 }
 
@@ -91,7 +314,24 @@ fn run() -> Result<()> {
         local_dir,
         verbose,
         dry,
+        checksum,
+        follow_symlinks,
+        exclude,
+        include,
+        tracked_only,
+        git_check_ignore,
+        delta,
+        delta_threshold,
+        delta_helper,
+        bulk_threshold,
+        no_bulk,
+        compress,
+        #[cfg(feature = "native-ssh")]
+        native_ssh,
+        no_remote_helper,
     } = Cli::parse();
+    #[cfg(not(feature = "native-ssh"))]
+    let native_ssh = false;
 
     let local_dir = match local_dir {
         Some(local_dir) => local_dir,
@@ -105,24 +345,100 @@ fn run() -> Result<()> {
     };
     let local_dir = strip_path_trailing_sep(local_dir);
 
+    // A remote can also be the name of a remote in the git config, which enables its options as
+    // well.
+    let named_remote = match &command {
+        Command::Up { remote, .. }
+        | Command::Down { remote }
+        | Command::Watch { remote, .. }
+        | Command::Sync { remote, .. }
+            if is_remote_name(remote) =>
+        {
+            NamedRemote::load(&local_dir, remote)?
+        }
+        _ => None,
+    };
+    let named_options = named_remote.clone().unwrap_or_default();
+    #[cfg(not(feature = "native-ssh"))]
+    if named_options.native_ssh {
+        return Err(anyhow::anyhow!(
+            "the built-in SSH client requires the `native-ssh` feature"
+        ));
+    }
+    let checksum = checksum || named_options.checksum;
+    let follow_symlinks = follow_symlinks || named_options.follow_symlinks;
+    let tracked_only = tracked_only || named_options.tracked_only;
+    let delta = delta || named_options.delta;
+    let no_bulk = no_bulk || named_options.no_bulk;
+    let compress = compress || named_options.compress;
+    let native_ssh = native_ssh || named_options.native_ssh;
+    let remote_helper = !(no_remote_helper || named_options.no_remote_helper);
+
     if verbose {
         println!("verbose = {verbose}");
         println!("local dir = {}", local_dir.to_slash_lossy());
     }
 
-    let scan_local =
-        DirectoryScanList::from_local_file_system(&local_dir)?.filter_by_gitignore(&local_dir)?;
-    if verbose {
-        println!(
-            "scanned local directory and found {} directories and {} files",
-            scan_local.directories().len(),
-            scan_local.files().len(),
-        );
-    }
+    let scan_options = ScanOptions { follow_symlinks };
+
+    // Delta and bulk transfers only pay off over the network. Both run shell commands, which are
+    // not available on Windows hosts.
+    let transfer_options = |remote: &Remote| {
+        let unix_host = remote
+            .host
+            .as_ref()
+            .is_some_and(|host| host.platform() == Platform::Unix);
+        TransferOptions {
+            delta: (delta && unix_host).then(|| DeltaOptions {
+                threshold: delta_threshold,
+                helper: delta_helper.clone(),
+            }),
+            bulk: (!no_bulk && unix_host).then_some(BulkOptions {
+                threshold: bulk_threshold,
+                compress,
+            }),
+        }
+    };
+
+    // The rules are loaded for every scan, so that changes to `.reposyncignore` are picked up when
+    // watching.
+    let rules_fn = || SyncRules::load(&local_dir, &exclude, &include);
+
+    // Returns the local scan with gitignore and sync rules applied, and the ignored directories
+    // (which the remote scan does not descend into).
+    let scan_local_fn = || -> Result<(DirectoryScanList, Vec<std::path::PathBuf>)> {
+        let rules = rules_fn()?;
+        let mut scan_local_all = if tracked_only {
+            DirectoryScanList::from_git_index(&local_dir, scan_options)?
+        } else {
+            DirectoryScanList::from_local_file_system(
+                &local_dir,
+                scan_options,
+                &scan::local_prune(&local_dir, &rules, git_check_ignore)?,
+            )?
+        };
+        let scan_local =
+            scan_local_all.filter_by_gitignore(&local_dir, &rules, git_check_ignore)?;
+        if verbose {
+            println!(
+                "scanned local directory and found {} directories and {} files",
+                scan_local.directories().len(),
+                scan_local.files().len(),
+            );
+        }
+        let prune = scan_local_all.pruned_directories(&scan_local);
+        Ok((scan_local, prune))
+    };
 
-    let scan_remote_fn = |remote: &Remote| -> Result<DirectoryScanList> {
-        let scan_remote = DirectoryScanList::from_remote_over_ssh(&remote.dir, &remote.host)?
-            .filter_by_gitignore(&local_dir)?;
+    // Returns the remote scan with gitignore and sync rules applied, and the complete remote scan
+    // (which is used to tell which directories still hold ignored files).
+    let scan_remote_fn = |remote: &Remote,
+                          transport: &mut dyn Transport,
+                          prune: &[std::path::PathBuf]|
+     -> Result<(DirectoryScanList, DirectoryScanList)> {
+        let mut scan_remote_all = transport.scan(&remote.dir, scan_options, prune)?;
+        let scan_remote =
+            scan_remote_all.filter_by_gitignore(&local_dir, &rules_fn()?, git_check_ignore)?;
         if verbose {
             println!(
                 "scanned remote directory and found {} directories and {} files",
@@ -130,57 +446,480 @@ fn run() -> Result<()> {
                 scan_remote.files().len(),
             );
         }
-        Ok(scan_remote)
+        Ok((scan_remote, scan_remote_all))
+    };
+
+    // In checksum mode, files are compared by their contents. Only the files that are left after
+    // filtering, and that the file sizes do not already tell apart from the files they are compared
+    // with (on the other end, or in the given baselines), are hashed.
+    let checksum_fn = |scan_local: &mut DirectoryScanList,
+                       scan_remote: &mut DirectoryScanList,
+                       baselines: &[&DirectoryScanList],
+                       remote: &Remote,
+                       transport: &mut dyn Transport|
+     -> Result<()> {
+        scan_local.compute_checksums(&[&[&*scan_remote], baselines].concat(), |paths| {
+            fs::checksums(&local_dir, paths)
+        })?;
+        scan_remote.compute_checksums(&[&[&*scan_local], baselines].concat(), |paths| {
+            transport.checksums(&remote.dir, paths)
+        })
     };
 
     match command {
-        Command::Up { remote } => {
-            let scan_remote = scan_remote_fn(&remote)?;
-            let sync = Sync::unidirectional(scan_local, scan_remote);
+        Command::Up {
+            remote,
+            since: Some(since),
+            ..
+        } => {
+            let remote = resolve_remote(&remote, named_remote.as_ref())?;
+            // Only the changed files are scanned, and the remote is not scanned at all.
+            let revision = Revision::resolve(&local_dir, &since)?;
+            let changes = revision.changes(!tracked_only)?;
+            let rules = rules_fn()?;
+            let source = DirectoryScanList::from_local_paths(
+                &local_dir,
+                &changes.present_paths(),
+                scan_options,
+            )?
+            .filter_by_gitignore(&local_dir, &rules, git_check_ignore)?;
+            // Files that were ignored in the revision were never uploaded, so they are not removed
+            // either.
+            let previous = scan::filter_paths_by_gitignore(
+                &local_dir,
+                &changes.previous_paths(),
+                &rules,
+                git_check_ignore,
+            )?;
+            let mut sync = Sync::incremental(
+                &local_dir,
+                &changes,
+                &source,
+                &previous,
+                &revision.directories()?,
+            );
+            if verbose {
+                println!(
+                    "found {} changed files since {}",
+                    source.files().len() + source.symlinks().len() + previous.len(),
+                    revision.commit(),
+                );
+            }
+            if !dry {
+                let mut transport = remote.transport(native_ssh, remote_helper, verbose)?;
+                sync.execute_remote(
+                    &local_dir,
+                    &remote.dir,
+                    transport.as_mut(),
+                    &transfer_options(&remote),
+                )?;
+                if verbose {
+                    print_sync_summary(&sync, &remote);
+                }
+            } else {
+                print_sync_dry(&sync, &[], local_dir.to_slash_lossy(), &remote);
+            }
+            Ok(())
+        }
+        Command::Up { remote, rev, .. } => {
+            let remote = resolve_remote(&remote, named_remote.as_ref())?;
+            let (mut scan_local, prune, revision) = match rev {
+                Some(rev) => {
+                    let mut revision = Revision::resolve(&local_dir, &rev)?;
+                    let scan_local = revision.scan()?.filter_by_rules(&rules_fn()?)?;
+                    if verbose {
+                        println!(
+                            "scanned revision {} and found {} directories and {} files",
+                            revision.commit(),
+                            scan_local.directories().len(),
+                            scan_local.files().len(),
+                        );
+                    }
+                    // Directories that are ignored in the working tree can hold files in the
+                    // revision, so the remote scan must descend into those.
+                    let directories = scan_local
+                        .directories()
+                        .iter()
+                        .map(|directory| directory.path.as_path())
+                        .collect::<std::collections::HashSet<_>>();
+                    let (_, prune) = scan_local_fn()?;
+                    let prune = prune
+                        .into_iter()
+                        .filter(|path| !directories.contains(path.as_path()))
+                        .collect();
+                    (scan_local, prune, Some(revision))
+                }
+                None => {
+                    let (scan_local, prune) = scan_local_fn()?;
+                    (scan_local, prune, None)
+                }
+            };
+            let mut transport = remote.transport(native_ssh, remote_helper, verbose)?;
+            let (mut scan_remote, scan_remote_all) =
+                scan_remote_fn(&remote, transport.as_mut(), &prune)?;
+            if checksum {
+                match &revision {
+                    Some(revision) => scan_local
+                        .compute_checksums(&[&scan_remote], |paths| revision.checksums(paths))?,
+                    None => scan_local.compute_checksums(&[&scan_remote], |paths| {
+                        fs::checksums(&local_dir, paths)
+                    })?,
+                }
+                scan_remote.compute_checksums(&[&scan_local], |paths| {
+                    transport.checksums(&remote.dir, paths)
+                })?;
+            }
+            let mut sync = Sync::unidirectional(scan_local, scan_remote);
+            sync.keep_nonempty_directories(&scan_remote_all);
             if !dry {
-                sync.execute_remote(&local_dir, &remote.dir, &remote.host)?;
+                // The files of a revision are copied from a temporary checkout of just the files
+                // that must be copied.
+                let checkout = revision
+                    .as_ref()
+                    .map(|revision| revision.checkout(sync.copy_files()))
+                    .transpose()?;
+                sync.execute_remote(
+                    checkout
+                        .as_ref()
+                        .map_or(local_dir.as_path(), |checkout| checkout.path()),
+                    &remote.dir,
+                    transport.as_mut(),
+                    &transfer_options(&remote),
+                )?;
                 if verbose {
-                    print_sync_summary(&sync, &remote.host);
+                    print_sync_summary(&sync, &remote);
                 }
             } else {
-                print_sync_dry(&sync, local_dir.to_slash_lossy(), &remote);
+                print_sync_dry(&sync, &[], local_dir.to_slash_lossy(), &remote);
             }
             Ok(())
         }
         Command::Down { remote } => {
-            let scan_remote = scan_remote_fn(&remote)?;
+            let remote = resolve_remote(&remote, named_remote.as_ref())?;
+            let (mut scan_local, prune) = scan_local_fn()?;
+            let mut transport = remote.transport(native_ssh, remote_helper, verbose)?;
+            let (mut scan_remote, _) = scan_remote_fn(&remote, transport.as_mut(), &prune)?;
+            if tracked_only {
+                scan_remote = scan_remote.select_tracked(&local_dir)?;
+            }
+            if checksum {
+                checksum_fn(
+                    &mut scan_local,
+                    &mut scan_remote,
+                    &[],
+                    &remote,
+                    transport.as_mut(),
+                )?;
+            }
             let sync = Sync::unidirectional(scan_remote, scan_local);
             if !dry {
-                sync.execute_local(&local_dir, &remote.dir, &remote.host)?;
+                sync.execute_local(
+                    &local_dir,
+                    &remote.dir,
+                    transport.as_mut(),
+                    &transfer_options(&remote),
+                )?;
                 if verbose {
                     print_sync_summary(&sync, "local host");
                 }
             } else {
-                print_sync_dry(&sync, &remote, local_dir.to_slash_lossy());
+                print_sync_dry(&sync, &[], &remote, local_dir.to_slash_lossy());
             }
             Ok(())
         }
+        Command::Watch { remote, debounce } => {
+            let remote = resolve_remote(&remote, named_remote.as_ref())?;
+            let mut transport = remote.transport(native_ssh, remote_helper, verbose)?;
+            // Start watching before the initial scan, so that no changes are missed.
+            let mut watcher = Watcher::new(&local_dir, std::time::Duration::from_millis(debounce))?
+                .with_index_rescans(tracked_only);
+            // The remote end is only scanned at startup and when a full rescan is needed. In between,
+            // its state is tracked by applying every executed sync.
+            let mut changes = Changes::Rescan;
+            let mut scan_remote = DirectoryScanList::default();
+            let mut scan_remote_all = DirectoryScanList::default();
+            let mut reconnect = false;
+            let mut backoff = WATCH_MIN_BACKOFF;
+            loop {
+                let result = (|| -> Result<()> {
+                    if reconnect {
+                        transport = remote.transport(native_ssh, remote_helper, verbose)?;
+                        reconnect = false;
+                    }
+                    let (mut source, mut target) = match &changes {
+                        Changes::Rescan => {
+                            let (scan_local, prune) = scan_local_fn()?;
+                            (scan_remote, scan_remote_all) =
+                                scan_remote_fn(&remote, transport.as_mut(), &prune)?;
+                            (scan_local, scan_remote.clone())
+                        }
+                        Changes::Paths(paths) => {
+                            let mut source = DirectoryScanList::from_local_paths(
+                                &local_dir,
+                                paths,
+                                scan_options,
+                            )?;
+                            if tracked_only {
+                                source = source.select_tracked(&local_dir)?;
+                            }
+                            (
+                                source.filter_by_gitignore(
+                                    &local_dir,
+                                    &rules_fn()?,
+                                    git_check_ignore,
+                                )?,
+                                scan_remote.select(paths),
+                            )
+                        }
+                    };
+                    if checksum {
+                        checksum_fn(&mut source, &mut target, &[], &remote, transport.as_mut())?;
+                    }
+                    let mut sync = Sync::unidirectional(source.clone(), target);
+                    sync.keep_nonempty_directories(&scan_remote_all);
+                    if !dry {
+                        sync.execute_remote(
+                            &local_dir,
+                            &remote.dir,
+                            transport.as_mut(),
+                            &transfer_options(&remote),
+                        )?;
+                        scan_remote = sync.apply(&source, &scan_remote);
+                        scan_remote_all = sync.apply(&source, &scan_remote_all);
+                        if verbose {
+                            print_sync_summary(&sync, &remote);
+                        }
+                    } else {
+                        print_sync_dry(&sync, &[], local_dir.to_slash_lossy(), &remote);
+                    }
+                    Ok(())
+                })();
+                changes = match result {
+                    Ok(()) => {
+                        backoff = WATCH_MIN_BACKOFF;
+                        watcher.wait()?
+                    }
+                    Err(err) => {
+                        // The remote end might be in an unknown state now, and the connection to it
+                        // might be broken, so start over with a new transport and a full scan of
+                        // both ends. Retries are spaced out further while the errors persist.
+                        eprintln!("error: {:#}", err);
+                        std::thread::sleep(backoff);
+                        backoff = (backoff * 2).min(WATCH_MAX_BACKOFF);
+                        reconnect = true;
+                        Changes::Rescan
+                    }
+                };
+            }
+        }
+        Command::Sync { remote, conflict } => {
+            let remote = resolve_remote(&remote, named_remote.as_ref())?;
+            let (mut scan_local, prune) = scan_local_fn()?;
+            let mut transport = remote.transport(native_ssh, remote_helper, verbose)?;
+            let (mut scan_remote, scan_remote_all) =
+                scan_remote_fn(&remote, transport.as_mut(), &prune)?;
+            if tracked_only {
+                scan_remote = scan_remote.select_tracked(&local_dir)?;
+            }
+            let baseline_path = Baseline::path(&local_dir, &remote)?;
+            let baseline = Baseline::load(&baseline_path)?;
+            if checksum {
+                checksum_fn(
+                    &mut scan_local,
+                    &mut scan_remote,
+                    &[&baseline.local, &baseline.remote],
+                    &remote,
+                    transport.as_mut(),
+                )?;
+            } else {
+                // Files that are new on both ends (such as every file in the first sync) have no
+                // common ancestor that tells which end changed them, so they are compared by their
+                // contents. Otherwise, identical files with different modification times would be
+                // conflicts, and stay conflicts in every later sync.
+                let new_local = scan_local.select_files_not_in(&baseline.local);
+                let new_remote = scan_remote.select_files_not_in(&baseline.remote);
+                scan_local
+                    .compute_checksums(&[&new_remote], |paths| fs::checksums(&local_dir, paths))?;
+                scan_remote.compute_checksums(&[&new_local], |paths| {
+                    transport.checksums(&remote.dir, paths)
+                })?;
+            }
+            let remote_name = remote
+                .host
+                .as_ref()
+                .map(|host| host.hostname().to_string())
+                .unwrap_or_else(|| "remote".to_string());
+            let mut sync =
+                Sync::bidirectional(&scan_local, &scan_remote, &baseline, conflict, &remote_name);
+            sync.keep_nonempty_remote_directories(&scan_remote_all);
+            if dry {
+                print_sync_dry(
+                    sync.to_remote(),
+                    sync.conflicts(),
+                    local_dir.to_slash_lossy(),
+                    &remote,
+                );
+                print_sync_dry(sync.to_local(), &[], &remote, local_dir.to_slash_lossy());
+                return Ok(());
+            }
+            if conflict == ConflictPolicy::Abort && !sync.conflicts().is_empty() {
+                for conflict in sync.conflicts() {
+                    eprintln!("conflict: {}", conflict.path.to_slash_lossy());
+                }
+                return Err(anyhow::anyhow!(
+                    "aborted: {} files changed on both ends",
+                    sync.conflicts().len()
+                ));
+            }
+            let options = transfer_options(&remote);
+            sync.to_remote_mut().execute_remote(
+                &local_dir,
+                &remote.dir,
+                transport.as_mut(),
+                &options,
+            )?;
+            sync.to_local()
+                .execute_local(&local_dir, &remote.dir, transport.as_mut(), &options)?;
+            let mut next_baseline = sync.baseline(&scan_local, &scan_remote, &baseline);
+            for conflict in sync.conflicts() {
+                let path = conflict.path.to_slash_lossy();
+                match conflict.policy {
+                    ConflictPolicy::Skip => {
+                        eprintln!("warning: skipped file changed on both ends: {path}");
+                    }
+                    ConflictPolicy::Merge => {
+                        let outcome = match baseline.blobs.get(&conflict.path) {
+                            Some(base_blob) => conflict::merge(
+                                &local_dir,
+                                &remote.dir,
+                                &baseline_path.with_extension("merge"),
+                                &conflict.path,
+                                base_blob,
+                                &remote_name,
+                                transport.as_mut(),
+                            )?,
+                            None => MergeOutcome::Unmergeable,
+                        };
+                        match outcome {
+                            MergeOutcome::Clean | MergeOutcome::Conflicted(_) => {
+                                let metadata = std::fs::metadata(local_dir.join(&conflict.path))
+                                    .context("failed to fetch file metadata")?;
+                                next_baseline.set_file(fs::File::new(
+                                    conflict.path.clone(),
+                                    metadata.len(),
+                                    fs::mtime(&metadata)?,
+                                ));
+                            }
+                            MergeOutcome::Unmergeable => {}
+                        }
+                        match outcome {
+                            MergeOutcome::Clean => {
+                                if verbose {
+                                    println!("merged: {path}");
+                                }
+                            }
+                            MergeOutcome::Conflicted(conflicts) => eprintln!(
+                                "warning: merged with {conflicts} conflicts (resolve conflict markers): {path}"
+                            ),
+                            MergeOutcome::Unmergeable => eprintln!(
+                                "warning: skipped file changed on both ends (cannot be merged): {path}"
+                            ),
+                        }
+                    }
+                    _ => {
+                        if verbose {
+                            println!("resolved conflict ({}): {path}", conflict.policy);
+                        }
+                    }
+                }
+            }
+            // Common ancestors are only needed for merging.
+            if conflict == ConflictPolicy::Merge {
+                next_baseline.record_blobs(&local_dir, &baseline_path, &baseline)?;
+            }
+            next_baseline.save(&baseline_path)?;
+            if verbose {
+                print_sync_summary(sync.to_remote(), &remote);
+                print_sync_summary(sync.to_local(), "local host");
+            }
+            Ok(())
+        }
+        // The helper commands run on the other end of a delta transfer.
+        Command::DeltaSignature { path } => {
+            let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+            delta::Signature::from_file(&path)?.write(&mut stdout)?;
+            stdout.flush().context("failed to write signature")
+        }
+        Command::DeltaCompute { path } => {
+            let signature = delta::Signature::read(&mut std::io::stdin().lock())?;
+            delta::compute(
+                &signature,
+                &path,
+                &mut std::io::BufWriter::new(std::io::stdout().lock()),
+            )
+        }
+        Command::DeltaPatch { path } => {
+            delta::apply(&path, &mut std::io::BufReader::new(std::io::stdin().lock()))
+        }
     }
 }
 
 fn print_sync_summary(sync: &Sync, target: impl std::fmt::Display) {
+    if !sync.rename_files().is_empty() {
+        println!("renamed {} files on {target}", sync.rename_files().len());
+    }
     println!("removed {} files on {target}", sync.remove_files().len());
     println!(
         "removed {} directories on {target}",
         sync.remove_directories().len()
     );
+    for directory in sync.keep_directories() {
+        println!(
+            "kept directory that is not empty on {target}: {}",
+            directory.to_slash_lossy()
+        );
+    }
     println!(
         "created {} directories on {target}",
         sync.create_directories().len()
     );
+    if !sync.create_symlinks().is_empty() {
+        println!(
+            "created {} symlinks on {target}",
+            sync.create_symlinks().len()
+        );
+    }
     println!("copied {} files to {target}", sync.copy_files().len());
+    if !sync.chmod_files().is_empty() {
+        println!(
+            "changed permissions of {} files on {target}",
+            sync.chmod_files().len()
+        );
+    }
 }
 
 fn print_sync_dry(
     sync: &Sync,
+    conflicts: &[Conflict],
     source_prefix: impl std::fmt::Display,
     target_prefix: impl std::fmt::Display,
 ) {
+    for conflict in conflicts {
+        println!(
+            "conflict ({}): {}",
+            conflict.policy,
+            conflict.path.to_slash_lossy()
+        );
+    }
+    for (from, to) in sync.rename_files() {
+        println!(
+            "rename file: {}/{} -> {}/{}",
+            target_prefix,
+            from.to_slash_lossy(),
+            target_prefix,
+            to.to_slash_lossy()
+        );
+    }
     for file in sync.remove_files() {
         println!("remove file: {}/{}", target_prefix, file.to_slash_lossy());
     }
@@ -191,6 +930,13 @@ fn print_sync_dry(
             directory.to_slash_lossy()
         );
     }
+    for directory in sync.keep_directories() {
+        println!(
+            "keep directory (contains ignored files): {}/{}",
+            target_prefix,
+            directory.to_slash_lossy()
+        );
+    }
     for directory in sync.create_directories() {
         println!(
             "create directory: {}/{}",
@@ -198,6 +944,14 @@ fn print_sync_dry(
             directory.to_slash_lossy()
         );
     }
+    for symlink in sync.create_symlinks() {
+        println!(
+            "create symlink: {}/{} -> {}",
+            target_prefix,
+            symlink.path.to_slash_lossy(),
+            symlink.target.to_slash_lossy()
+        );
+    }
     for file in sync.copy_files() {
         println!(
             "copy file: {}/{} -> {}/{}",
@@ -207,6 +961,32 @@ fn print_sync_dry(
             file.to_slash_lossy()
         );
     }
+    for (file, mode) in sync.chmod_files() {
+        println!(
+            "change permissions: {}/{} ({mode:o})",
+            target_prefix,
+            file.to_slash_lossy()
+        );
+    }
+}
+
+/// Whether or not a path is explicitly local (absolute, or starting with `.` or `..`).
+fn is_explicit_local_path(path: &str) -> bool {
+    let path = std::path::Path::new(path);
+    path.is_absolute()
+        || matches!(
+            path.components().next(),
+            Some(std::path::Component::CurDir | std::path::Component::ParentDir)
+        )
+}
+
+/// Whether or not a path starts with a Windows drive letter (such as `C:`).
+fn has_drive_letter(path: &str) -> bool {
+    let mut chars = path.chars();
+    matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(letter), Some(':'), None | Some('/' | '\\')) if letter.is_ascii_alphabetic()
+    )
 }
 
 fn strip_path_trailing_sep(p: std::path::PathBuf) -> std::path::PathBuf {
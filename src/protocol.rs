@@ -0,0 +1,375 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+
+use crate::fs::{Directory, File, Symlink};
+use crate::scan::{DirectoryScanList, ScanOptions};
+
+/// Written by the remote helper when it starts, followed by the protocol version.
+pub const MAGIC: &[u8; 4] = b"GRSH";
+
+/// Version of the protocol. The helper must speak the same version as the client.
+pub const VERSION: u32 = 4;
+
+/// Requests that the client sends to the remote helper. Every request starts with its opcode and
+/// is followed by its fields. The helper answers every request with a status, which is followed by
+/// an error message or the result of the request. Requests are answered in order, so the client
+/// may send more requests before it reads the statuses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Opcode {
+    /// Fields: path, scan flags, directories to prune. Result: the scan entries.
+    Scan = 1,
+    /// Fields: path.
+    Mkdir = 2,
+    /// Fields: path.
+    Rm = 3,
+    /// Fields: path.
+    Rmdir = 4,
+    /// Fields: path, new path.
+    Rename = 5,
+    /// Fields: target, path.
+    Symlink = 6,
+    /// Fields: path, mode.
+    Chmod = 7,
+    /// Fields: path, file metadata, file contents.
+    Put = 8,
+    /// Fields: path. Result: file metadata, file contents.
+    Get = 9,
+    /// Fields: path of directory, paths of files in it. Result: the checksums of the files.
+    Checksums = 10,
+    /// Fields: shell command, its input as chunks. Result: its output as chunks, which precedes the
+    /// status (since the command only exits once its output has been read).
+    Exec = 11,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = anyhow::Error;
+
+    fn try_from(opcode: u8) -> Result<Self> {
+        Ok(match opcode {
+            1 => Opcode::Scan,
+            2 => Opcode::Mkdir,
+            3 => Opcode::Rm,
+            4 => Opcode::Rmdir,
+            5 => Opcode::Rename,
+            6 => Opcode::Symlink,
+            7 => Opcode::Chmod,
+            8 => Opcode::Put,
+            9 => Opcode::Get,
+            10 => Opcode::Checksums,
+            11 => Opcode::Exec,
+            _ => return Err(anyhow::anyhow!("unknown opcode: {opcode}")),
+        })
+    }
+}
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+const SCAN_FOLLOW_SYMLINKS: u8 = 1 << 1;
+
+const ENTRY_END: u8 = 0;
+const ENTRY_DIRECTORY: u8 = 1;
+const ENTRY_FILE: u8 = 2;
+const ENTRY_SYMLINK: u8 = 3;
+
+/// Marks unknown permission bits (on platforms without them).
+const UNKNOWN_MODE: u32 = u32::MAX;
+
+/// Maximum size of the chunks that file contents are sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub fn write_u8(writer: &mut impl Write, value: u8) -> std::io::Result<()> {
+    writer.write_all(&[value])
+}
+
+pub fn write_u32(writer: &mut impl Write, value: u32) -> std::io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+pub fn write_u64(writer: &mut impl Write, value: u64) -> std::io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+pub fn write_bytes(writer: &mut impl Write, value: &[u8]) -> std::io::Result<()> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value)
+}
+
+pub fn write_path(writer: &mut impl Write, path: &std::path::Path) -> std::io::Result<()> {
+    write_bytes(writer, &crate::fs::path_to_bytes(path))
+}
+
+/// Write a list of paths, preceded by its length.
+pub fn write_paths(writer: &mut impl Write, paths: &[std::path::PathBuf]) -> std::io::Result<()> {
+    write_u32(writer, paths.len() as u32)?;
+    for path in paths {
+        write_path(writer, path)?;
+    }
+    Ok(())
+}
+
+pub fn read_u8(reader: &mut impl Read) -> std::io::Result<u8> {
+    let mut buffer = [0; 1];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+pub fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buffer = [0; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_be_bytes(buffer))
+}
+
+pub fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut buffer = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_be_bytes(buffer))
+}
+
+pub fn read_bytes(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let len = read_u32(reader)?;
+    let mut buffer = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buffer)?;
+    if buffer.len() != len as usize {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buffer)
+}
+
+pub fn read_path(reader: &mut impl Read) -> std::io::Result<std::path::PathBuf> {
+    Ok(crate::fs::path_from_bytes(&read_bytes(reader)?))
+}
+
+/// Read a list of paths written by [`write_paths`].
+pub fn read_paths(reader: &mut impl Read) -> std::io::Result<Vec<std::path::PathBuf>> {
+    (0..read_u32(reader)?).map(|_| read_path(reader)).collect()
+}
+
+/// Write the status of a request. Errors are sent with their message.
+///
+/// # Arguments
+///
+/// * `writer` - Writer to write to.
+/// * `result` - Result of the request.
+pub fn write_status<T>(writer: &mut impl Write, result: &Result<T>) -> std::io::Result<()> {
+    match result {
+        Ok(_) => write_u8(writer, STATUS_OK),
+        Err(err) => {
+            write_u8(writer, STATUS_ERROR)?;
+            write_bytes(writer, format!("{err:#}").as_bytes())
+        }
+    }
+}
+
+/// Read the status of a request, and turn an error status into an error.
+///
+/// # Arguments
+///
+/// * `reader` - Reader to read from.
+pub fn read_status(reader: &mut impl Read) -> Result<()> {
+    match read_u8(reader).context("failed to read from remote helper")? {
+        STATUS_OK => Ok(()),
+        STATUS_ERROR => {
+            let message = read_bytes(reader).context("failed to read from remote helper")?;
+            Err(anyhow::anyhow!(
+                "remote helper: {}",
+                String::from_utf8_lossy(&message)
+            ))
+        }
+        status => Err(anyhow::anyhow!(
+            "remote helper sent unknown status: {status}"
+        )),
+    }
+}
+
+pub fn write_scan_options(writer: &mut impl Write, options: ScanOptions) -> std::io::Result<()> {
+    let mut flags = 0;
+    if options.follow_symlinks {
+        flags |= SCAN_FOLLOW_SYMLINKS;
+    }
+    write_u8(writer, flags)
+}
+
+pub fn read_scan_options(reader: &mut impl Read) -> std::io::Result<ScanOptions> {
+    let flags = read_u8(reader)?;
+    Ok(ScanOptions {
+        follow_symlinks: flags & SCAN_FOLLOW_SYMLINKS != 0,
+    })
+}
+
+/// Write the entries of a scan, followed by an end marker.
+///
+/// # Arguments
+///
+/// * `writer` - Writer to write to.
+/// * `scan` - Scan to write.
+pub fn write_scan(writer: &mut impl Write, scan: &DirectoryScanList) -> std::io::Result<()> {
+    for directory in scan.directories() {
+        write_u8(writer, ENTRY_DIRECTORY)?;
+        write_path(writer, &directory.path)?;
+    }
+    for file in scan.files() {
+        write_u8(writer, ENTRY_FILE)?;
+        write_path(writer, &file.path)?;
+        write_u64(writer, file.size)?;
+        write_u64(writer, file.mtime as u64)?;
+        write_u32(writer, file.mode.unwrap_or(UNKNOWN_MODE))?;
+    }
+    for symlink in scan.symlinks() {
+        write_u8(writer, ENTRY_SYMLINK)?;
+        write_path(writer, &symlink.path)?;
+        write_path(writer, &symlink.target)?;
+    }
+    write_u8(writer, ENTRY_END)
+}
+
+/// Read the entries of a scan written by [`write_scan`].
+///
+/// # Arguments
+///
+/// * `reader` - Reader to read from.
+pub fn read_scan(reader: &mut impl Read) -> Result<DirectoryScanList> {
+    let mut directories = Vec::new();
+    let mut files = Vec::new();
+    let mut symlinks = Vec::new();
+    loop {
+        match read_u8(reader)? {
+            ENTRY_END => break,
+            ENTRY_DIRECTORY => directories.push(Directory::new(read_path(reader)?)),
+            ENTRY_FILE => {
+                let path = read_path(reader)?;
+                let size = read_u64(reader)?;
+                let mtime = read_u64(reader)? as i64;
+                let mut file = File::new(path, size, mtime);
+                file.mode = Some(read_u32(reader)?).filter(|&mode| mode != UNKNOWN_MODE);
+                files.push(file);
+            }
+            ENTRY_SYMLINK => {
+                let path = read_path(reader)?;
+                let target = read_path(reader)?;
+                symlinks.push(Symlink::new(path, target));
+            }
+            entry => return Err(anyhow::anyhow!("remote helper sent unknown entry: {entry}")),
+        }
+    }
+    Ok(DirectoryScanList::new(directories, files).with_symlinks(symlinks))
+}
+
+/// Write the contents of a reader as chunks, followed by an empty chunk.
+///
+/// The empty chunk is written even when reading fails, so that the stream stays usable. Errors of
+/// the stream are returned in the outer result, and errors of the reader in the inner result.
+///
+/// # Arguments
+///
+/// * `writer` - Writer to write to.
+/// * `reader` - Reader of contents.
+pub fn write_chunks(
+    writer: &mut impl Write,
+    reader: &mut (impl Read + ?Sized),
+) -> std::io::Result<std::io::Result<()>> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(n) => n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                write_bytes(writer, &[])?;
+                return Ok(Err(err));
+            }
+        };
+        write_bytes(writer, &buffer[..n])?;
+        if n == 0 {
+            return Ok(Ok(()));
+        }
+    }
+}
+
+/// Read chunks written by [`write_chunks`] into a writer.
+///
+/// All chunks are read, even when writing fails, so that the stream stays usable. Errors of the
+/// stream are returned in the outer result, and errors of the writer in the inner result.
+///
+/// # Arguments
+///
+/// * `reader` - Reader to read from.
+/// * `writer` - Writer of contents.
+pub fn read_chunks(
+    reader: &mut impl Read,
+    writer: &mut (impl Write + ?Sized),
+) -> std::io::Result<std::io::Result<()>> {
+    let mut result = Ok(());
+    loop {
+        let chunk = read_bytes(reader)?;
+        if chunk.is_empty() {
+            return Ok(result);
+        }
+        if result.is_ok() {
+            result = writer.write_all(&chunk);
+        }
+    }
+}
+
+/// Write a file: its modification time and permissions, and its contents as chunks followed by an
+/// empty chunk.
+///
+/// Unlike with [`write_chunks`], the stream ends without the empty chunk when reading the file
+/// fails, so that a partial file is never taken for a complete one.
+///
+/// # Arguments
+///
+/// * `writer` - Writer to write to.
+/// * `file` - Local file to write.
+pub fn write_file(writer: &mut impl Write, mut file: std::fs::File) -> Result<()> {
+    let metadata = file.metadata().context("failed to fetch file metadata")?;
+    let mtime = crate::fs::mtime(&metadata).context("failed to fetch file modification time")?;
+    write_u64(writer, mtime as u64)?;
+    write_u32(writer, crate::fs::mode(&metadata).unwrap_or(UNKNOWN_MODE))?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer).context("failed to read file")?;
+        write_bytes(writer, &buffer[..n])?;
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Read a file written by [`write_file`] into a local file.
+///
+/// The file is written to a temporary file first, which is then moved into place. All chunks are
+/// read, even when writing the file fails, so that the stream stays usable. Errors of the stream
+/// are returned in the outer result, and errors of the file in the inner result.
+///
+/// # Arguments
+///
+/// * `reader` - Reader to read from.
+/// * `path` - Path of local file to write.
+pub fn read_file(reader: &mut impl Read, path: &std::path::Path) -> std::io::Result<Result<()>> {
+    let mtime = read_u64(reader)? as i64;
+    let mode = Some(read_u32(reader)?).filter(|&mode| mode != UNKNOWN_MODE);
+    let temporary_path = crate::fs::temporary_path(path);
+    let file = match std::fs::File::create(&temporary_path).context("failed to create file") {
+        Ok(mut file) => read_chunks(reader, &mut file)?
+            .context("failed to write file")
+            .map(|()| file),
+        Err(err) => {
+            let _ = read_chunks(reader, &mut std::io::sink())?;
+            Err(err)
+        }
+    };
+    let result = file.and_then(|file| {
+        file.set_modified(crate::fs::system_time(mtime)?)
+            .context("failed to set file modification time")?;
+        if let Some(mode) = mode {
+            crate::fs::set_mode(&temporary_path, mode)?;
+        }
+        std::fs::rename(&temporary_path, path).context("failed to rename file")
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary_path);
+    }
+    Ok(result)
+}
@@ -0,0 +1,504 @@
+use std::io::{BufRead, Read, Write};
+
+use anyhow::{Context, Result};
+
+use sha2::Digest;
+
+use crate::fs::{self, Directory, File, Symlink};
+use crate::scan::DirectoryScanList;
+
+/// Mode of an executable file in a git tree.
+const GIT_MODE_EXECUTABLE: &[u8] = b"100755";
+
+/// Mode of a symlink in a git tree.
+const GIT_MODE_SYMLINK: &[u8] = b"120000";
+
+/// Changes in the working tree of a local git repository since a revision, as listed by
+/// [`Revision::changes`]. Paths are relative to the local directory.
+#[derive(Debug, Clone, Default)]
+pub struct TreeChanges {
+    /// Files that were added, including untracked files.
+    pub added: std::collections::BTreeSet<std::path::PathBuf>,
+    /// Files that were modified.
+    pub modified: std::collections::BTreeSet<std::path::PathBuf>,
+    /// Files that were removed.
+    pub removed: std::collections::BTreeSet<std::path::PathBuf>,
+    /// Files that were renamed, and whether or not their contents changed as well.
+    pub renamed: Vec<(std::path::PathBuf, std::path::PathBuf, bool)>,
+}
+
+impl TreeChanges {
+    /// Paths of the files that exist in the working tree.
+    pub fn present_paths(&self) -> std::collections::BTreeSet<std::path::PathBuf> {
+        self.added
+            .iter()
+            .chain(&self.modified)
+            .chain(self.renamed.iter().map(|(_, to, _)| to))
+            .cloned()
+            .collect()
+    }
+
+    /// Paths of the files that existed in the revision, but no longer exist in the working tree.
+    pub fn previous_paths(&self) -> std::collections::BTreeSet<std::path::PathBuf> {
+        self.removed
+            .iter()
+            .chain(self.renamed.iter().map(|(from, _, _)| from))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Revision of a local git repository, which is synchronized instead of the working tree.
+///
+/// The contents of files are read from the git object database. Since git does not store
+/// modification times, every file gets the commit time as its modification time.
+pub struct Revision {
+    local_dir: std::path::PathBuf,
+    /// Full name of the commit.
+    commit: String,
+    /// Commit time in seconds since the epoch.
+    mtime: i64,
+    /// Blob of every file that was scanned.
+    blobs: std::collections::HashMap<std::path::PathBuf, Blob>,
+}
+
+/// Blob of a file in the tree of a revision.
+struct Blob {
+    /// Object name.
+    object: String,
+    /// Unix permission bits.
+    mode: u32,
+}
+
+impl Revision {
+    /// Look up a revision (such as `HEAD`, a tag or a commit) in a local git repository.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Path of local git directory.
+    /// * `rev` - Revision to look up.
+    pub fn resolve(local_dir: &std::path::Path, rev: &str) -> Result<Revision> {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(local_dir)
+            .args(["show", "--no-patch", "--format=%H %ct", "--end-of-options"])
+            .arg(format!("{rev}^{{commit}}"))
+            .stderr(std::process::Stdio::null())
+            .output()
+            .context("failed to run git command")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("unknown revision: {rev}"));
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        let (commit, mtime) = output
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("malformed git show output: {output}"))?;
+        Ok(Revision {
+            local_dir: local_dir.to_path_buf(),
+            commit: commit.to_string(),
+            mtime: mtime.parse().context("failed to parse commit time")?,
+            blobs: std::collections::HashMap::new(),
+        })
+    }
+
+    /// Full name of the commit.
+    pub fn commit(&self) -> &str {
+        &self.commit
+    }
+
+    /// Scans the tree of the revision (below the local directory).
+    ///
+    /// Symlinks are never followed, since their targets are not part of the tree. Submodules are
+    /// skipped.
+    pub fn scan(&mut self) -> Result<DirectoryScanList> {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&self.local_dir)
+            // Prints `<mode> <type> <object> <size>\t<path>` for every blob, separated by NUL so
+            // that any file name can be read.
+            .args([
+                "ls-tree",
+                "-r",
+                "-l",
+                "-z",
+                "--end-of-options",
+                &self.commit,
+            ])
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .context("failed to run git command")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("git ls-tree failed: {}", output.status));
+        }
+
+        let mut directories = std::collections::BTreeSet::new();
+        let mut files = Vec::new();
+        let mut symlinks = Vec::new();
+        for entry in output.stdout.split(|&byte| byte == 0) {
+            if entry.is_empty() {
+                continue;
+            }
+            let malformed = || {
+                anyhow::anyhow!(
+                    "malformed git ls-tree output: {}",
+                    String::from_utf8_lossy(entry)
+                )
+            };
+            let tab = entry
+                .iter()
+                .position(|&byte| byte == b'\t')
+                .ok_or_else(malformed)?;
+            let fields = entry[..tab]
+                .split(|&byte| byte == b' ')
+                .filter(|field| !field.is_empty())
+                .collect::<Vec<_>>();
+            let [mode, object_type, object, size] = fields[..] else {
+                return Err(malformed());
+            };
+            if object_type != b"blob" {
+                continue;
+            }
+            let path = fs::path_from_bytes(&entry[tab + 1..]);
+            directories.extend(
+                path.ancestors()
+                    .skip(1)
+                    .filter(|ancestor| ancestor.components().count() > 0)
+                    .map(|ancestor| ancestor.to_path_buf()),
+            );
+            let blob = Blob {
+                object: String::from_utf8_lossy(object).into_owned(),
+                mode: if mode == GIT_MODE_EXECUTABLE {
+                    0o755
+                } else {
+                    0o644
+                },
+            };
+            if mode == GIT_MODE_SYMLINK {
+                symlinks.push(path.clone());
+            } else {
+                let size = std::str::from_utf8(size)
+                    .ok()
+                    .and_then(|size| size.parse().ok())
+                    .ok_or_else(malformed)?;
+                let mut file = File::new(path.clone(), size, self.mtime);
+                file.mode = Some(blob.mode);
+                files.push(file);
+            }
+            self.blobs.insert(path, blob);
+        }
+
+        // The target of a symlink is the contents of its blob.
+        let mut targets = Vec::new();
+        self.read_blobs(&symlinks, |_, blob| {
+            let mut target = Vec::new();
+            blob.read_to_end(&mut target)
+                .context("failed to read symlink target")?;
+            targets.push(fs::path_from_bytes(&target));
+            Ok(())
+        })?;
+        Ok(
+            DirectoryScanList::new(directories.into_iter().map(Directory::new).collect(), files)
+                .with_symlinks(
+                    symlinks
+                        .into_iter()
+                        .zip(targets)
+                        .map(|(path, target)| Symlink::new(path, target))
+                        .collect(),
+                ),
+        )
+    }
+
+    /// List the changes in the working tree since the revision, including uncommitted changes.
+    ///
+    /// A file that changed its type (such as a file that became a symlink) is listed as removed
+    /// and added. Submodules are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `untracked` - Whether or not to list untracked files that are not ignored as added.
+    pub fn changes(&self, untracked: bool) -> Result<TreeChanges> {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&self.local_dir)
+            // Prints `<status>\0<path>\0` for every change, and `R<score>\0<from>\0<to>\0` for
+            // renames. Comparing against the working tree includes staged and unstaged changes.
+            .args([
+                "diff",
+                "--name-status",
+                "-z",
+                "--find-renames",
+                "--relative",
+                "--ignore-submodules=all",
+                "--end-of-options",
+                &self.commit,
+            ])
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .context("failed to run git command")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("git diff failed: {}", output.status));
+        }
+
+        let mut changes = TreeChanges::default();
+        let mut fields = output
+            .stdout
+            .split(|&byte| byte == 0)
+            .filter(|field| !field.is_empty());
+        while let Some(status) = fields.next() {
+            let malformed = || {
+                anyhow::anyhow!(
+                    "malformed git diff output: {}",
+                    String::from_utf8_lossy(status)
+                )
+            };
+            let mut path = || fields.next().map(fs::path_from_bytes).ok_or_else(malformed);
+            match status[0] {
+                b'A' => {
+                    changes.added.insert(path()?);
+                }
+                // Unmerged files are modified in the working tree.
+                b'M' | b'U' => {
+                    changes.modified.insert(path()?);
+                }
+                b'D' => {
+                    changes.removed.insert(path()?);
+                }
+                b'T' => {
+                    let path = path()?;
+                    changes.removed.insert(path.clone());
+                    changes.added.insert(path);
+                }
+                b'R' => {
+                    let (from, to) = (path()?, path()?);
+                    changes.renamed.push((from, to, &status[1..] != b"100"));
+                }
+                _ => return Err(malformed()),
+            }
+        }
+
+        if untracked {
+            let output = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&self.local_dir)
+                .args(["ls-files", "--others", "--exclude-standard", "-z"])
+                .stderr(std::process::Stdio::inherit())
+                .output()
+                .context("failed to run git command")?;
+            if !output.status.success() {
+                return Err(anyhow::anyhow!("git ls-files failed: {}", output.status));
+            }
+            changes.added.extend(
+                output
+                    .stdout
+                    .split(|&byte| byte == 0)
+                    .filter(|path| !path.is_empty())
+                    .map(fs::path_from_bytes),
+            );
+        }
+        Ok(changes)
+    }
+
+    /// List the directories in the tree of the revision (below the local directory).
+    pub fn directories(&self) -> Result<std::collections::HashSet<std::path::PathBuf>> {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&self.local_dir)
+            .args([
+                "ls-tree",
+                "-r",
+                "-d",
+                "--name-only",
+                "-z",
+                "--end-of-options",
+                &self.commit,
+            ])
+            .stderr(std::process::Stdio::inherit())
+            .output()
+            .context("failed to run git command")?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!("git ls-tree failed: {}", output.status));
+        }
+        Ok(output
+            .stdout
+            .split(|&byte| byte == 0)
+            .filter(|path| !path.is_empty())
+            .map(fs::path_from_bytes)
+            .collect())
+    }
+
+    /// Write files of the revision to a temporary directory, with the commit time as their
+    /// modification time. The directory is removed when the returned checkout is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `paths` - Paths of files to write (which must have been scanned).
+    pub fn checkout(&self, paths: &[std::path::PathBuf]) -> Result<Checkout> {
+        let checkout = Checkout::create(&self.commit[..12.min(self.commit.len())])?;
+        self.read_blobs(paths, |i, blob| {
+            let path = checkout.path.join(&paths[i]);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).context("failed to create directory")?;
+            }
+            let mut file = std::fs::File::create(&path).context("failed to create file")?;
+            std::io::copy(blob, &mut file).context("failed to write file")?;
+            file.set_modified(fs::system_time(self.mtime)?)
+                .context("failed to set file modification time")?;
+            fs::set_mode(&path, self.blobs[&paths[i]].mode)
+        })?;
+        Ok(checkout)
+    }
+
+    /// Compute the hex-encoded SHA-256 digests of the contents of files in the revision.
+    ///
+    /// # Arguments
+    ///
+    /// * `paths` - Paths of files (which must have been scanned).
+    pub fn checksums(&self, paths: &[std::path::PathBuf]) -> Result<Vec<String>> {
+        let mut checksums = Vec::with_capacity(paths.len());
+        self.read_blobs(paths, |_, blob| {
+            let mut hasher = sha2::Sha256::new();
+            std::io::copy(blob, &mut hasher).context("failed to read blob for hashing")?;
+            checksums.push(
+                hasher
+                    .finalize()
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect(),
+            );
+            Ok(())
+        })?;
+        Ok(checksums)
+    }
+
+    /// Stream the blobs of files from the git object database, in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `paths` - Paths of files (which must have been scanned).
+    /// * `f` - Function that is called with the index of every file and a reader for its blob.
+    fn read_blobs(
+        &self,
+        paths: &[std::path::PathBuf],
+        mut f: impl FnMut(usize, &mut dyn Read) -> Result<()>,
+    ) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let objects = paths
+            .iter()
+            .map(|path| {
+                self.blobs
+                    .get(path)
+                    .map(|blob| blob.object.as_str())
+                    .ok_or_else(|| anyhow::anyhow!("file not in revision: {}", path.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut process = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&self.local_dir)
+            .args(["cat-file", "--batch"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::inherit())
+            .spawn()
+            .context("failed to spawn git command")?;
+        let mut stdin = std::io::BufWriter::new(process.stdin.take().unwrap());
+        let stdout = std::io::BufReader::new(process.stdout.take().unwrap());
+        // Object names are written on a separate thread while blobs are read, so that neither pipe
+        // can fill up and block the process.
+        let result = std::thread::scope(|scope| -> Result<()> {
+            scope.spawn(move || -> std::io::Result<()> {
+                for object in objects {
+                    writeln!(stdin, "{object}")?;
+                }
+                stdin.flush()
+            });
+            // Stdout is closed before the writer is joined, so that the process cannot block it
+            // when reading fails halfway.
+            let mut stdout = stdout;
+            let mut read = || -> Result<()> {
+                for i in 0..paths.len() {
+                    // Every blob is preceded by `<object> blob <size>` and followed by a line break.
+                    let mut header = String::new();
+                    stdout
+                        .read_line(&mut header)
+                        .context("failed to read from git cat-file")?;
+                    let size = header
+                        .trim_end()
+                        .rsplit_once(' ')
+                        .and_then(|(_, size)| size.parse::<u64>().ok())
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("malformed git cat-file output: {header}")
+                        })?;
+                    let mut blob = (&mut stdout).take(size);
+                    f(i, &mut blob)?;
+                    std::io::copy(&mut blob, &mut std::io::sink())
+                        .and_then(|_| stdout.read_exact(&mut [0]))
+                        .context("failed to read from git cat-file")?;
+                }
+                Ok(())
+            };
+            let result = read();
+            drop(stdout);
+            result
+        });
+        let exit_status = process.wait().context("failed to run git command")?;
+        result?;
+        if !exit_status.success() {
+            return Err(anyhow::anyhow!("git cat-file failed: {exit_status}"));
+        }
+        Ok(())
+    }
+}
+
+/// Temporary directory with files of a revision, as written by [`Revision::checkout`].
+pub struct Checkout {
+    path: std::path::PathBuf,
+}
+
+impl Checkout {
+    /// Create a new temporary directory that only the current user can access.
+    ///
+    /// The directory is never one that already exists, since the temporary directory is shared
+    /// with other users, who could otherwise have created it first to read or replace the files.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name to include in the name of the directory.
+    fn create(name: &str) -> Result<Checkout> {
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        for attempt in 0..100 {
+            let path = std::env::temp_dir().join(format!(
+                "git-repo-sync-{name}-{}-{}",
+                std::process::id(),
+                nanos.wrapping_add(attempt)
+            ));
+            match builder.create(&path) {
+                Ok(()) => return Ok(Checkout { path }),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err).context("failed to create temporary directory"),
+            }
+        }
+        Err(anyhow::anyhow!("failed to create temporary directory"))
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
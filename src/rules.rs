@@ -0,0 +1,267 @@
+use anyhow::{Context, Result};
+
+/// Name of the file in the local directory with extra rules (in gitignore syntax).
+pub const RULES_FILE_NAME: &str = ".reposyncignore";
+
+/// Extra rules that decide which items are synchronized, on top of the gitignore rules.
+///
+/// The rules take precedence over the gitignore rules, so they can exclude tracked files as well as
+/// include ignored files. From highest to lowest precedence:
+///
+/// 1. `--include` patterns,
+/// 2. `--exclude` patterns,
+/// 3. `.reposyncignore` (where a pattern excludes items, and a pattern with `!` includes them),
+/// 4. gitignore rules.
+///
+/// Within a level, the last matching pattern wins (as with gitignore), so a `--include` or
+/// `--exclude` pattern with `!` takes back an earlier one of the same kind.
+#[derive(Debug, Clone)]
+pub struct SyncRules {
+    includes: ignore::gitignore::Gitignore,
+    excludes: ignore::gitignore::Gitignore,
+    file: ignore::gitignore::Gitignore,
+    /// Directories that lead to an item that an include pattern names, such as `a` and `a/b` for
+    /// `a/b/c`.
+    include_prefixes: ignore::gitignore::Gitignore,
+    /// Directories inside of which include patterns match at any depth, such as `a` for `a/**/c`.
+    include_subtrees: ignore::gitignore::Gitignore,
+    /// Whether or not an include pattern matches at any depth, such as `*.so` or `**/c`.
+    includes_anywhere: bool,
+}
+
+impl SyncRules {
+    /// Load the rules from `.reposyncignore` (if it exists) and the given patterns.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Path of local directory.
+    /// * `excludes` - Patterns of items to exclude.
+    /// * `includes` - Patterns of items to include.
+    pub fn load(
+        local_dir: &std::path::Path,
+        excludes: &[String],
+        includes: &[String],
+    ) -> Result<SyncRules> {
+        let mut file = ignore::gitignore::GitignoreBuilder::new(local_dir);
+        // Include patterns are the `--include` patterns that are not negated, and the
+        // `.reposyncignore` patterns that are.
+        let mut include_patterns = includes
+            .iter()
+            .filter_map(|pattern| match parse_pattern(pattern)? {
+                (false, pattern) => Some(pattern.to_string()),
+                (true, _) => None,
+            })
+            .collect::<Vec<_>>();
+        let rules_path = local_dir.join(RULES_FILE_NAME);
+        if rules_path.exists() {
+            if let Some(err) = file.add(&rules_path) {
+                return Err(err).with_context(|| format!("failed to read {RULES_FILE_NAME}"));
+            }
+            let contents = std::fs::read_to_string(&rules_path)
+                .with_context(|| format!("failed to read {RULES_FILE_NAME}"))?;
+            include_patterns.extend(contents.lines().filter_map(|line| {
+                match parse_pattern(line)? {
+                    (true, pattern) => Some(pattern.to_string()),
+                    (false, _) => None,
+                }
+            }));
+        }
+        let build = |patterns: &[String], kind: &str| -> Result<ignore::gitignore::Gitignore> {
+            let mut builder = ignore::gitignore::GitignoreBuilder::new(local_dir);
+            for pattern in patterns {
+                builder
+                    .add_line(None, pattern)
+                    .with_context(|| format!("invalid {kind} pattern: {pattern}"))?;
+            }
+            builder.build().context("failed to build sync rules")
+        };
+
+        let mut prefixes = Vec::new();
+        let mut subtrees = Vec::new();
+        let mut includes_anywhere = false;
+        for pattern in &include_patterns {
+            let pattern = pattern.trim_end();
+            let pattern = pattern.strip_suffix('/').unwrap_or(pattern);
+            // As with gitignore, a pattern without a slash matches at any depth.
+            if !pattern.contains('/') {
+                includes_anywhere |= !pattern.is_empty();
+                continue;
+            }
+            let components = pattern
+                .trim_start_matches('/')
+                .split('/')
+                .collect::<Vec<_>>();
+            for (i, component) in components.iter().enumerate() {
+                if component.contains("**") {
+                    match i {
+                        0 => includes_anywhere = true,
+                        _ => subtrees.push(format!("/{}/", components[..i].join("/"))),
+                    }
+                    break;
+                } else if i + 1 < components.len() {
+                    prefixes.push(format!("/{}/", components[..=i].join("/")));
+                }
+            }
+        }
+
+        Ok(SyncRules {
+            includes: build(includes, "include")?,
+            excludes: build(excludes, "exclude")?,
+            file: file.build().context("failed to build sync rules")?,
+            include_prefixes: build(&prefixes, "include")?,
+            include_subtrees: build(&subtrees, "include")?,
+            includes_anywhere,
+        })
+    }
+
+    /// Decide whether or not an item is synchronized. Returns `None` if no rule matches the item
+    /// (or any of its parent directories), in which case the gitignore rules decide.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of item, relative to the local directory.
+    /// * `is_dir` - Whether or not the item is a directory.
+    pub fn is_included(&self, path: &std::path::Path, is_dir: bool) -> Option<bool> {
+        if self
+            .includes
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return Some(true);
+        }
+        if self
+            .excludes
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return Some(false);
+        }
+        match self.file.matched_path_or_any_parents(path, is_dir) {
+            ignore::Match::None => None,
+            ignore::Match::Ignore(_) => Some(false),
+            ignore::Match::Whitelist(_) => Some(true),
+        }
+    }
+
+    /// Whether or not an include pattern can match an item inside a directory, in which case the
+    /// directory must be scanned even when it is excluded or ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of directory, relative to the local directory.
+    pub fn may_include_inside(&self, path: &std::path::Path) -> bool {
+        self.includes_anywhere
+            || self.include_prefixes.matched(path, true).is_ignore()
+            || self
+                .include_subtrees
+                .matched_path_or_any_parents(path, true)
+                .is_ignore()
+    }
+}
+
+/// Split a pattern in gitignore syntax into whether or not it is negated and the rest of the
+/// pattern, the way `GitignoreBuilder::add_line` does. An escaped `\!` is kept as it is, since it
+/// matches a literal `!`. Returns `None` for blank lines and comments.
+///
+/// # Arguments
+///
+/// * `line` - Pattern.
+fn parse_pattern(line: &str) -> Option<(bool, &str)> {
+    if line.starts_with('#') || line.trim_end().is_empty() {
+        return None;
+    }
+    match line.strip_prefix('!') {
+        Some(pattern) => Some((true, pattern)),
+        None => Some((false, line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(file: &str, excludes: &[&str], includes: &[&str]) -> SyncRules {
+        let local_dir = std::env::temp_dir().join(format!(
+            "git-repo-sync-rules-test-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        std::fs::create_dir(&local_dir).unwrap();
+        std::fs::write(local_dir.join(RULES_FILE_NAME), file).unwrap();
+        let strings =
+            |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let rules = SyncRules::load(&local_dir, &strings(excludes), &strings(includes)).unwrap();
+        std::fs::remove_dir_all(&local_dir).unwrap();
+        rules
+    }
+
+    #[test]
+    fn test_precedence() {
+        let rules = load("dir/\n!dir/file\n", &[], &[]);
+        assert_eq!(rules.is_included("dir/file".as_ref(), false), Some(true));
+        assert_eq!(rules.is_included("dir/other".as_ref(), false), Some(false));
+        assert_eq!(rules.is_included("other".as_ref(), false), None);
+
+        // An exclude pattern beats an include pattern in `.reposyncignore`, even for a path inside
+        // the excluded directory.
+        let rules = load("!dir/file\n", &["dir/"], &[]);
+        assert_eq!(rules.is_included("dir/file".as_ref(), false), Some(false));
+
+        let rules = load("", &["dir/"], &["dir/file"]);
+        assert_eq!(rules.is_included("dir/file".as_ref(), false), Some(true));
+        assert_eq!(rules.is_included("dir/other".as_ref(), false), Some(false));
+    }
+
+    #[test]
+    fn test_may_include_inside() {
+        let rules = load("!/target/release/app\n", &[], &["gen/**/*.rs"]);
+        assert!(rules.may_include_inside("target".as_ref()));
+        assert!(rules.may_include_inside("target/release".as_ref()));
+        assert!(!rules.may_include_inside("target/debug".as_ref()));
+        assert!(!rules.may_include_inside("node_modules".as_ref()));
+        assert!(rules.may_include_inside("gen".as_ref()));
+        assert!(rules.may_include_inside("gen/a/b".as_ref()));
+
+        let rules = load("", &[], &["*.so"]);
+        assert!(rules.may_include_inside("target".as_ref()));
+    }
+
+    #[test]
+    fn test_escaped_negation() {
+        // `\!` names an item that starts with `!`, so it excludes that item instead of including it.
+        let rules = load("\\!dir/file\n", &[], &[]);
+        assert_eq!(rules.is_included("!dir/file".as_ref(), false), Some(false));
+        assert_eq!(rules.is_included("dir/file".as_ref(), false), None);
+        assert!(!rules.may_include_inside("dir".as_ref()));
+        assert!(!rules.may_include_inside("!dir".as_ref()));
+
+        let rules = load("", &[], &["\\!dir/file"]);
+        assert_eq!(rules.is_included("!dir/file".as_ref(), false), Some(true));
+        assert!(rules.may_include_inside("!dir".as_ref()));
+        assert!(!rules.may_include_inside("dir".as_ref()));
+    }
+
+    #[test]
+    fn test_negated_include() {
+        // A negated include pattern takes back an earlier one, and does not include anything itself.
+        let rules = load("", &["target/"], &["target/**/*.so", "!target/debug/**"]);
+        assert_eq!(
+            rules.is_included("target/release/a.so".as_ref(), false),
+            Some(true)
+        );
+        assert_eq!(
+            rules.is_included("target/debug/a.so".as_ref(), false),
+            Some(false)
+        );
+        assert!(rules.may_include_inside("target".as_ref()));
+        assert!(!rules.may_include_inside("!target".as_ref()));
+
+        let rules = load("", &[], &["!dir/file"]);
+        assert_eq!(rules.is_included("dir/file".as_ref(), false), None);
+        assert!(!rules.may_include_inside("dir".as_ref()));
+        assert!(!rules.may_include_inside("!dir".as_ref()));
+    }
+}
@@ -1,257 +1,721 @@
-use std::io::{BufRead, Write};
-
 use anyhow::{Context, Result};
 
 use path_slash::PathExt;
 
-use crate::fs::{Directory, File};
-use crate::host::Host;
+use crate::fs::{self, Directory, File, Symlink};
+use crate::gitignore;
+use crate::host::{Host, Platform};
+use crate::rules::SyncRules;
+use crate::transport::ssh;
+
+/// Mode of a submodule entry in the git index.
+const GIT_MODE_SUBMODULE: u32 = 0o160000;
+
+/// Maximum number of directories that a remote scan prunes, which keeps the scan command well
+/// within the limits on command line length.
+const MAX_PRUNED_DIRECTORIES: usize = 1000;
 
-#[derive(Debug)]
+/// Options for scanning a directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Whether or not to follow symlinks and scan what they point to, instead of scanning the
+    /// symlinks themselves.
+    pub follow_symlinks: bool,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct DirectoryScanList {
     directories: Vec<Directory>,
     files: Vec<File>,
+    symlinks: Vec<Symlink>,
 }
 
 impl DirectoryScanList {
-    /// Scans a local directory.
+    pub fn new(directories: Vec<Directory>, files: Vec<File>) -> DirectoryScanList {
+        DirectoryScanList {
+            directories,
+            files,
+            symlinks: Vec::new(),
+        }
+    }
+
+    /// Replace the symlinks in the directory scan list.
     ///
-    /// Recursively finds every item in the directory. If one or more entries cannot be walked, the
-    /// function fails as a whole.
+    /// # Arguments
+    ///
+    /// * `symlinks` - Symlinks.
+    pub fn with_symlinks(mut self, symlinks: Vec<Symlink>) -> DirectoryScanList {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Scans a local directory.
     ///
-    /// Note: Symlinks are ignored.
+    /// Recursively finds every item in the directory, walking directories in parallel. If one or
+    /// more entries cannot be walked, the function fails as a whole.
     ///
     /// # Arguments
     ///
     /// * `root` - Path of root directory to scan.
-    pub fn from_local_file_system(root: &std::path::Path) -> Result<DirectoryScanList> {
-        let mut directories = Vec::new();
-        let mut files = Vec::new();
-        for entry in walkdir::WalkDir::new(root).into_iter() {
-            let entry = entry.context("failed to walk entry")?;
-            let relative_path = entry.path().strip_prefix(root).unwrap().to_path_buf();
-            if entry.file_type().is_file() {
-                files.push(File::new(
-                    relative_path,
-                    entry
-                        .metadata()
-                        .context("failed to fetch file metadata")?
-                        .len(),
-                ));
-            } else if entry.file_type().is_dir() && relative_path.components().count() > 0 {
-                directories.push(Directory::new(relative_path));
+    /// * `options` - Scan options.
+    /// * `prune` - Whether or not a directory (relative to the root directory) is listed, but not
+    ///   descended into.
+    pub fn from_local_file_system(
+        root: &std::path::Path,
+        options: ScanOptions,
+        prune: &(dyn Fn(&std::path::Path) -> bool + Sync),
+    ) -> Result<DirectoryScanList> {
+        let mut scan = DirectoryScanList::default();
+        walk_local_file_system(root, root, options, prune, &mut scan)?;
+        scan.directories.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.files.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.symlinks.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(scan)
+    }
+
+    /// Scans the files in a local git directory that are tracked by git.
+    ///
+    /// The files are listed with `git ls-files`, and their metadata is read from the working tree.
+    /// Tracked files that were deleted from the working tree and submodules are skipped. The parent
+    /// directories of every file are included as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Path of local git directory.
+    /// * `options` - Scan options.
+    pub fn from_git_index(
+        root: &std::path::Path,
+        options: ScanOptions,
+    ) -> Result<DirectoryScanList> {
+        let mut scan = DirectoryScanList::default();
+        let mut directories = std::collections::BTreeSet::new();
+        for (mode, path) in git_ls_files(root)? {
+            let full_path = root.join(&path);
+            let metadata = match std::fs::symlink_metadata(&full_path) {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err).context("failed to fetch file metadata"),
+            };
+            if mode == GIT_MODE_SUBMODULE {
+                continue;
+            }
+            if metadata.is_symlink() && options.follow_symlinks && full_path.is_dir() {
+                walk_local_file_system(root, &full_path, options, &|_| false, &mut scan)?;
+            } else if metadata.is_symlink() && !(options.follow_symlinks && full_path.is_file()) {
+                let target = std::fs::read_link(&full_path).context("failed to read symlink")?;
+                scan.symlinks.push(Symlink::new(path.clone(), target));
+            } else {
+                let metadata =
+                    std::fs::metadata(&full_path).context("failed to fetch file metadata")?;
+                let mut file = File::new(
+                    path.clone(),
+                    metadata.len(),
+                    fs::mtime(&metadata).context("failed to fetch file modification time")?,
+                );
+                file.mode = fs::mode(&metadata);
+                scan.files.push(file);
+            }
+            directories.extend(
+                path.ancestors()
+                    .skip(1)
+                    .filter(|ancestor| ancestor.components().count() > 0)
+                    .map(|ancestor| ancestor.to_path_buf()),
+            );
+        }
+        scan.directories
+            .extend(directories.into_iter().map(Directory::new));
+        scan.directories.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.directories.dedup();
+        scan.files.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.symlinks.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(scan)
+    }
+
+    /// Scans a selection of paths in a local directory.
+    ///
+    /// Directories are scanned recursively. The existing parent directories of every path are
+    /// included as well. Paths that do not exist (anymore) are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Path of root directory.
+    /// * `paths` - Paths to scan, relative to the root directory.
+    /// * `options` - Scan options.
+    pub fn from_local_paths(
+        root: &std::path::Path,
+        paths: &std::collections::BTreeSet<std::path::PathBuf>,
+        options: ScanOptions,
+    ) -> Result<DirectoryScanList> {
+        let mut scan = DirectoryScanList::default();
+        for path in paths {
+            if std::fs::symlink_metadata(root.join(path)).is_ok() {
+                walk_local_file_system(root, &root.join(path), options, &|_| false, &mut scan)?;
             }
+            scan.directories.extend(
+                path.ancestors()
+                    .skip(1)
+                    .filter(|ancestor| {
+                        ancestor.components().count() > 0 && root.join(ancestor).is_dir()
+                    })
+                    .map(|ancestor| Directory::new(ancestor.to_path_buf())),
+            );
         }
-        Ok(DirectoryScanList { directories, files })
+        scan.directories.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.directories.dedup();
+        scan.files.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.files.dedup_by(|a, b| a.path == b.path);
+        scan.symlinks.sort_by(|a, b| a.path.cmp(&b.path));
+        scan.symlinks.dedup_by(|a, b| a.path == b.path);
+        Ok(scan)
     }
 
     /// Scans a remote directory.
     ///
     /// Internally, this function issues a `find` command on the remote host over SSH.
     ///
-    /// Note: Symlinks are ignored.
-    ///
     /// # Arguments
     ///
     /// * `path` - Path to scan.
     /// * `target` - SSH host to scan.
+    /// * `options` - Scan options.
+    /// * `prune` - Directories (relative to the path) that are listed, but not descended into.
     pub fn from_remote_over_ssh(
         path: &std::path::Path,
         target: &Host,
+        options: ScanOptions,
+        prune: &[std::path::PathBuf],
     ) -> Result<DirectoryScanList> {
-        let output = std::process::Command::new("ssh")
-            .args([
-                format!("{target}"),
-                // This command indexes the remote directory and file structure:
-                //
-                // First, it runs `mkdir -p` to create the target directory if it does not yet
-                // exist.
-                //
-                // The `find` command is used to list all files and directories on the remote.
-                // We're only interested in files and directories. The most portable method for
-                // speciyfing this is by splitting up the invocation in two and use the `-o` option
-                // to indicate that both invocations match. Apart from selecting a different type
-                // of `-type f` versus `-type d`, the invocations are equivalent.
-                //
-                // The `-printf` options is used to format each file with the info that we'll be
-                // needing:
-                // * `%P`: the file path relative to the starting-point (the target directory).
-                // * `%y`: the file type: `d` for directory, `f` for file.
-                // * `%s`: the file size in bytes.
-                //
-                // The `-mindepth 1` makes sure that `find` does not print the starting-point
-                // directory (we do not need it).
-                format!(
-                    "mkdir -p {0}; find {0} -type f -printf \"%P %y %s\n\" -mindepth 1 -o -type d -printf \"%P %y %s\n\" -mindepth 1",
-                    path.to_slash_lossy()
-                ),
-            ])
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()
-            .context("failed to spawn ssh command")?
-            .wait_with_output()
-            .context("failed to run ssh command")?;
-        if output.status.success() {
-            let stdout = String::from_utf8(output.stdout).unwrap_or_default();
-            let stdout_lines = stdout.trim().lines();
-            let mut directories = Vec::new();
-            let mut files = Vec::new();
-            for line in stdout_lines {
-                if let Some((entry_p1, entry_size)) = line.trim().rsplit_once(' ') {
-                    if let Some((entry_path, entry_type)) = entry_p1.trim().rsplit_once(' ') {
-                        let path = std::path::Path::new(entry_path).to_path_buf();
-                        match entry_type.trim() {
-                            "f" => files.push(File::new(
-                                path,
-                                entry_size.parse().context("failed to parse file size")?,
-                            )),
-                            "d" => {
-                                if path.components().count() > 0 {
-                                    directories.push(Directory::new(path));
-                                }
-                            }
-                            _ => {
-                                return Err(anyhow::anyhow!(
-                                    "malformed find output line (incorrect file type): {line}"
-                                ))
-                            }
-                        }
-                    } else {
-                        return Err(anyhow::anyhow!("malformed find output line: {line}"));
+        let stdout = ssh::run(
+            target,
+            &Self::remote_scan_command(path, target.platform(), options, prune)?,
+        )?;
+        Self::from_remote_scan_output(&stdout)
+    }
+
+    /// Build the shell command that scans a remote directory.
+    ///
+    /// The output of the command can be parsed with [`DirectoryScanList::from_remote_scan_output`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to scan.
+    /// * `platform` - Operating system family of the remote host.
+    /// * `options` - Scan options.
+    /// * `prune` - Directories (relative to the path) that are listed, but not descended into.
+    pub fn remote_scan_command(
+        path: &std::path::Path,
+        platform: Platform,
+        options: ScanOptions,
+        prune: &[std::path::PathBuf],
+    ) -> Result<String> {
+        if platform == Platform::Windows {
+            return Self::remote_windows_scan_command(path, options, prune);
+        }
+        // This command indexes the remote directory and file structure:
+        //
+        // First, it runs `mkdir -p` to create the target directory if it does not yet
+        // exist.
+        //
+        // The `find` command is used to list all files and directories on the remote.
+        // We're only interested in files and directories. The most portable method for
+        // speciyfing this is by splitting up the invocation in two and use the `-o` option
+        // to indicate that both invocations match. Apart from selecting a different type
+        // of `-type f` versus `-type d`, the invocations are equivalent.
+        //
+        // The `-printf` options is used to format each item as six NUL-terminated fields, since
+        // NUL is the only byte that cannot occur in a file name:
+        // * The file type: `d` for directory, `f` for file, `l` for symlink.
+        // * `%s`: the file size in bytes.
+        // * `%T@`: the last modification time in seconds since the epoch (with a fractional part).
+        // * `%m`: the permission bits in octal.
+        // * `%P`: the file path relative to the starting-point (the target directory).
+        // * `%l`: the target of a symlink.
+        //
+        // The `-mindepth 1` makes sure that `find` does not print the starting-point
+        // directory (we do not need it).
+        //
+        // Symlinks are printed by a third invocation. When following symlinks (`-L`), only broken
+        // symlinks are printed this way.
+        //
+        // Only GNU `find` supports `-printf`, so whether or not it is available is detected in the
+        // same round trip. Without it (such as on macOS and BusyBox), a portable scanner prints the
+        // same fields with `stat` and `readlink` instead.
+        //
+        // Pruned directories are matched first. They are printed as directories, but `-prune` keeps
+        // `find` from descending into them.
+        let follow = if options.follow_symlinks { "-L " } else { "" };
+        let prune_gnu = match find_prune_expression(&path.to_slash_lossy(), prune) {
+            Some(expression) => {
+                format!("{expression} -printf 'd\\0%s\\0%T@\\0%m\\0%P\\0\\0' -o ")
+            }
+            None => String::new(),
+        };
+        let prune_dot = find_prune_expression(".", prune);
+        let path = ssh::shell_quote(&path.to_slash_lossy());
+        Ok(format!(
+            "mkdir -p {path}; if find {path} -maxdepth 0 -printf '' >/dev/null 2>&1; then find {follow}{path} -mindepth 1 {prune_gnu}-type f -printf 'f\\0%s\\0%T@\\0%m\\0%P\\0\\0' -o -mindepth 1 -type d -printf 'd\\0%s\\0%T@\\0%m\\0%P\\0\\0' -o -mindepth 1 -type l -printf 'l\\0\\0\\0\\0%P\\0%l\\0'; else {}; fi",
+            Self::remote_portable_scan_command(&path, follow, prune_dot.as_deref()),
+        ))
+    }
+
+    /// Build the PowerShell command that scans a remote directory on a Windows host, for
+    /// [`DirectoryScanList::remote_scan_command`].
+    ///
+    /// The command prints the same fields as the `find` scanners, except for the permissions
+    /// (which Windows does not have).
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to scan.
+    /// * `options` - Scan options.
+    /// * `prune` - Directories (relative to the path) that are listed, but not descended into.
+    fn remote_windows_scan_command(
+        path: &std::path::Path,
+        options: ScanOptions,
+        prune: &[std::path::PathBuf],
+    ) -> Result<String> {
+        if options.follow_symlinks {
+            return Err(anyhow::anyhow!(
+                "following symlinks is not supported on Windows hosts"
+            ));
+        }
+        // The directory tree is walked by hand instead of with `Get-ChildItem -Recurse`, which
+        // follows symlinks in some versions of PowerShell. The output is written to stdout as raw
+        // UTF-8 bytes, so that PowerShell does not re-encode it.
+        let script = format!(
+            r#"$ErrorActionPreference = 'Stop'
+$root = (New-Item -ItemType Directory -Force -Path '{}').FullName.TrimEnd('\')
+$stdout = [Console]::OpenStandardOutput()
+function Write-Output-Bytes($text) {{
+  $bytes = [Text.Encoding]::UTF8.GetBytes($text)
+  $stdout.Write($bytes, 0, $bytes.Length)
+}}
+$prune = @{{{}}}
+$directories = New-Object Collections.Stack
+$directories.Push($root)
+while ($directories.Count -gt 0) {{
+  foreach ($item in (New-Object IO.DirectoryInfo $directories.Pop()).EnumerateFileSystemInfos()) {{
+    $path = $item.FullName.Substring($root.Length + 1).Replace('\', '/')
+    $mtime = [DateTimeOffset]::new($item.LastWriteTimeUtc).ToUnixTimeSeconds()
+    if ($item.Attributes -band [IO.FileAttributes]::ReparsePoint) {{
+      $target = "$((Get-Item -LiteralPath $item.FullName -Force).Target)".Replace('\', '/')
+      Write-Output-Bytes "l`0`0`0`0$path`0$target`0"
+    }} elseif ($item -is [IO.DirectoryInfo]) {{
+      Write-Output-Bytes "d`00`0$mtime`0`0$path`0`0"
+      if (-not $prune.ContainsKey($path)) {{
+        $directories.Push($item.FullName)
+      }}
+    }} else {{
+      Write-Output-Bytes "f`0$($item.Length)`0$mtime`0`0$path`0`0"
+    }}
+  }}
+}}
+"#,
+            path.to_string_lossy().replace('\'', "''"),
+            prune
+                .iter()
+                .map(|path| format!("'{}' = $true", path.to_slash_lossy().replace('\'', "''")))
+                .collect::<Vec<_>>()
+                .join("; "),
+        );
+        Ok(ssh::powershell_command(&script))
+    }
+
+    /// Build the shell command that scans a remote directory without GNU `find`, for
+    /// [`DirectoryScanList::remote_scan_command`].
+    ///
+    /// The command only relies on POSIX `find` and `sh`, and on `stat` with either the GNU and
+    /// BusyBox flavor (`-c`) or the BSD flavor (`-f`) of format strings, which is detected first.
+    /// It prints the same fields as the GNU `find` scanner.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Quoted path to scan.
+    /// * `follow` - Flag to make `find` follow symlinks (if any).
+    /// * `prune` - Expression that matches the directories to prune, relative to `.` (if any).
+    fn remote_portable_scan_command(path: &str, follow: &str, prune: Option<&str>) -> String {
+        // Every batch of paths is passed to a single `stat` invocation. Its output lines are in
+        // the same order as the paths, and do not include the paths themselves, so that a path
+        // with a line break cannot throw off the output.
+        let stat_script = ssh::shell_quote(
+            "type=$0 flag=$1 format=$2; shift 2; stat -L \"$flag\" \"$format\" \"$@\" | for path; do read -r size mtime mode || exit 1; printf '%s\\0%s\\0%s\\0%s\\0%s\\0\\0' \"$type\" \"$size\" \"$mtime\" \"$mode\" \"${path#./}\"; done",
+        );
+        let symlink_script = ssh::shell_quote(
+            "for path; do printf 'l\\0\\0\\0\\0%s\\0%s\\0' \"${path#./}\" \"$(readlink \"$path\")\"; done",
+        );
+        let prune = match prune {
+            Some(expression) => {
+                format!("{expression} -exec sh -c {stat_script} d \"$1\" \"$2\" {{}} + -o ")
+            }
+            None => String::new(),
+        };
+        format!(
+            "(cd {path} && if stat -c %s . >/dev/null 2>&1; then set -- -c '%s %Y %a'; else set -- -f '%z %m %Mp%Lp'; fi && find {follow}. {prune}! -path . -type f -exec sh -c {stat_script} f \"$1\" \"$2\" {{}} + -o ! -path . -type d -exec sh -c {stat_script} d \"$1\" \"$2\" {{}} + -o -type l -exec sh -c {symlink_script} sh {{}} +)"
+        )
+    }
+
+    /// Parse the output of the command built by [`DirectoryScanList::remote_scan_command`].
+    ///
+    /// # Arguments
+    ///
+    /// * `stdout` - Output of the remote scan command.
+    pub fn from_remote_scan_output(stdout: &[u8]) -> Result<DirectoryScanList> {
+        let mut directories = Vec::new();
+        let mut files = Vec::new();
+        let mut symlinks = Vec::new();
+        let mut rest = stdout;
+        while !rest.is_empty() {
+            let entry_type = next_field(&mut rest)?;
+            let entry_size = String::from_utf8_lossy(next_field(&mut rest)?);
+            let entry_mtime = String::from_utf8_lossy(next_field(&mut rest)?);
+            let entry_mode = String::from_utf8_lossy(next_field(&mut rest)?);
+            let path = fs::path_from_bytes(next_field(&mut rest)?);
+            let entry_target = next_field(&mut rest)?;
+            match entry_type {
+                b"f" => {
+                    let mut file = File::new(
+                        path,
+                        entry_size.parse().context("failed to parse file size")?,
+                        parse_find_mtime(&entry_mtime)?,
+                    );
+                    // Hosts without permission bits (Windows) print an empty field.
+                    if !entry_mode.is_empty() {
+                        file.mode = Some(
+                            u32::from_str_radix(&entry_mode, 8)
+                                .context("failed to parse file permissions")?,
+                        );
                     }
-                } else {
-                    return Err(anyhow::anyhow!("malformed find output line: {line}"));
+                    files.push(file);
+                }
+                b"d" => {
+                    if path.components().count() > 0 {
+                        directories.push(Directory::new(path));
+                    }
+                }
+                b"l" => {
+                    symlinks.push(Symlink::new(path, fs::path_from_bytes(entry_target)));
+                }
+                _ => {
+                    return Err(anyhow::anyhow!(
+                        "malformed find output (incorrect file type): {}",
+                        String::from_utf8_lossy(entry_type)
+                    ))
                 }
             }
-            Ok(DirectoryScanList { directories, files })
-        } else {
-            let stdout = String::from_utf8(output.stdout)
-                .unwrap_or_default()
-                .trim()
-                .to_string();
-            let stderr = String::from_utf8(output.stderr)
-                .unwrap_or_default()
-                .trim()
-                .to_string();
-            let reason = match (!stdout.is_empty(), !stderr.is_empty()) {
-                (true, true) => format!("{stdout} {stderr}"),
-                (true, false) => stdout,
-                (false, true) => stderr,
-                (false, false) => "<command has no output>".to_string(),
-            };
-            Err(anyhow::anyhow!(
-                "remote command failed with status code {}: {}",
-                output
-                    .status
-                    .code()
-                    .map(|code| code.to_string())
-                    .unwrap_or_else(|| "<no status code>".to_string()),
-                reason,
-            ))
         }
+        Ok(DirectoryScanList {
+            directories,
+            files,
+            symlinks,
+        })
+    }
+
+    /// Build the command that computes the checksums of files in a remote directory.
+    ///
+    /// The command reads the paths of the files from its input, as NUL-terminated paths relative to
+    /// the directory, and prints the output of `sha256sum` for them. Its output can be parsed with
+    /// [`parse_remote_checksums`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of directory.
+    /// * `platform` - Operating system family of the remote host.
+    pub fn remote_checksum_command(path: &std::path::Path, platform: Platform) -> String {
+        if platform == Platform::Windows {
+            // The paths are read as raw UTF-8 bytes, and the output is written in the same way,
+            // so that PowerShell does not re-encode them.
+            return ssh::powershell_command(&format!(
+                r#"$ErrorActionPreference = 'Stop'
+$root = '{}'
+$stdout = [Console]::OpenStandardOutput()
+$stdin = New-Object IO.MemoryStream
+[Console]::OpenStandardInput().CopyTo($stdin)
+foreach ($path in [Text.Encoding]::UTF8.GetString($stdin.ToArray()).Split([char]0)) {{
+  if ($path) {{
+    $checksum = (Get-FileHash -Algorithm SHA256 -LiteralPath (Join-Path $root $path)).Hash.ToLower()
+    $bytes = [Text.Encoding]::UTF8.GetBytes("$checksum  $path`n")
+    $stdout.Write($bytes, 0, $bytes.Length)
+  }}
+}}
+"#,
+                path.to_string_lossy().replace('\'', "''"),
+            ));
+        }
+        // On remotes without `sha256sum` (such as macOS), `shasum` prints the same output.
+        format!(
+            "cd {} && if command -v sha256sum >/dev/null 2>&1; then set -- sha256sum; else set -- shasum -a 256; fi && xargs -0 \"$@\"",
+            ssh::shell_quote(&path.to_slash_lossy()),
+        )
     }
 
-    /// Create a filtered version of the directory scan list that only contains items matched by
-    /// `git` (with `gitignore` rules applied).
+    /// Compute the checksums of the files that are compared by their contents with the files in
+    /// other directory scan lists, if they do not have one yet.
+    ///
+    /// Files of different sizes always differ, so only files that have the same size as a file with
+    /// the same path in one of the other lists need a checksum. Computing them after the ignored
+    /// items were filtered out keeps ignored files from being read at all.
+    ///
+    /// # Arguments
+    ///
+    /// * `others` - Directory scan lists that the files are compared with.
+    /// * `checksums` - Function that computes the checksums of files (in the same order), given
+    ///   their paths.
+    pub fn compute_checksums(
+        &mut self,
+        others: &[&DirectoryScanList],
+        checksums: impl FnOnce(&[std::path::PathBuf]) -> Result<Vec<String>>,
+    ) -> Result<()> {
+        let sizes = others
+            .iter()
+            .flat_map(|other| other.files())
+            .map(|file| (file.path.as_path(), file.size))
+            .collect::<std::collections::HashSet<_>>();
+        let indices = self
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| {
+                file.checksum.is_none() && sizes.contains(&(file.path.as_path(), file.size))
+            })
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if indices.is_empty() {
+            return Ok(());
+        }
+        let paths = indices
+            .iter()
+            .map(|&i| self.files[i].path.clone())
+            .collect::<Vec<_>>();
+        for (i, checksum) in indices.into_iter().zip(checksums(&paths)?) {
+            self.files[i].checksum = Some(checksum);
+        }
+        Ok(())
+    }
+
+    /// Create a filtered version of the directory scan list that only contains items that are not
+    /// ignored by the `gitignore` rules, or that are included by the extra sync rules.
+    ///
+    /// The parent directories of the items that are kept are kept as well, since an item can be
+    /// included while its parent directory is ignored.
     ///
     /// # Arguments
     ///
     /// * `local_dir` - Path of local git directory.
+    /// * `rules` - Extra rules, which take precedence over the gitignore rules.
+    /// * `git_check_ignore` - Whether or not to evaluate the gitignore rules with
+    ///   `git check-ignore` instead of the built-in matcher.
     pub fn filter_by_gitignore(
         &mut self,
         local_dir: &std::path::Path,
+        rules: &SyncRules,
+        git_check_ignore: bool,
     ) -> Result<DirectoryScanList> {
-        let mut git_check_ignore_process = std::process::Command::new("git")
-            .args([
-                // Execute from local directory context.
-                "-C",
-                &local_dir.to_slash_lossy(),
-                // Git subcommand to check gitignore matching.
-                "check-ignore",
-                // By default `check-ignore` only returns the paths of ignored files. We also want
-                // to see any paths that were matched.
-                "--non-matching",
-                // Take input via stdin.
-                "--stdin",
-                // Include some extra information such as the line that actually matched. We use
-                // this to figure out if git included or excluded the file.
-                "--verbose",
-            ])
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()
-            .context("failed to spawn git command")?;
-
-        fn is_output_line_match(git_check_ignore_line: &str) -> Result<bool> {
-            let mut output_cols = git_check_ignore_line.split('\t');
-            let header = output_cols.next().ok_or(anyhow::anyhow!(
-                "git check-ignore output missing first part"
-            ))?;
-            let mut header_cols = header.split(':');
-            let _source = header_cols
-                .next()
-                .ok_or(anyhow::anyhow!("git check-ignore output missing source"))?;
-            let _linenum = header_cols
-                .next()
-                .ok_or(anyhow::anyhow!("git check-ignore output missing linenum"))?;
-            let pattern = header_cols
-                .next()
-                .ok_or(anyhow::anyhow!("git check-ignore output missing pattern"))?;
-            let path = output_cols
-                .next()
-                .ok_or(anyhow::anyhow!(
-                    "git check-ignore output missing second part"
-                ))?
-                .trim();
-            let is_git_dir = path == ".git" || path.starts_with(".git/");
-            Ok((pattern.is_empty() || pattern.starts_with('!')) && !is_git_dir)
-        }
+        with_gitignore_matcher(local_dir, rules, git_check_ignore, |is_matched| {
+            self.filter(is_matched)
+        })
+    }
 
-        let git_check_ignore_stdin = git_check_ignore_process.stdin.as_mut().unwrap();
-        let mut git_check_ignore_stdout =
-            std::io::BufReader::new(git_check_ignore_process.stdout.take().unwrap());
+    /// Create a filtered version of the directory scan list that only contains items that are not
+    /// excluded by the extra sync rules. The gitignore rules do not apply, which is the case for
+    /// files that are tracked by git.
+    ///
+    /// # Arguments
+    ///
+    /// * `rules` - Extra rules.
+    pub fn filter_by_rules(&self, rules: &SyncRules) -> Result<DirectoryScanList> {
+        self.filter(|path, is_dir| {
+            Ok(!path.starts_with(".git") && rules.is_included(path, is_dir) != Some(false))
+        })
+    }
 
+    /// Create a filtered version of the directory scan list that only contains the matched items,
+    /// and the parent directories of the items that are kept.
+    fn filter(
+        &self,
+        mut is_matched: impl FnMut(&std::path::Path, bool) -> Result<bool>,
+    ) -> Result<DirectoryScanList> {
         let mut matched_directories = Vec::new();
         for directory in &self.directories {
-            writeln!(
-                git_check_ignore_stdin,
-                "{}",
-                &directory.path.to_slash_lossy()
-            )
-            .context("failed to write to git check-ignore")?;
-            let mut output_line = String::new();
-            git_check_ignore_stdout.read_line(&mut output_line)?;
-            if is_output_line_match(&output_line)? {
+            if is_matched(&directory.path, true)? {
                 matched_directories.push(directory.clone());
             }
         }
 
         let mut matched_files = Vec::new();
         for file in &self.files {
-            writeln!(git_check_ignore_stdin, "{}", &file.path.to_slash_lossy())
-                .context("failed to write to git check-ignore")?;
-            let mut output_line = String::new();
-            git_check_ignore_stdout.read_line(&mut output_line)?;
-            if is_output_line_match(&output_line)? {
+            if is_matched(&file.path, false)? {
                 matched_files.push(file.clone());
             }
         }
 
-        let exit_status = git_check_ignore_process
-            .wait()
-            .context("failed to run git command")?;
-        match exit_status.code() {
-            // XXX: `git-check-ignore` returns 1 sometimes as part of normal operation
-            Some(0 | 1) => Ok(DirectoryScanList {
-                directories: matched_directories,
-                files: matched_files,
-            }),
-            _ => Err(anyhow::anyhow!("git check-ignore failed: {exit_status}")),
+        let mut matched_symlinks = Vec::new();
+        for symlink in &self.symlinks {
+            if is_matched(&symlink.path, false)? {
+                matched_symlinks.push(symlink.clone());
+            }
+        }
+
+        // Every ancestor is added only once, since the walk up stops at an ancestor that is kept
+        // already (whose own ancestors are added when it is visited itself).
+        let mut kept = matched_directories
+            .iter()
+            .map(|directory| directory.path.clone())
+            .collect::<std::collections::HashSet<_>>();
+        for path in matched_directories
+            .iter()
+            .map(|directory| &directory.path)
+            .chain(matched_files.iter().map(|file| &file.path))
+            .chain(matched_symlinks.iter().map(|symlink| &symlink.path))
+        {
+            for ancestor in path.ancestors().skip(1) {
+                if ancestor.as_os_str().is_empty() || !kept.insert(ancestor.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+        let matched_directories = self
+            .directories
+            .iter()
+            .filter(|directory| kept.contains(&directory.path))
+            .cloned()
+            .collect();
+        Ok(DirectoryScanList {
+            directories: matched_directories,
+            files: matched_files,
+            symlinks: matched_symlinks,
+        })
+    }
+
+    /// Find the directories that are not in a filtered version of the directory scan list, and are
+    /// not inside another such directory. A scan of the other end can skip descending into these
+    /// directories, since nothing inside them is synchronized (unless it exists on the other end
+    /// only).
+    ///
+    /// Returns no more than a fixed number of directories, the ones closest to the root first.
+    ///
+    /// # Arguments
+    ///
+    /// * `filtered` - Filtered version of the directory scan list.
+    pub fn pruned_directories(&self, filtered: &DirectoryScanList) -> Vec<std::path::PathBuf> {
+        let matched = filtered
+            .directories
+            .iter()
+            .map(|directory| directory.path.as_path())
+            .collect::<std::collections::HashSet<_>>();
+        let mut pruned = self
+            .directories
+            .iter()
+            .map(|directory| directory.path.as_path())
+            .filter(|path| {
+                !matched.contains(path)
+                    && path.parent().is_some_and(|parent| {
+                        parent.as_os_str().is_empty() || matched.contains(parent)
+                    })
+            })
+            .map(|path| path.to_path_buf())
+            .collect::<Vec<_>>();
+        pruned.sort_by_key(|path| path.components().count());
+        pruned.truncate(MAX_PRUNED_DIRECTORIES);
+        pruned
+    }
+
+    /// Create a version of the directory scan list that only contains the items that are tracked by
+    /// git in a local directory (whether or not they exist there), and their parent directories.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_dir` - Path of local git directory.
+    pub fn select_tracked(&self, local_dir: &std::path::Path) -> Result<DirectoryScanList> {
+        let tracked = git_ls_files(local_dir)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect::<std::collections::HashSet<_>>();
+        let tracked_directories = tracked
+            .iter()
+            .flat_map(|path| path.ancestors().skip(1))
+            .collect::<std::collections::HashSet<_>>();
+        Ok(DirectoryScanList {
+            directories: self
+                .directories
+                .iter()
+                .filter(|directory| tracked_directories.contains(directory.path.as_path()))
+                .cloned()
+                .collect(),
+            files: self
+                .files
+                .iter()
+                .filter(|file| tracked.contains(&file.path))
+                .cloned()
+                .collect(),
+            symlinks: self
+                .symlinks
+                .iter()
+                .filter(|symlink| tracked.contains(&symlink.path))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Create a version of the directory scan list that only contains the given paths, the items
+    /// inside them, and their parent directories.
+    ///
+    /// # Arguments
+    ///
+    /// * `paths` - Paths to select.
+    pub fn select(
+        &self,
+        paths: &std::collections::BTreeSet<std::path::PathBuf>,
+    ) -> DirectoryScanList {
+        let parents = paths
+            .iter()
+            .flat_map(|path| path.ancestors().skip(1))
+            .collect::<std::collections::HashSet<_>>();
+        let is_selected = |path: &std::path::Path| {
+            parents.contains(path) || path.ancestors().any(|ancestor| paths.contains(ancestor))
+        };
+        DirectoryScanList {
+            directories: self
+                .directories
+                .iter()
+                .filter(|directory| is_selected(&directory.path))
+                .cloned()
+                .collect(),
+            files: self
+                .files
+                .iter()
+                .filter(|file| is_selected(&file.path))
+                .cloned()
+                .collect(),
+            symlinks: self
+                .symlinks
+                .iter()
+                .filter(|symlink| is_selected(&symlink.path))
+                .cloned()
+                .collect(),
         }
     }
 
+    /// Create a directory scan list with only the files that are not in another list (by path).
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - Directory scan list with the files to leave out.
+    pub fn select_files_not_in(&self, other: &DirectoryScanList) -> DirectoryScanList {
+        let paths = other
+            .files
+            .iter()
+            .map(|file| file.path.as_path())
+            .collect::<std::collections::HashSet<_>>();
+        DirectoryScanList::new(
+            Vec::new(),
+            self.files
+                .iter()
+                .filter(|file| !paths.contains(file.path.as_path()))
+                .cloned()
+                .collect(),
+        )
+    }
+
     pub fn directories(&self) -> &[Directory] {
         &self.directories
     }
@@ -260,7 +724,403 @@ impl DirectoryScanList {
         &self.files
     }
 
+    pub fn symlinks(&self) -> &[Symlink] {
+        &self.symlinks
+    }
+
     pub fn into_parts(self) -> (Vec<Directory>, Vec<File>) {
         (self.directories, self.files)
     }
 }
+
+/// List the files that are tracked by git in a local directory, with their modes.
+///
+/// # Arguments
+///
+/// * `local_dir` - Path of local git directory.
+fn git_ls_files(local_dir: &std::path::Path) -> Result<Vec<(u32, std::path::PathBuf)>> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(local_dir)
+        // Prints `<mode> <object> <stage>\t<path>` for every entry, separated by NUL so that any
+        // file name can be read.
+        .args(["ls-files", "--cached", "--stage", "-z"])
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .context("failed to run git command")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!("git ls-files failed: {}", output.status));
+    }
+    let mut files = Vec::new();
+    for entry in output.stdout.split(|&byte| byte == 0) {
+        if entry.is_empty() {
+            continue;
+        }
+        let malformed = || {
+            anyhow::anyhow!(
+                "malformed git ls-files output: {}",
+                String::from_utf8_lossy(entry)
+            )
+        };
+        let tab = entry
+            .iter()
+            .position(|&byte| byte == b'\t')
+            .ok_or_else(malformed)?;
+        let mode = std::str::from_utf8(&entry[..tab])
+            .ok()
+            .and_then(|stage| stage.split(' ').next())
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+            .ok_or_else(malformed)?;
+        files.push((mode, fs::path_from_bytes(&entry[tab + 1..])));
+    }
+    // Files with merge conflicts have an entry for every stage.
+    files.dedup_by(|a, b| a.1 == b.1);
+    Ok(files)
+}
+
+/// Create the predicate for the directories that a scan of the local directory does not descend
+/// into: the git directory, and the directories that nothing inside of is synchronized.
+///
+/// These are the directories that are excluded by the rules, or that are ignored and hold no
+/// tracked files (unless the gitignore rules are evaluated with `git check-ignore`). Directories
+/// that an include rule can match something inside of are never pruned.
+///
+/// # Arguments
+///
+/// * `local_dir` - Path of local git directory.
+/// * `rules` - Extra rules, which take precedence over the gitignore rules.
+/// * `git_check_ignore` - Whether or not the gitignore rules are evaluated with `git check-ignore`.
+pub fn local_prune<'a>(
+    local_dir: &std::path::Path,
+    rules: &'a SyncRules,
+    git_check_ignore: bool,
+) -> Result<impl Fn(&std::path::Path) -> bool + Sync + 'a> {
+    let matcher = if git_check_ignore {
+        None
+    } else {
+        Some(std::sync::Mutex::new(gitignore::GitignoreMatcher::new(
+            local_dir,
+        )?))
+    };
+    Ok(move |path: &std::path::Path| {
+        if path == std::path::Path::new(".git") {
+            return true;
+        }
+        let is_excluded = match rules.is_included(path, true) {
+            Some(is_included) => !is_included,
+            None => matcher
+                .as_ref()
+                .is_some_and(|matcher| matcher.lock().unwrap().is_prunable(path)),
+        };
+        is_excluded && !rules.may_include_inside(path)
+    })
+}
+
+/// Filter paths of files (that do not need to exist) to the ones that are not ignored by the
+/// `gitignore` rules, or that are included by the extra sync rules.
+///
+/// # Arguments
+///
+/// * `local_dir` - Path of local git directory.
+/// * `paths` - Paths of files to filter.
+/// * `rules` - Extra rules, which take precedence over the gitignore rules.
+/// * `git_check_ignore` - Whether or not to evaluate the gitignore rules with `git check-ignore`
+///   instead of the built-in matcher.
+pub fn filter_paths_by_gitignore(
+    local_dir: &std::path::Path,
+    paths: &std::collections::BTreeSet<std::path::PathBuf>,
+    rules: &SyncRules,
+    git_check_ignore: bool,
+) -> Result<std::collections::BTreeSet<std::path::PathBuf>> {
+    with_gitignore_matcher(local_dir, rules, git_check_ignore, |is_matched| {
+        let mut matched = std::collections::BTreeSet::new();
+        for path in paths {
+            if is_matched(path, false)? {
+                matched.insert(path.clone());
+            }
+        }
+        Ok(matched)
+    })
+}
+
+/// Call a function with a matcher that tells whether or not an item is kept by the `gitignore`
+/// rules and the extra sync rules.
+///
+/// # Arguments
+///
+/// * `local_dir` - Path of local git directory.
+/// * `rules` - Extra rules, which take precedence over the gitignore rules.
+/// * `git_check_ignore` - Whether or not to evaluate the gitignore rules with `git check-ignore`
+///   instead of the built-in matcher.
+/// * `f` - Function that gets the matcher, which takes a path and whether or not it is a
+///   directory.
+fn with_gitignore_matcher<T>(
+    local_dir: &std::path::Path,
+    rules: &SyncRules,
+    git_check_ignore: bool,
+    f: impl FnOnce(&mut dyn FnMut(&std::path::Path, bool) -> Result<bool>) -> Result<T>,
+) -> Result<T> {
+    let mut checker = gitignore::checker(local_dir, git_check_ignore)?;
+
+    // XXX: `git check-ignore` refuses paths beyond a symbolic link, which we get when symlinks
+    // are followed. Items inside a symlinked directory are checked as the symlink itself.
+    let mut is_symlink_cache = std::collections::HashMap::new();
+    let mut checked_path = |path: &std::path::Path, is_dir: bool| -> (std::path::PathBuf, bool) {
+        for ancestor in path
+            .ancestors()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .skip(1)
+        {
+            if ancestor == path {
+                break;
+            }
+            let is_symlink = *is_symlink_cache
+                .entry(ancestor.to_path_buf())
+                .or_insert_with(|| local_dir.join(ancestor).is_symlink());
+            if is_symlink {
+                return (ancestor.to_path_buf(), false);
+            }
+        }
+        (path.to_path_buf(), is_dir)
+    };
+
+    // The git directory is never synchronized, whatever the rules say.
+    let mut is_matched = |path: &std::path::Path, is_dir: bool| -> Result<bool> {
+        if path.starts_with(".git") {
+            return Ok(false);
+        }
+        match rules.is_included(path, is_dir) {
+            Some(is_included) => Ok(is_included),
+            None => {
+                let (checked_path, is_dir) = checked_path(path, is_dir);
+                Ok(!checker.is_ignored(&checked_path, is_dir)?)
+            }
+        }
+    };
+
+    let result = f(&mut is_matched)?;
+    checker.finish()?;
+    Ok(result)
+}
+
+/// Recursively walk a local directory (or single file), and add every item to the scan with its
+/// path relative to the root directory. Pruned directories are added, but not descended into.
+///
+/// Directories are walked in parallel, so the items are added in no particular order.
+fn walk_local_file_system(
+    root: &std::path::Path,
+    start: &std::path::Path,
+    options: ScanOptions,
+    prune: &(dyn Fn(&std::path::Path) -> bool + Sync),
+    scan: &mut DirectoryScanList,
+) -> Result<()> {
+    let shared_scan = std::sync::Mutex::new(std::mem::take(scan));
+    let first_error = std::sync::Mutex::new(None);
+    let visit_entry =
+        |entry: Result<ignore::DirEntry, ignore::Error>| -> Result<ignore::WalkState> {
+            let entry = match entry {
+                Ok(entry) => entry,
+                // Symlinks that cannot be followed (because they are broken or form a loop) are kept as
+                // symlinks.
+                Err(err) if error_path(&err).is_some_and(|path| path.is_symlink()) => {
+                    let path = error_path(&err).unwrap();
+                    let target = std::fs::read_link(path).context("failed to read symlink")?;
+                    let relative_path = path.strip_prefix(root).unwrap().to_path_buf();
+                    let symlink = Symlink::new(relative_path, target);
+                    shared_scan.lock().unwrap().symlinks.push(symlink);
+                    return Ok(ignore::WalkState::Continue);
+                }
+                Err(err) => return Err(err).context("failed to walk entry"),
+            };
+            let Some(file_type) = entry.file_type() else {
+                return Ok(ignore::WalkState::Continue);
+            };
+            let relative_path = entry.path().strip_prefix(root).unwrap().to_path_buf();
+            // The walker follows a symlink that it starts from, unlike the symlinks it comes
+            // across.
+            let is_symlink = file_type.is_symlink()
+                || (!options.follow_symlinks
+                    && entry.depth() == 0
+                    && relative_path.components().count() > 0
+                    && entry.path().is_symlink());
+            if is_symlink {
+                let target = std::fs::read_link(entry.path()).context("failed to read symlink")?;
+                let symlink = Symlink::new(relative_path, target);
+                shared_scan.lock().unwrap().symlinks.push(symlink);
+            } else if file_type.is_file() {
+                let metadata = entry.metadata().context("failed to fetch file metadata")?;
+                let mut file = File::new(
+                    relative_path,
+                    metadata.len(),
+                    fs::mtime(&metadata).context("failed to fetch file modification time")?,
+                );
+                file.mode = fs::mode(&metadata);
+                shared_scan.lock().unwrap().files.push(file);
+            } else if file_type.is_dir() && relative_path.components().count() > 0 {
+                let is_pruned = prune(&relative_path);
+                let directory = Directory::new(relative_path);
+                shared_scan.lock().unwrap().directories.push(directory);
+                if is_pruned {
+                    return Ok(ignore::WalkState::Skip);
+                }
+            }
+            Ok(ignore::WalkState::Continue)
+        };
+
+    // The walker applies no filters of its own, since gitignore rules are applied separately.
+    ignore::WalkBuilder::new(start)
+        .standard_filters(false)
+        .follow_links(options.follow_symlinks)
+        .build_parallel()
+        .run(|| {
+            Box::new(|entry| match visit_entry(entry) {
+                Ok(state) => state,
+                Err(err) => {
+                    first_error.lock().unwrap().get_or_insert(err);
+                    ignore::WalkState::Quit
+                }
+            })
+        });
+
+    if let Some(err) = first_error.into_inner().unwrap() {
+        return Err(err);
+    }
+    *scan = shared_scan.into_inner().unwrap();
+    Ok(())
+}
+
+/// Find the path that a walk error is about (if any).
+fn error_path(err: &ignore::Error) -> Option<&std::path::Path> {
+    match err {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            error_path(err)
+        }
+        _ => None,
+    }
+}
+
+/// Build the `find` expression that prunes the given directories, or `None` if there are none.
+///
+/// # Arguments
+///
+/// * `start` - Starting point that `find` is run with (unquoted).
+/// * `prune` - Directories to prune, relative to the starting point.
+fn find_prune_expression(start: &str, prune: &[std::path::PathBuf]) -> Option<String> {
+    if prune.is_empty() {
+        return None;
+    }
+    // The `-path` pattern is a glob, so glob characters in the path are escaped.
+    let patterns = prune
+        .iter()
+        .map(|path| {
+            let mut pattern = String::new();
+            for c in format!("{start}/{}", path.to_slash_lossy()).chars() {
+                if matches!(c, '\\' | '*' | '?' | '[') {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+            }
+            format!("-path {}", ssh::shell_quote(&pattern))
+        })
+        .collect::<Vec<_>>();
+    Some(format!("\\( {} \\) -prune", patterns.join(" -o ")))
+}
+
+/// Split the next NUL-terminated field off the output of the remote scan command.
+fn next_field<'a>(rest: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let end = rest
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or_else(|| anyhow::anyhow!("malformed find output (missing field)"))?;
+    let field = &rest[..end];
+    *rest = &rest[end + 1..];
+    Ok(field)
+}
+
+/// Parse the `%T@` modification time printed by `find` into whole seconds since the epoch.
+///
+/// The fractional part is discarded since SFTP only transfers modification times with a resolution
+/// of seconds.
+fn parse_find_mtime(mtime: &str) -> Result<i64> {
+    let seconds = mtime
+        .split_once('.')
+        .map(|(seconds, _)| seconds)
+        .unwrap_or(mtime);
+    seconds
+        .parse()
+        .context("failed to parse file modification time")
+}
+
+/// Parse the output of the command built by [`DirectoryScanList::remote_checksum_command`] into
+/// the checksums of the files, in the same order as the paths.
+///
+/// # Arguments
+///
+/// * `stdout` - Output of the remote checksum command.
+/// * `paths` - Paths of the files that the command was given.
+pub fn parse_remote_checksums(stdout: &[u8], paths: &[std::path::PathBuf]) -> Result<Vec<String>> {
+    let mut checksums = std::collections::HashMap::new();
+    for line in stdout.split(|&byte| byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let (checksum, path) = parse_sha256sum_line(line)?;
+        checksums.insert(path, checksum);
+    }
+    paths
+        .iter()
+        .map(|path| {
+            checksums.remove(path).ok_or_else(|| {
+                anyhow::anyhow!("missing checksum for remote file: {}", path.display())
+            })
+        })
+        .collect()
+}
+
+/// Parse a single line of `sha256sum` output into the digest and the relative file path.
+///
+/// `sha256sum` escapes file names that contain a backslash or line break, and marks the line with
+/// a leading backslash when it does so.
+fn parse_sha256sum_line(line: &[u8]) -> Result<(String, std::path::PathBuf)> {
+    let malformed = || {
+        anyhow::anyhow!(
+            "malformed sha256sum output line: {}",
+            String::from_utf8_lossy(line)
+        )
+    };
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let separator = line
+        .windows(2)
+        .position(|window| window == b"  ")
+        .ok_or_else(malformed)?;
+    let checksum = std::str::from_utf8(&line[..separator]).map_err(|_| malformed())?;
+    let path = &line[separator + 2..];
+    let path = path.strip_prefix(b"./").unwrap_or(path);
+    let path = if escaped {
+        let mut unescaped = Vec::with_capacity(path.len());
+        let mut bytes = path.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte == b'\\' {
+                match bytes.next() {
+                    Some(b'n') => unescaped.push(b'\n'),
+                    Some(b'r') => unescaped.push(b'\r'),
+                    Some(&byte) => unescaped.push(byte),
+                    None => {}
+                }
+            } else {
+                unescaped.push(byte);
+            }
+        }
+        fs::path_from_bytes(&unescaped)
+    } else {
+        fs::path_from_bytes(path)
+    };
+    Ok((checksum.to_string(), path))
+}
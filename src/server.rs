@@ -0,0 +1,178 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+
+use crate::protocol::{self, Opcode};
+use crate::transport::{LocalTransport, Transport};
+
+/// Argument that starts the remote helper instead of the command line interface.
+pub const SERVER_ARG: &str = "--server";
+
+/// Run the remote helper, which serves requests on stdin and writes the responses to stdout until
+/// stdin is closed.
+///
+/// Requests are carried out on the local file system in the same way as for a local remote, so
+/// that the helper behaves exactly like the local end.
+pub fn run() -> Result<()> {
+    // Stdin is not locked, since commands read their input from it on another thread.
+    let mut reader = std::io::BufReader::new(std::io::stdin());
+    let mut writer = std::io::BufWriter::new(std::io::stdout().lock());
+    writer.write_all(protocol::MAGIC)?;
+    protocol::write_u32(&mut writer, protocol::VERSION)?;
+    writer.flush()?;
+
+    let mut local = LocalTransport::new();
+    loop {
+        let opcode = match protocol::read_u8(&mut reader) {
+            Ok(opcode) => Opcode::try_from(opcode)?,
+            // The client closes the connection when it is done.
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err).context("failed to read request"),
+        };
+        match opcode {
+            Opcode::Scan => {
+                let path = protocol::read_path(&mut reader)?;
+                let options = protocol::read_scan_options(&mut reader)?;
+                let prune = protocol::read_paths(&mut reader)?;
+                // As with the other transports, the directory on the remote host is created first
+                // if it does not yet exist (unlike for a local remote).
+                let result = std::fs::create_dir_all(&path)
+                    .context("failed to create directory")
+                    .and_then(|()| local.scan(&path, options, &prune));
+                protocol::write_status(&mut writer, &result)?;
+                if let Ok(scan) = &result {
+                    protocol::write_scan(&mut writer, scan)?;
+                }
+            }
+            Opcode::Mkdir => {
+                let path = protocol::read_path(&mut reader)?;
+                protocol::write_status(&mut writer, &local.mkdir(&path))?;
+            }
+            Opcode::Rm => {
+                let path = protocol::read_path(&mut reader)?;
+                protocol::write_status(&mut writer, &local.rm(&path))?;
+            }
+            Opcode::Rmdir => {
+                let path = protocol::read_path(&mut reader)?;
+                protocol::write_status(&mut writer, &local.rmdir(&path))?;
+            }
+            Opcode::Rename => {
+                let from = protocol::read_path(&mut reader)?;
+                let to = protocol::read_path(&mut reader)?;
+                protocol::write_status(&mut writer, &local.rename(&from, &to))?;
+            }
+            Opcode::Symlink => {
+                let target = protocol::read_path(&mut reader)?;
+                let path = protocol::read_path(&mut reader)?;
+                protocol::write_status(&mut writer, &local.symlink(&target, &path))?;
+            }
+            Opcode::Chmod => {
+                let path = protocol::read_path(&mut reader)?;
+                let mode = protocol::read_u32(&mut reader)?;
+                protocol::write_status(&mut writer, &local.chmod(&path, mode))?;
+            }
+            Opcode::Put => {
+                let path = protocol::read_path(&mut reader)?;
+                let result = protocol::read_file(&mut reader, &path)?;
+                protocol::write_status(&mut writer, &result)?;
+            }
+            Opcode::Checksums => {
+                let path = protocol::read_path(&mut reader)?;
+                let files = protocol::read_paths(&mut reader)?;
+                let result = local.checksums(&path, &files);
+                protocol::write_status(&mut writer, &result)?;
+                if let Ok(checksums) = &result {
+                    for checksum in checksums {
+                        protocol::write_bytes(&mut writer, checksum.as_bytes())?;
+                    }
+                }
+            }
+            Opcode::Exec => {
+                let command = protocol::read_bytes(&mut reader)?;
+                let result = exec(&String::from_utf8_lossy(&command), &mut reader, &mut writer)?;
+                protocol::write_status(&mut writer, &result)?;
+            }
+            Opcode::Get => {
+                let path = protocol::read_path(&mut reader)?;
+                // Whether or not the file can be opened is checked first, since an error cannot
+                // be reported anymore once the file is being sent.
+                let result = std::fs::File::open(&path).context("failed to open file");
+                protocol::write_status(&mut writer, &result)?;
+                if let Ok(file) = result {
+                    protocol::write_file(&mut writer, file)?;
+                }
+            }
+        }
+        writer.flush().context("failed to write response")?;
+    }
+}
+
+/// Run a shell command for [`Opcode::Exec`], with its input read from the request and its output
+/// written to the response (both as chunks).
+///
+/// All input is read, even when the command fails or exits early, so that the stream stays usable.
+/// Errors of the stream are returned in the outer result, and errors of the command in the inner
+/// result.
+///
+/// # Arguments
+///
+/// * `command` - Command to run.
+/// * `reader` - Reader of request.
+/// * `writer` - Writer of response.
+fn exec(
+    command: &str,
+    reader: &mut (impl Read + Send),
+    writer: &mut impl Write,
+) -> std::io::Result<Result<()>> {
+    let process = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn();
+    let mut process = match process {
+        Ok(process) => process,
+        Err(err) => {
+            let _ = protocol::read_chunks(reader, &mut std::io::sink())?;
+            let _ = protocol::write_chunks(writer, &mut std::io::empty())?;
+            return Ok(Err(err).context("failed to spawn command"));
+        }
+    };
+    let mut stdin = process.stdin.take().unwrap();
+    let mut stdout = process.stdout.take().unwrap();
+    let mut stderr = process.stderr.take().unwrap();
+    let (input, output, stderr) = std::thread::scope(|scope| {
+        // The command may exit without reading all of its input, so failing to write it is not an
+        // error in itself (the exit status tells).
+        let input = scope.spawn(move || protocol::read_chunks(reader, &mut stdin).map(|_| ()));
+        let stderr = scope.spawn(move || {
+            let mut output = String::new();
+            let _ = stderr.read_to_string(&mut output);
+            output
+        });
+        let output = protocol::write_chunks(writer, &mut stdout);
+        (
+            input.join().unwrap(),
+            output,
+            stderr.join().unwrap_or_default(),
+        )
+    });
+    input?;
+    let output = output?;
+    let exit_status = match process.wait() {
+        Ok(exit_status) => exit_status,
+        Err(err) => return Ok(Err(err).context("failed to run command")),
+    };
+    if !exit_status.success() {
+        return Ok(Err(anyhow::anyhow!(
+            "command failed with status code {}: {}",
+            exit_status
+                .code()
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "<no status code>".to_string()),
+            stderr.trim(),
+        )));
+    }
+    Ok(output.context("failed to read command output"))
+}
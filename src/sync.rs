@@ -1,18 +1,37 @@
-use std::io::Write;
-
 use anyhow::{Context, Result};
 
-use path_slash::PathExt;
-
-use crate::host::Host;
+use crate::baseline::Baseline;
+use crate::bulk::{self, BulkOptions};
+use crate::conflict::{Conflict, ConflictPolicy};
+use crate::delta::{self, DeltaOptions};
+use crate::fs::{Directory, File, Symlink};
+use crate::revision::TreeChanges;
 use crate::scan::DirectoryScanList;
+use crate::transport::{LocalTransport, Transport};
+
+/// Options for how files are copied to the target.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferOptions {
+    /// Transfer large files as a delta, or `None` to copy them whole.
+    pub delta: Option<DeltaOptions>,
+
+    /// Transfer many files as a single stream, or `None` to copy them one by one.
+    pub bulk: Option<BulkOptions>,
+}
 
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Sync {
+    rename_files: Vec<(std::path::PathBuf, std::path::PathBuf)>,
     remove_files: Vec<std::path::PathBuf>,
     remove_directories: Vec<std::path::PathBuf>,
+    keep_directories: Vec<std::path::PathBuf>,
     create_directories: Vec<std::path::PathBuf>,
+    create_symlinks: Vec<Symlink>,
     copy_files: Vec<std::path::PathBuf>,
+    chmod_files: Vec<(std::path::PathBuf, u32)>,
+    /// Whether or not the directories to remove were not scanned on the target, so that they might
+    /// still hold files that are not part of the sync.
+    unscanned_directories: bool,
 }
 
 impl Sync {
@@ -21,6 +40,32 @@ impl Sync {
         let mut remove_directories = Vec::new();
         let mut create_directories = Vec::new();
         let mut copy_files = Vec::new();
+        let mut chmod_files = Vec::new();
+
+        // Symlinks that differ are replaced, so they are removed first.
+        let source_symlinks = source
+            .symlinks()
+            .iter()
+            .map(|symlink| (&symlink.path, &symlink.target))
+            .collect::<std::collections::HashMap<_, _>>();
+        let target_symlinks = target
+            .symlinks()
+            .iter()
+            .map(|symlink| (&symlink.path, &symlink.target))
+            .collect::<std::collections::HashMap<_, _>>();
+        let create_symlinks = source
+            .symlinks()
+            .iter()
+            .filter(|symlink| target_symlinks.get(&symlink.path) != Some(&&symlink.target))
+            .cloned()
+            .collect::<Vec<_>>();
+        remove_files.extend(
+            target
+                .symlinks()
+                .iter()
+                .filter(|symlink| source_symlinks.get(&symlink.path) != Some(&&symlink.target))
+                .map(|symlink| symlink.path.clone()),
+        );
 
         let (mut source_directories, mut source_files) = source.into_parts();
         let (mut target_directories, mut target_files) = target.into_parts();
@@ -77,8 +122,14 @@ impl Sync {
                     std::cmp::Ordering::Equal => {
                         let source_file = source_files.pop_front().unwrap();
                         let target_file = target_files.pop_front().unwrap();
-                        if source_file.size != target_file.size {
+                        if source_file.differs_from(&target_file) {
                             copy_files.push(source_file.path);
+                        } else if let (true, Some(mode)) = (
+                            source_file.mode_differs_from(&target_file),
+                            source_file.mode,
+                        ) {
+                            // Only the permissions changed, so there is no need to copy the file.
+                            chmod_files.push((source_file.path, mode));
                         }
                     }
                     std::cmp::Ordering::Less => {
@@ -108,106 +159,605 @@ impl Sync {
             }
         }
 
+        // Directories must be removed children-first.
+        remove_directories.reverse();
+
         Sync {
+            rename_files: Vec::new(),
             remove_directories,
+            keep_directories: Vec::new(),
             remove_files,
             create_directories,
+            create_symlinks,
             copy_files,
+            chmod_files,
+            unscanned_directories: false,
         }
     }
 
-    pub fn execute_remote(
+    /// Compute the sync from the changes in the source since a revision, for a target that matches
+    /// the revision. Neither end is scanned completely.
+    ///
+    /// Renamed files are renamed on the target, unless they moved into a new directory (since
+    /// renames happen before directories are created). Directories of the revision that held
+    /// removed files are removed as well once they no longer exist in the source. Directories that
+    /// still exist are kept, since they might hold ignored files. The same goes for directories
+    /// that turn out not to be empty on the target, which are listed in
+    /// [`Sync::keep_directories`] once the sync is executed.
+    ///
+    /// # Arguments
+    ///
+    /// * `source_dir` - Path of source directory.
+    /// * `changes` - Changes in the source since the revision.
+    /// * `source` - Scan of the files in the source that changed, with ignored files filtered out.
+    /// * `previous` - Paths of the files of the revision that no longer exist in the source, with
+    ///   ignored files filtered out.
+    /// * `directories` - Directories in the tree of the revision.
+    pub fn incremental(
+        source_dir: &std::path::Path,
+        changes: &TreeChanges,
+        source: &DirectoryScanList,
+        previous: &std::collections::BTreeSet<std::path::PathBuf>,
+        directories: &std::collections::HashSet<std::path::PathBuf>,
+    ) -> Sync {
+        let source_files = source
+            .files()
+            .iter()
+            .map(|file| file.path.as_path())
+            .collect::<std::collections::HashSet<_>>();
+        let source_symlinks = source
+            .symlinks()
+            .iter()
+            .map(|symlink| (symlink.path.as_path(), symlink))
+            .collect::<std::collections::HashMap<_, _>>();
+        let exists_in_revision =
+            |path: &std::path::Path| path.as_os_str().is_empty() || directories.contains(path);
+
+        let mut sync = Sync {
+            unscanned_directories: true,
+            ..Sync::default()
+        };
+        let add = |sync: &mut Sync, path: &std::path::Path| {
+            if source_files.contains(path) {
+                sync.copy_files.push(path.to_path_buf());
+            } else if let Some(symlink) = source_symlinks.get(path) {
+                sync.create_symlinks.push((*symlink).clone());
+            }
+        };
+        for path in &changes.removed {
+            if previous.contains(path) {
+                sync.remove_files.push(path.clone());
+            }
+        }
+        for path in &changes.added {
+            add(&mut sync, path);
+        }
+        for path in &changes.modified {
+            // Symlinks cannot be overwritten, so they are replaced.
+            if source_symlinks.contains_key(path.as_path()) {
+                sync.remove_files.push(path.clone());
+            }
+            add(&mut sync, path);
+        }
+        for (from, to, modified) in &changes.renamed {
+            let from_exists = previous.contains(from);
+            if from_exists
+                && source_files.contains(to.as_path())
+                && to.parent().is_some_and(exists_in_revision)
+            {
+                sync.rename_files.push((from.clone(), to.clone()));
+                if *modified {
+                    sync.copy_files.push(to.clone());
+                }
+            } else {
+                if from_exists {
+                    sync.remove_files.push(from.clone());
+                }
+                add(&mut sync, to);
+            }
+        }
+
+        // Directories are removed children-first.
+        let mut remove_directories = previous
+            .iter()
+            .flat_map(|path| path.ancestors().skip(1))
+            .filter(|path| directories.contains(*path))
+            .filter(|path| !source_dir.join(path).is_dir())
+            .collect::<std::collections::HashSet<_>>()
+            .into_iter()
+            .map(|path| path.to_path_buf())
+            .collect::<Vec<_>>();
+        remove_directories.sort_by(|a, b| b.cmp(a));
+        sync.remove_directories = remove_directories;
+
+        // Directories are listed parents-first.
+        sync.create_directories = source
+            .directories()
+            .iter()
+            .filter(|directory| !exists_in_revision(&directory.path))
+            .map(|directory| directory.path.clone())
+            .collect();
+        sync
+    }
+
+    /// Compare both ends against the state they were in after the previous sync, and compute
+    /// which changes must be propagated in each direction.
+    ///
+    /// Changes from either end are propagated to the other end. A deletion is only propagated if
+    /// the other end did not change the file. Files that were changed on both ends (to different
+    /// contents) are conflicts, which are resolved according to the conflict policy.
+    ///
+    /// Note: Conflicts with the [`ConflictPolicy::Merge`] policy are not part of the plan, and
+    /// must be merged separately.
+    ///
+    /// # Arguments
+    ///
+    /// * `local` - Scan of the local end.
+    /// * `remote` - Scan of the remote end.
+    /// * `baseline` - Snapshot of both ends after the previous sync.
+    /// * `policy` - How to resolve conflicts.
+    /// * `remote_name` - Name of the remote end, used to name conflict copies.
+    pub fn bidirectional(
+        local: &DirectoryScanList,
+        remote: &DirectoryScanList,
+        baseline: &Baseline,
+        policy: ConflictPolicy,
+        remote_name: &str,
+    ) -> BidirectionalSync {
+        let mut to_remote = Sync::default();
+        let mut to_local = Sync::default();
+        let mut conflicts = Vec::new();
+
+        // Versions of every file and symlink on the local end, remote end, and in the local and
+        // remote baseline (in that order).
+        let mut entries = std::collections::BTreeMap::<&std::path::Path, [Option<Entry>; 4]>::new();
+        for (index, scan) in [local, remote, &baseline.local, &baseline.remote]
+            .into_iter()
+            .enumerate()
+        {
+            for file in scan.files() {
+                entries.entry(&file.path).or_default()[index] = Some(Entry::File(file));
+            }
+            for symlink in scan.symlinks() {
+                entries.entry(&symlink.path).or_default()[index] = Some(Entry::Symlink(symlink));
+            }
+        }
+        for (path, &[local_entry, remote_entry, base_local_entry, base_remote_entry]) in &entries {
+            let path = path.to_path_buf();
+            let local_changed = is_changed(local_entry, base_local_entry);
+            let remote_changed = is_changed(remote_entry, base_remote_entry);
+            match (local_changed, remote_changed) {
+                (false, false) => {
+                    // Only the permissions changed, so there is no need to copy the file.
+                    match (
+                        mode_change(local_entry, base_local_entry),
+                        mode_change(remote_entry, base_remote_entry),
+                    ) {
+                        (Some(mode), None) => to_remote.chmod_files.push((path, mode)),
+                        (None, Some(mode)) => to_local.chmod_files.push((path, mode)),
+                        (Some(local_mode), Some(remote_mode)) if local_mode != remote_mode => {
+                            match policy {
+                                ConflictPolicy::PreferRemote => {
+                                    to_local.chmod_files.push((path, remote_mode))
+                                }
+                                _ => to_remote.chmod_files.push((path, local_mode)),
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                (true, false) => match local_entry {
+                    Some(local_entry) => to_remote.replace(path, local_entry, remote_entry),
+                    None if remote_entry.is_some() => to_remote.remove_files.push(path),
+                    None => {}
+                },
+                (false, true) => match remote_entry {
+                    Some(remote_entry) => to_local.replace(path, remote_entry, local_entry),
+                    None if local_entry.is_some() => to_local.remove_files.push(path),
+                    None => {}
+                },
+                (true, true) => match (local_entry, remote_entry) {
+                    (Some(local_entry), Some(remote_entry)) => {
+                        if local_entry.differs_from(&remote_entry) {
+                            // Only files can be merged.
+                            let policy = match (policy, local_entry, remote_entry) {
+                                (ConflictPolicy::Merge, Entry::File(_), Entry::File(_)) => policy,
+                                (ConflictPolicy::Merge, _, _) => ConflictPolicy::Skip,
+                                _ => policy,
+                            };
+                            match policy {
+                                ConflictPolicy::PreferLocal => {
+                                    to_remote.replace(
+                                        path.clone(),
+                                        local_entry,
+                                        Some(remote_entry),
+                                    );
+                                }
+                                ConflictPolicy::PreferRemote => {
+                                    to_local.replace(path.clone(), remote_entry, Some(local_entry));
+                                }
+                                ConflictPolicy::KeepBoth => {
+                                    // Move the remote version out of the way, so that it can be
+                                    // downloaded under its new name, and the local version can be
+                                    // uploaded.
+                                    let conflict_path = (1..)
+                                        .map(|n| conflict_path(&path, remote_name, n))
+                                        .find(|conflict_path| {
+                                            !entries.contains_key(conflict_path.as_path())
+                                        })
+                                        .unwrap();
+                                    to_remote
+                                        .rename_files
+                                        .push((path.clone(), conflict_path.clone()));
+                                    to_remote.replace(path.clone(), local_entry, None);
+                                    to_local.replace(conflict_path, remote_entry, None);
+                                }
+                                ConflictPolicy::Skip
+                                | ConflictPolicy::Abort
+                                | ConflictPolicy::Merge => {}
+                            }
+                            conflicts.push(Conflict::new(path, policy));
+                        }
+                    }
+                    // Deleted on one end and changed on the other: the change wins.
+                    (Some(local_entry), None) => to_remote.replace(path, local_entry, None),
+                    (None, Some(remote_entry)) => to_local.replace(path, remote_entry, None),
+                    (None, None) => {}
+                },
+            }
+        }
+
+        // A directory is still needed if any file that survives the sync is inside it. Entries
+        // that are replaced are removed first, but they survive.
+        let removed_entries = to_local
+            .remove_files
+            .iter()
+            .chain(&to_remote.remove_files)
+            .map(|path| path.as_path())
+            .collect::<std::collections::HashSet<_>>();
+        let replaced_entries = [&to_local, &to_remote]
+            .into_iter()
+            .flat_map(|sync| {
+                sync.copy_files
+                    .iter()
+                    .chain(sync.create_symlinks.iter().map(|symlink| &symlink.path))
+            })
+            .map(|path| path.as_path())
+            .collect::<std::collections::HashSet<_>>();
+        let mut needed_directories = std::collections::HashSet::new();
+        for (path, &[local_entry, remote_entry, ..]) in &entries {
+            let removed = removed_entries.contains(path) && !replaced_entries.contains(path);
+            if (local_entry.is_some() || remote_entry.is_some()) && !removed {
+                needed_directories.extend(path.ancestors().skip(1).map(|p| p.to_path_buf()));
+            }
+        }
+
+        let mut directories =
+            std::collections::BTreeMap::<&std::path::Path, [Option<&Directory>; 4]>::new();
+        for (index, scan) in [local, remote, &baseline.local, &baseline.remote]
+            .into_iter()
+            .enumerate()
+        {
+            for directory in scan.directories() {
+                directories.entry(&directory.path).or_default()[index] = Some(directory);
+            }
+        }
+        // Walk the directories deepest-first, so that a directory that must be created on the
+        // other end marks its parents as needed before they are visited.
+        for (
+            path,
+            [local_directory, remote_directory, base_local_directory, base_remote_directory],
+        ) in directories.iter().rev()
+        {
+            let needed = needed_directories.contains(*path);
+            let path = path.to_path_buf();
+            match (local_directory, remote_directory) {
+                (Some(_), None) => {
+                    if base_remote_directory.is_some() && !needed {
+                        to_local.remove_directories.push(path);
+                    } else {
+                        needed_directories.extend(path.ancestors().map(|p| p.to_path_buf()));
+                        to_remote.create_directories.push(path);
+                    }
+                }
+                (None, Some(_)) => {
+                    if base_local_directory.is_some() && !needed {
+                        to_remote.remove_directories.push(path);
+                    } else {
+                        needed_directories.extend(path.ancestors().map(|p| p.to_path_buf()));
+                        to_local.create_directories.push(path);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Directories must be created parents-first.
+        to_remote.create_directories.reverse();
+        to_local.create_directories.reverse();
+
+        BidirectionalSync {
+            to_remote,
+            to_local,
+            conflicts,
+        }
+    }
+
+    /// Keep directories that would be removed, but would not be empty after the sync because they
+    /// hold entries that are not part of the sync (such as ignored files).
+    ///
+    /// The kept directories are no longer removed, and are listed in
+    /// [`Sync::keep_directories`] instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Scan of the target end that includes ignored files.
+    pub fn keep_nonempty_directories(&mut self, target: &DirectoryScanList) {
+        let removed = self
+            .remove_files
+            .iter()
+            .chain(self.rename_files.iter().map(|(from, _)| from))
+            .chain(self.remove_directories.iter())
+            .map(|path| path.as_path())
+            .collect::<std::collections::HashSet<_>>();
+        let remove_directories = self
+            .remove_directories
+            .iter()
+            .map(|path| path.as_path())
+            .collect::<std::collections::HashSet<_>>();
+        let mut keep = std::collections::HashSet::new();
+        let remaining = target
+            .files()
+            .iter()
+            .map(|file| file.path.as_path())
+            .chain(
+                target
+                    .directories()
+                    .iter()
+                    .map(|directory| directory.path.as_path()),
+            )
+            .chain(
+                target
+                    .symlinks()
+                    .iter()
+                    .map(|symlink| symlink.path.as_path()),
+            )
+            .chain(self.rename_files.iter().map(|(_, to)| to.as_path()))
+            .filter(|path| !removed.contains(path));
+        for path in remaining {
+            keep.extend(
+                path.ancestors()
+                    .skip(1)
+                    .filter(|ancestor| remove_directories.contains(ancestor))
+                    .map(|ancestor| ancestor.to_path_buf()),
+            );
+        }
+        let (keep_directories, remove_directories) = std::mem::take(&mut self.remove_directories)
+            .into_iter()
+            .partition(|directory| keep.contains(directory));
+        self.keep_directories = keep_directories;
+        self.remove_directories = remove_directories;
+    }
+
+    /// Compute the state of the target after executing the sync.
+    ///
+    /// Copied files take over the metadata of the source file, since their modification time is
+    /// preserved. Renames are applied to the target before anything else.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Scan of the source end.
+    /// * `target` - Scan of the target end.
+    pub fn apply(
         &self,
+        source: &DirectoryScanList,
+        target: &DirectoryScanList,
+    ) -> DirectoryScanList {
+        fn as_set(paths: &[std::path::PathBuf]) -> std::collections::HashSet<&std::path::Path> {
+            paths.iter().map(|path| path.as_path()).collect()
+        }
+        let remove_directories = as_set(&self.remove_directories);
+        let remove_files = as_set(&self.remove_files);
+        let copy_files = as_set(&self.copy_files);
+        let rename_files = self
+            .rename_files
+            .iter()
+            .map(|(from, to)| (from.as_path(), to))
+            .collect::<std::collections::HashMap<_, _>>();
+        let chmod_files = self
+            .chmod_files
+            .iter()
+            .map(|(path, mode)| (path.as_path(), *mode))
+            .collect::<std::collections::HashMap<_, _>>();
+
+        let mut directories = target
+            .directories()
+            .iter()
+            .filter(|directory| !remove_directories.contains(directory.path.as_path()))
+            .cloned()
+            .collect::<Vec<_>>();
+        directories.extend(
+            self.create_directories
+                .iter()
+                .map(|path| Directory::new(path.clone())),
+        );
+        let mut files = target
+            .files()
+            .iter()
+            .map(|file| {
+                let mut file = file.clone();
+                if let Some(to) = rename_files.get(file.path.as_path()) {
+                    file.path = (*to).clone();
+                }
+                file
+            })
+            .filter(|file| {
+                !remove_files.contains(file.path.as_path())
+                    && !copy_files.contains(file.path.as_path())
+            })
+            .map(|mut file| {
+                if let Some(mode) = chmod_files.get(file.path.as_path()) {
+                    file.mode = Some(*mode);
+                }
+                file
+            })
+            .collect::<Vec<_>>();
+        files.extend(
+            source
+                .files()
+                .iter()
+                .filter(|file| copy_files.contains(file.path.as_path()))
+                .cloned(),
+        );
+        let mut symlinks = target
+            .symlinks()
+            .iter()
+            .map(|symlink| {
+                let mut symlink = symlink.clone();
+                if let Some(to) = rename_files.get(symlink.path.as_path()) {
+                    symlink.path = (*to).clone();
+                }
+                symlink
+            })
+            .filter(|symlink| !remove_files.contains(symlink.path.as_path()))
+            .collect::<Vec<_>>();
+        symlinks.extend(self.create_symlinks.iter().cloned());
+        DirectoryScanList::new(directories, files).with_symlinks(symlinks)
+    }
+
+    /// Execute the sync with the other end of the transport as target.
+    ///
+    /// Directories that were not scanned on the target (see [`Sync::incremental`]) are kept when
+    /// they cannot be removed, since they might hold files that are not part of the sync, and are
+    /// moved to [`Sync::keep_directories`].
+    ///
+    /// # Arguments
+    ///
+    /// * `local_path` - Path of local directory (source).
+    /// * `remote_path` - Path of directory on the other end (target).
+    /// * `transport` - Transport to the other end.
+    /// * `options` - Options for copying files.
+    pub fn execute_remote(
+        &mut self,
         local_path: &std::path::Path,
         remote_path: &std::path::Path,
-        remote: &Host,
+        transport: &mut dyn Transport,
+        options: &TransferOptions,
     ) -> Result<()> {
         // The order of operations is important:
-        // 1. Remove files.
-        // 2. Remove directories.
-        // 3. Create directories.
-        // 4. Copy files.
+        // 1. Rename files.
+        // 2. Remove files.
+        // 3. Remove directories.
+        // 4. Create directories.
+        // 5. Create symlinks.
+        // 6. Copy files.
+        // 7. Change permissions of files that were not copied.
         //
         // This ordering makes sure that no conflicts arise:
+        // * Files should be renamed first, since they might be replaced by copied files.
         // * Files should be removed before directories to prevent removing non-empty directories.
         // * Files should be removed before directories are created to prevent file/directory naming
         //   conflicts.
         // * Files must be copied after directories are created to prevent copying files into
         //   directories that do not exist yet.
 
-        let mut sftp_process = std::process::Command::new("sftp")
-            // Batched mode triggers correct exit status code when one of the
-            // operations fails.
-            .args(["-b", "-"])
-            .arg(format!("{remote}"))
-            .stdin(std::process::Stdio::piped())
-            // XXX: Pipe output to /dev/null. Not doing so will cause the stdout to fill up and
-            // SFTP will stack blocking (both stdout and stderr must be piped).
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .spawn()
-            .context("failed to spawn sftp process")?;
-        // XXX: Skipping deleting remote directories! To do it correctly (only remove directories
-        // that are non-empty) there are two options: Either we do some magic to figure out if the
-        // directory is empty beforehand (we could pull that info out of `DirectoryScanList`) OR
-        // we'd need to run a separate "sftp" instance without batch mode (`-b`) in which case we
-        // could just use `rmdir` and all non-empty dirs would be ignored. For now we do neither.
-        // The reason that we can't delete directories without knowing if they have contents is
-        // that it might be possible that the other side holds ignored files inside the directory.
+        for (from, to) in &self.rename_files {
+            transport.rename(&remote_path.join(from), &remote_path.join(to))?;
+        }
         for file in &self.remove_files {
-            writeln!(
-                sftp_process.stdin.as_mut().unwrap(),
-                "rm {}",
-                remote_path.join(file).to_slash_lossy(),
-            )
-            .context("failed to write data to sftp process")?;
+            transport.rm(&remote_path.join(file))?;
+        }
+        if self.unscanned_directories {
+            // Every directory is removed on its own, since a failed removal aborts the queue.
+            // Directories are removed children-first, so the parents of a kept directory are kept
+            // as well.
+            transport.flush()?;
+            for directory in std::mem::take(&mut self.remove_directories) {
+                match transport
+                    .rmdir(&remote_path.join(&directory))
+                    .and_then(|()| transport.flush())
+                {
+                    Ok(()) => self.remove_directories.push(directory),
+                    Err(_) => self.keep_directories.push(directory),
+                }
+            }
+        } else {
+            for directory in &self.remove_directories {
+                transport.rmdir(&remote_path.join(directory))?;
+            }
         }
         for directory in &self.create_directories {
-            writeln!(
-                sftp_process.stdin.as_mut().unwrap(),
-                "mkdir {}",
-                remote_path.join(directory).to_slash_lossy(),
-            )
-            .context("failed to write data to sftp process")?;
-        }
-        for file in &self.copy_files {
-            writeln!(
-                sftp_process.stdin.as_mut().unwrap(),
-                "put {} {}",
-                local_path.join(file).to_slash_lossy(),
-                remote_path.join(file).to_slash_lossy(),
-            )
-            .context("failed to write data to sftp process")?;
+            transport.mkdir(&remote_path.join(directory))?;
         }
-        let exit_status = sftp_process.wait().context("failed to run sftp command")?;
-        if exit_status.success() {
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("sftp failed: {exit_status}"))
+        for symlink in &self.create_symlinks {
+            transport.symlink(&symlink.target, &remote_path.join(&symlink.path))?;
         }
+        let (delta_files, files) = options.partition_copy_files(&self.copy_files, local_path);
+        match &options.bulk {
+            Some(bulk) if bulk.applies_to(files.len()) => {
+                bulk::put(transport, bulk, local_path, remote_path, &files)?;
+            }
+            _ => {
+                for file in files {
+                    transport.put(&local_path.join(file), &remote_path.join(file))?;
+                }
+            }
+        }
+        // Delta transfers run after all queued operations completed, so they go last to keep the
+        // queue in one piece.
+        if let Some(delta) = &options.delta {
+            for file in delta_files {
+                delta::put(
+                    transport,
+                    &delta.helper,
+                    &local_path.join(file),
+                    &remote_path.join(file),
+                )?;
+            }
+        }
+        for (file, mode) in &self.chmod_files {
+            transport.chmod(&remote_path.join(file), *mode)?;
+        }
+        transport.flush()
     }
 
+    /// Execute the sync with the local host as target.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_path` - Path of local directory (target).
+    /// * `remote_path` - Path of directory on the other end (source).
+    /// * `transport` - Transport to the other end.
+    /// * `options` - Options for copying files.
     pub fn execute_local(
         &self,
         local_path: &std::path::Path,
         remote_path: &std::path::Path,
-        remote: &Host,
+        transport: &mut dyn Transport,
+        options: &TransferOptions,
     ) -> Result<()> {
         // The order of operations is important:
-        // 1. Remove files.
-        // 2. Remove directories.
-        // 3. Create directories.
-        // 4. Copy files.
+        // 1. Rename files.
+        // 2. Remove files.
+        // 3. Remove directories.
+        // 4. Create directories.
+        // 5. Create symlinks.
+        // 6. Copy files.
+        // 7. Change permissions of files that were not copied.
         //
         // This ordering makes sure that no conflicts arise:
+        // * Files should be renamed first, since they might be replaced by copied files.
         // * Files should be removed before directories to prevent removing non-empty directories.
         // * Files should be removed before directories are created to prevent file/directory naming
         //   conflicts.
         // * Files must be copied after directories are created to prevent copying files into
         //   directories that do not exist yet.
 
+        let mut local = LocalTransport::new();
+        for (from, to) in &self.rename_files {
+            local.rename(&local_path.join(from), &local_path.join(to))?;
+        }
         for file in &self.remove_files {
-            std::fs::remove_file(local_path.join(file)).context("failed to remove file")?;
+            local.rm(&local_path.join(file))?;
         }
         for directory in &self.remove_directories {
             // XXX: Only remove the target directory if it is empty! It is possible that the target
@@ -218,38 +768,46 @@ impl Sync {
                 .next()
                 .is_none()
             {
-                std::fs::remove_dir(local_path.join(directory))
-                    .context("failed to remove directory")?;
+                local.rmdir(&local_path.join(directory))?;
             }
         }
         for directory in &self.create_directories {
-            std::fs::create_dir_all(directory).context("failed to create directory")?;
-        }
-        let mut sftp_process = std::process::Command::new("sftp")
-            // Batched mode triggers correct exit status code when one of the
-            // operations fails.
-            .args(["-b", "-"])
-            .arg(format!("{remote}"))
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()
-            .context("failed to spawn sftp process")?;
-        for file in &self.copy_files {
-            writeln!(
-                sftp_process.stdin.as_mut().unwrap(),
-                "get {} {}",
-                remote_path.join(file).to_slash_lossy(),
-                local_path.join(file).to_slash_lossy(),
-            )
-            .context("failed to write data to sftp process")?;
+            local.mkdir(&local_path.join(directory))?;
         }
-        let exit_status = sftp_process.wait().context("failed to run sftp command")?;
-        if exit_status.success() {
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("sftp failed: {exit_status}"))
+        for symlink in &self.create_symlinks {
+            local.symlink(&symlink.target, &local_path.join(&symlink.path))?;
+        }
+        let (delta_files, files) = options.partition_copy_files(&self.copy_files, local_path);
+        match &options.bulk {
+            Some(bulk) if bulk.applies_to(files.len()) => {
+                bulk::get(transport, bulk, remote_path, local_path, &files)?;
+            }
+            _ => {
+                for file in files {
+                    transport.get(&remote_path.join(file), &local_path.join(file))?;
+                }
+            }
         }
+        // Delta transfers run after all queued operations completed, so they go last to keep the
+        // queue in one piece.
+        if let Some(delta) = &options.delta {
+            for file in delta_files {
+                delta::get(
+                    transport,
+                    &delta.helper,
+                    &remote_path.join(file),
+                    &local_path.join(file),
+                )?;
+            }
+        }
+        for (file, mode) in &self.chmod_files {
+            local.chmod(&local_path.join(file), *mode)?;
+        }
+        transport.flush()
+    }
+
+    pub fn rename_files(&self) -> &[(std::path::PathBuf, std::path::PathBuf)] {
+        &self.rename_files
     }
 
     pub fn remove_files(&self) -> &[std::path::PathBuf] {
@@ -260,11 +818,217 @@ impl Sync {
         &self.remove_directories
     }
 
+    pub fn keep_directories(&self) -> &[std::path::PathBuf] {
+        &self.keep_directories
+    }
+
     pub fn create_directories(&self) -> &[std::path::PathBuf] {
         &self.create_directories
     }
 
+    pub fn create_symlinks(&self) -> &[Symlink] {
+        &self.create_symlinks
+    }
+
     pub fn copy_files(&self) -> &[std::path::PathBuf] {
         &self.copy_files
     }
+
+    pub fn chmod_files(&self) -> &[(std::path::PathBuf, u32)] {
+        &self.chmod_files
+    }
+}
+
+impl TransferOptions {
+    /// Split files to copy into the files that are transferred as a delta, and all other files.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - Relative paths of files to copy.
+    /// * `local_path` - Path of local directory.
+    fn partition_copy_files<'a>(
+        &self,
+        files: &'a [std::path::PathBuf],
+        local_path: &std::path::Path,
+    ) -> (Vec<&'a std::path::Path>, Vec<&'a std::path::Path>) {
+        files.iter().map(|file| file.as_path()).partition(|file| {
+            self.delta
+                .as_ref()
+                .is_some_and(|delta| delta.applies_to(&local_path.join(file)))
+        })
+    }
+}
+
+/// Plan for a two-way sync, as computed by [`Sync::bidirectional`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BidirectionalSync {
+    to_remote: Sync,
+    to_local: Sync,
+    conflicts: Vec<Conflict>,
+}
+
+impl BidirectionalSync {
+    /// Compute the baseline for the next sync, which is the state of both ends after executing
+    /// this sync.
+    ///
+    /// Conflicting files that were left untouched keep their previous baseline entries, so that
+    /// they are detected as conflicts again next time.
+    ///
+    /// # Arguments
+    ///
+    /// * `local` - Scan of the local end.
+    /// * `remote` - Scan of the remote end.
+    /// * `previous` - Baseline that the sync was computed with.
+    pub fn baseline(
+        &self,
+        local: &DirectoryScanList,
+        remote: &DirectoryScanList,
+        previous: &Baseline,
+    ) -> Baseline {
+        let untouched = self
+            .conflicts
+            .iter()
+            .filter(|conflict| {
+                matches!(
+                    conflict.policy,
+                    ConflictPolicy::Skip | ConflictPolicy::Abort | ConflictPolicy::Merge
+                )
+            })
+            .map(|conflict| &conflict.path)
+            .collect::<std::collections::HashSet<_>>();
+        let keep_untouched = |scan: DirectoryScanList, previous: &DirectoryScanList| {
+            let mut symlinks = scan.symlinks().to_vec();
+            let (directories, mut files) = scan.into_parts();
+            files.retain(|file| !untouched.contains(&file.path));
+            files.extend(
+                previous
+                    .files()
+                    .iter()
+                    .filter(|file| untouched.contains(&file.path))
+                    .cloned(),
+            );
+            symlinks.retain(|symlink| !untouched.contains(&symlink.path));
+            symlinks.extend(
+                previous
+                    .symlinks()
+                    .iter()
+                    .filter(|symlink| untouched.contains(&symlink.path))
+                    .cloned(),
+            );
+            DirectoryScanList::new(directories, files).with_symlinks(symlinks)
+        };
+        // The remote end is synced first, so the local end receives files as they are on the
+        // remote end after that (this matters for renamed conflict copies).
+        let remote_after = self.to_remote.apply(local, remote);
+        let local_after = self.to_local.apply(&remote_after, local);
+        Baseline::new(
+            keep_untouched(local_after, &previous.local),
+            keep_untouched(remote_after, &previous.remote),
+        )
+    }
+
+    /// Keep directories that would be removed from the remote end, but still hold ignored files.
+    /// See [`Sync::keep_nonempty_directories`].
+    ///
+    /// # Arguments
+    ///
+    /// * `remote` - Scan of the remote end that includes ignored files.
+    pub fn keep_nonempty_remote_directories(&mut self, remote: &DirectoryScanList) {
+        self.to_remote.keep_nonempty_directories(remote);
+    }
+
+    pub fn to_remote(&self) -> &Sync {
+        &self.to_remote
+    }
+
+    pub fn to_remote_mut(&mut self) -> &mut Sync {
+        &mut self.to_remote
+    }
+
+    pub fn to_local(&self) -> &Sync {
+        &self.to_local
+    }
+
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+}
+
+/// Path for the remote version of a conflicting file: `<file>.conflict-<remote>`, followed by a
+/// counter if the first choice is already taken.
+fn conflict_path(path: &std::path::Path, remote_name: &str, n: usize) -> std::path::PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".conflict-{remote_name}"));
+    if n > 1 {
+        file_name.push(format!("-{n}"));
+    }
+    path.with_file_name(file_name)
+}
+
+/// File or symlink on one end of a two-way sync.
+#[derive(Debug, Clone, Copy)]
+enum Entry<'a> {
+    File(&'a File),
+    Symlink(&'a Symlink),
+}
+
+impl Entry<'_> {
+    /// Whether or not this entry differs from `other` in kind, contents or link target.
+    /// Permission bits are compared separately.
+    fn differs_from(&self, other: &Entry) -> bool {
+        match (self, other) {
+            (Entry::File(file), Entry::File(other)) => file.differs_from(other),
+            (Entry::Symlink(symlink), Entry::Symlink(other)) => symlink.target != other.target,
+            _ => true,
+        }
+    }
+}
+
+impl Sync {
+    /// Make an entry of the source appear on the target, replacing what the target has at its
+    /// path (if anything).
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the entry on the target.
+    /// * `source` - Entry on the source end.
+    /// * `target` - Entry on the target end, which is removed first if it cannot be overwritten.
+    fn replace(&mut self, path: std::path::PathBuf, source: Entry, target: Option<Entry>) {
+        // Symlinks cannot be overwritten, and copying a file onto a symlink would write to the
+        // file that the symlink points to.
+        if matches!(
+            (source, target),
+            (Entry::Symlink(_), Some(_)) | (_, Some(Entry::Symlink(_)))
+        ) {
+            self.remove_files.push(path.clone());
+        }
+        match source {
+            Entry::File(_) => self.copy_files.push(path),
+            Entry::Symlink(symlink) => self
+                .create_symlinks
+                .push(Symlink::new(path, symlink.target.clone())),
+        }
+    }
+}
+
+/// Whether or not a file or symlink changed since the baseline (including creation and deletion).
+/// Permission bits are compared separately.
+fn is_changed(current: Option<Entry>, base: Option<Entry>) -> bool {
+    match (current, base) {
+        (Some(current), Some(base)) => current.differs_from(&base),
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+/// New permission bits of a file whose permission bits changed since the baseline.
+fn mode_change(current: Option<Entry>, base: Option<Entry>) -> Option<u32> {
+    match (current, base) {
+        (Some(Entry::File(current)), Some(Entry::File(base)))
+            if current.mode_differs_from(base) =>
+        {
+            current.mode
+        }
+        _ => None,
+    }
 }
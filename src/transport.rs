@@ -0,0 +1,227 @@
+mod helper;
+mod local;
+#[cfg(feature = "native-ssh")]
+mod native;
+pub mod ssh;
+
+pub use helper::HelperTransport;
+pub use local::LocalTransport;
+#[cfg(feature = "native-ssh")]
+pub use native::NativeSshTransport;
+pub use ssh::SshTransport;
+
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+
+use crate::host::Platform;
+use crate::scan::{self, DirectoryScanList, ScanOptions};
+
+/// Provides access to the file system on the other end of a sync.
+///
+/// All paths that refer to the other end are absolute or relative to its working directory (the
+/// home directory for SSH). Operations that modify the other end may be queued until
+/// [`Transport::flush`] is called, so errors may be reported late.
+pub trait Transport {
+    /// Scans a directory. On remote hosts, the directory is created first if it does not yet
+    /// exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to scan.
+    /// * `options` - Scan options.
+    /// * `prune` - Directories (relative to the path) that are listed, but not descended into.
+    fn scan(
+        &mut self,
+        path: &std::path::Path,
+        options: ScanOptions,
+        prune: &[std::path::PathBuf],
+    ) -> Result<DirectoryScanList>;
+
+    /// Creates a directory. The parent directory must exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of directory to create.
+    fn mkdir(&mut self, path: &std::path::Path) -> Result<()>;
+
+    /// Removes a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of file to remove.
+    fn rm(&mut self, path: &std::path::Path) -> Result<()>;
+
+    /// Removes an empty directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of directory to remove.
+    fn rmdir(&mut self, path: &std::path::Path) -> Result<()>;
+
+    /// Renames a file, replacing the destination if it exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `from` - Path of file to rename.
+    /// * `to` - New path of file.
+    fn rename(&mut self, from: &std::path::Path, to: &std::path::Path) -> Result<()>;
+
+    /// Creates a symlink.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Path that the link points to.
+    /// * `path` - Path of link to create.
+    fn symlink(&mut self, target: &std::path::Path, path: &std::path::Path) -> Result<()>;
+
+    /// Changes the permission bits of a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of file.
+    /// * `mode` - Unix permission bits.
+    fn chmod(&mut self, path: &std::path::Path, mode: u32) -> Result<()>;
+
+    /// Copies a local file to the other end, preserving its modification time and permissions.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_path` - Path of local file to copy.
+    /// * `path` - Path to copy the file to.
+    fn put(&mut self, local_path: &std::path::Path, path: &std::path::Path) -> Result<()>;
+
+    /// Copies a file from the other end to the local host, preserving its modification time and
+    /// permissions.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of file to copy.
+    /// * `local_path` - Local path to copy the file to.
+    fn get(&mut self, path: &std::path::Path, local_path: &std::path::Path) -> Result<()>;
+
+    /// Computes the hex-encoded SHA-256 digests of files, in the same order as the files.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of directory that holds the files.
+    /// * `files` - Paths of files, relative to the directory.
+    fn checksums(
+        &mut self,
+        path: &std::path::Path,
+        files: &[std::path::PathBuf],
+    ) -> Result<Vec<String>>;
+
+    /// Command that runs `git-repo-sync` on the other end in [`Transport::exec`], if the transport
+    /// provides one of its own.
+    fn helper(&self) -> Option<&str>;
+
+    /// Runs a shell command on the other end, after all queued operations completed.
+    ///
+    /// The input is written while the output is read, so the command may write output before it
+    /// has read all of its input.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - Command to run.
+    /// * `stdin` - Input for the command.
+    /// * `stdout` - Output of the command.
+    fn exec(
+        &mut self,
+        command: &str,
+        stdin: &mut (dyn std::io::Read + Send),
+        stdout: &mut (dyn std::io::Write + Send),
+    ) -> Result<()>;
+
+    /// Waits for all queued operations to complete.
+    fn flush(&mut self) -> Result<()>;
+}
+
+/// Computes the digests of files on the other end with a shell command, for
+/// [`Transport::checksums`].
+///
+/// # Arguments
+///
+/// * `transport` - Transport to run the command with.
+/// * `path` - Path of directory that holds the files.
+/// * `platform` - Operating system family of the other end.
+/// * `files` - Paths of files, relative to the directory.
+fn exec_checksums(
+    transport: &mut impl Transport,
+    path: &std::path::Path,
+    platform: Platform,
+    files: &[std::path::PathBuf],
+) -> Result<Vec<String>> {
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut stdin = Vec::new();
+    for file in files {
+        // The paths are prefixed with `./`, so that none of them is taken for an option.
+        stdin.extend_from_slice(b"./");
+        stdin.extend_from_slice(&crate::fs::path_to_bytes(file));
+        stdin.push(0);
+    }
+    let mut stdout = Vec::new();
+    transport
+        .exec(
+            &DirectoryScanList::remote_checksum_command(path, platform),
+            &mut stdin.as_slice(),
+            &mut stdout,
+        )
+        .context("failed to compute checksums of remote files")?;
+    scan::parse_remote_checksums(&stdout, files)
+}
+
+/// Runs a process with the given input, and copies its output to `stdout`.
+///
+/// Input is written on a separate thread while output is read, so that neither pipe can fill up
+/// and block the process.
+///
+/// # Arguments
+///
+/// * `command` - Process to run.
+/// * `stdin` - Input for the process.
+/// * `stdout` - Output of the process.
+fn exec_process(
+    command: &mut std::process::Command,
+    stdin: &mut (dyn Read + Send),
+    stdout: &mut (dyn Write + Send),
+) -> Result<()> {
+    let mut process = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("failed to spawn command")?;
+    let mut process_stdin = process.stdin.take().unwrap();
+    let mut process_stdout = process.stdout.take().unwrap();
+    let mut process_stderr = process.stderr.take().unwrap();
+    let (written, stderr) = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || std::io::copy(stdin, &mut process_stdin));
+        let stderr = scope.spawn(move || {
+            let mut stderr = String::new();
+            let _ = process_stderr.read_to_string(&mut stderr);
+            stderr
+        });
+        let read = std::io::copy(&mut process_stdout, stdout);
+        (
+            writer.join().unwrap().and(read),
+            stderr.join().unwrap_or_default(),
+        )
+    });
+    let exit_status = process.wait().context("failed to run command")?;
+    if !exit_status.success() {
+        return Err(anyhow::anyhow!(
+            "remote command failed with status code {}: {}",
+            exit_status
+                .code()
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "<no status code>".to_string()),
+            stderr.trim(),
+        ));
+    }
+    written
+        .map(|_| ())
+        .context("failed to transfer remote command data")
+}
@@ -0,0 +1,447 @@
+use std::io::{BufRead, Read, Write};
+
+use anyhow::{Context, Result};
+
+use crate::host::{Host, Platform};
+use crate::protocol::{self, Opcode};
+use crate::scan::{DirectoryScanList, ScanOptions};
+use crate::transport::Transport;
+
+/// Transport that runs a helper on the remote host (`git-repo-sync --server`) and talks to it over
+/// one `ssh` session, instead of running shell commands and `sftp`.
+///
+/// The helper is a copy of the local executable in a cache directory on the remote host. When it
+/// is not present yet, it is uploaded first. Requests are sent without waiting for the helper to
+/// carry them out, and their statuses are read when the transport is flushed (or when it needs a
+/// result), so errors may be reported late.
+pub struct HelperTransport {
+    /// Path of helper on the remote host (quoted for the shell).
+    helper_path: String,
+    process: std::process::Child,
+    /// Closed when the transport is dropped, which makes the helper exit.
+    writer: Option<std::io::BufWriter<std::process::ChildStdin>>,
+    reader: std::io::BufReader<std::process::ChildStdout>,
+    /// Responses that have not been read yet, in the order of the requests.
+    pending: std::collections::VecDeque<Pending>,
+}
+
+/// Response of a request that has been sent, but not read yet.
+enum Pending {
+    /// Only a status.
+    Status,
+    /// A status, followed by a file that is written to the given local path.
+    File(std::path::PathBuf),
+}
+
+/// Maximum number of responses that are left unread. Once there are more, they are read before
+/// more requests are sent, so that the unread responses (and requests for files) always fit in the
+/// pipes, and neither end blocks while the other end waits for it.
+const MAX_PENDING: usize = 64;
+
+/// Outcome of starting the remote helper.
+enum Start {
+    /// The helper is running.
+    Started(HelperTransport),
+    /// The helper is not present on the remote host, which runs the given operating system and
+    /// architecture (as printed by `uname -s` and `uname -m`).
+    Missing(String, String),
+}
+
+impl HelperTransport {
+    /// Start the helper on a remote host, uploading it first when it is not present.
+    ///
+    /// Fails if the helper cannot run on the remote host, which is the case for Windows hosts,
+    /// hosts that run another operating system or architecture, and (on Linux) when the local
+    /// executable is dynamically linked, since it might need other libraries than the remote host
+    /// provides.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - SSH host to run the helper on.
+    pub fn connect(host: &Host) -> Result<Self> {
+        if host.platform() == Platform::Windows {
+            return Err(anyhow::anyhow!(
+                "the remote helper is not supported on Windows hosts"
+            ));
+        }
+        // The helper is named after the build, so that every build uploads its own copy, and the
+        // protocol on both ends always matches.
+        let helper_path = format!(
+            "\"${{XDG_CACHE_HOME:-$HOME/.cache}}/git-repo-sync/git-repo-sync-{}-{}\"",
+            env!("CARGO_PKG_VERSION"),
+            env!("GIT_REPO_SYNC_BUILD_ID"),
+        );
+        match Self::start(host, &helper_path)? {
+            Start::Started(transport) => Ok(transport),
+            Start::Missing(os, arch) => {
+                if !is_local_platform(&os, &arch) {
+                    return Err(anyhow::anyhow!(
+                        "remote runs {os} on {arch}, but the local executable is built for {} on {}",
+                        std::env::consts::OS,
+                        std::env::consts::ARCH,
+                    ));
+                }
+                let executable =
+                    std::env::current_exe().context("failed to determine path of executable")?;
+                if !is_static_executable(&executable)? {
+                    return Err(anyhow::anyhow!(
+                        "the local executable is dynamically linked, so it is not uploaded"
+                    ));
+                }
+                upload(host, &executable, &helper_path)?;
+                match Self::start(host, &helper_path)? {
+                    Start::Started(transport) => Ok(transport),
+                    Start::Missing(..) => Err(anyhow::anyhow!(
+                        "remote helper is missing after uploading it to {host}"
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Start the helper on a remote host, if it is present.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - SSH host to run the helper on.
+    /// * `helper_path` - Path of helper on the remote host (quoted for the shell).
+    fn start(host: &Host, helper_path: &str) -> Result<Start> {
+        // When the helper is not present, the command prints the platform of the remote host in
+        // the same round trip, which tells whether or not the local executable can run there.
+        let mut process = std::process::Command::new("ssh")
+            .arg(format!("{host}"))
+            .arg(format!(
+                "helper={helper_path}; if [ -x \"$helper\" ]; then exec \"$helper\" {}; fi; echo \"missing $(uname -s) $(uname -m)\"",
+                crate::server::SERVER_ARG,
+            ))
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .context("failed to spawn ssh command")?;
+        let writer = std::io::BufWriter::new(process.stdin.take().unwrap());
+        let mut reader = std::io::BufReader::new(process.stdout.take().unwrap());
+        let mut stderr = process.stderr.take().unwrap();
+        // Errors of starting the helper (such as an executable that cannot run on the remote host)
+        // are reported with what the remote shell printed.
+        let mut fail = |message: String| -> anyhow::Error {
+            let _ = process.kill();
+            let _ = process.wait();
+            let mut output = String::new();
+            let _ = stderr.read_to_string(&mut output);
+            match output.trim() {
+                "" => anyhow::anyhow!("{message}"),
+                output => anyhow::anyhow!("{message}: {output}"),
+            }
+        };
+
+        let mut magic = [0; 4];
+        if reader.read_exact(&mut magic).is_err() {
+            return Err(fail("failed to start remote helper".to_string()));
+        }
+        if &magic != protocol::MAGIC {
+            let mut line = String::new();
+            let _ = reader.read_line(&mut line);
+            let line = String::from_utf8_lossy(&magic).into_owned() + &line;
+            let mut words = line.split_whitespace();
+            return match (words.next(), words.next(), words.next()) {
+                (Some("missing"), Some(os), Some(arch)) => {
+                    let _ = process.wait();
+                    Ok(Start::Missing(os.to_string(), arch.to_string()))
+                }
+                _ => Err(fail(format!(
+                    "failed to start remote helper: {}",
+                    line.trim()
+                ))),
+            };
+        }
+        match protocol::read_u32(&mut reader) {
+            Ok(protocol::VERSION) => {}
+            Ok(version) => {
+                return Err(fail(format!(
+                    "remote helper speaks protocol version {version} instead of {}",
+                    protocol::VERSION
+                )))
+            }
+            Err(_) => return Err(fail("failed to start remote helper".to_string())),
+        }
+        // Once it runs, the helper reports errors in its responses, but anything else that it
+        // prints is passed on.
+        std::thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::stderr()));
+        Ok(Start::Started(HelperTransport {
+            helper_path: helper_path.to_string(),
+            process,
+            writer: Some(writer),
+            reader,
+            pending: std::collections::VecDeque::new(),
+        }))
+    }
+
+    /// Send a request that operates on paths, whose status is read later.
+    ///
+    /// # Arguments
+    ///
+    /// * `opcode` - Opcode of request.
+    /// * `paths` - Paths to operate on.
+    fn request(&mut self, opcode: Opcode, paths: &[&std::path::Path]) -> Result<()> {
+        self.send(opcode, paths)?;
+        self.push(Pending::Status)
+    }
+
+    /// Record the response of a request that has been sent, and read the unread responses when
+    /// there are too many.
+    ///
+    /// # Arguments
+    ///
+    /// * `pending` - Response of request.
+    fn push(&mut self, pending: Pending) -> Result<()> {
+        self.pending.push_back(pending);
+        if self.pending.len() > MAX_PENDING {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn writer(&mut self) -> &mut std::io::BufWriter<std::process::ChildStdin> {
+        self.writer.as_mut().unwrap()
+    }
+
+    /// Write the opcode and paths of a request, without flushing it.
+    ///
+    /// # Arguments
+    ///
+    /// * `opcode` - Opcode of request.
+    /// * `paths` - Paths to operate on.
+    fn send(&mut self, opcode: Opcode, paths: &[&std::path::Path]) -> Result<()> {
+        protocol::write_u8(self.writer(), opcode as u8)
+            .context("failed to write to remote helper")?;
+        for path in paths {
+            protocol::write_path(self.writer(), path)
+                .context("failed to write to remote helper")?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for HelperTransport {
+    fn scan(
+        &mut self,
+        path: &std::path::Path,
+        options: ScanOptions,
+        prune: &[std::path::PathBuf],
+    ) -> Result<DirectoryScanList> {
+        // Queued requests are completed first, so that their errors are not mixed up with the
+        // result.
+        self.flush()?;
+        self.send(Opcode::Scan, &[path])?;
+        protocol::write_scan_options(self.writer(), options)
+            .and_then(|()| protocol::write_paths(self.writer(), prune))
+            .context("failed to write to remote helper")?;
+        self.flush()?;
+        protocol::read_status(&mut self.reader)?;
+        protocol::read_scan(&mut self.reader).context("failed to read scan from remote helper")
+    }
+
+    fn mkdir(&mut self, path: &std::path::Path) -> Result<()> {
+        self.request(Opcode::Mkdir, &[path])
+    }
+
+    fn rm(&mut self, path: &std::path::Path) -> Result<()> {
+        self.request(Opcode::Rm, &[path])
+    }
+
+    fn rmdir(&mut self, path: &std::path::Path) -> Result<()> {
+        self.request(Opcode::Rmdir, &[path])
+    }
+
+    fn rename(&mut self, from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+        self.request(Opcode::Rename, &[from, to])
+    }
+
+    fn symlink(&mut self, target: &std::path::Path, path: &std::path::Path) -> Result<()> {
+        self.request(Opcode::Symlink, &[target, path])
+    }
+
+    fn chmod(&mut self, path: &std::path::Path, mode: u32) -> Result<()> {
+        self.send(Opcode::Chmod, &[path])?;
+        protocol::write_u32(self.writer(), mode).context("failed to write to remote helper")?;
+        self.push(Pending::Status)
+    }
+
+    fn put(&mut self, local_path: &std::path::Path, path: &std::path::Path) -> Result<()> {
+        // The helper writes the file to a temporary file first, which is then moved into place.
+        let file = std::fs::File::open(local_path).context("failed to open file")?;
+        self.send(Opcode::Put, &[path])?;
+        protocol::write_file(self.writer(), file)?;
+        self.push(Pending::Status)
+    }
+
+    fn get(&mut self, path: &std::path::Path, local_path: &std::path::Path) -> Result<()> {
+        self.send(Opcode::Get, &[path])?;
+        self.push(Pending::File(local_path.to_path_buf()))
+    }
+
+    fn checksums(
+        &mut self,
+        path: &std::path::Path,
+        files: &[std::path::PathBuf],
+    ) -> Result<Vec<String>> {
+        self.flush()?;
+        self.send(Opcode::Checksums, &[path])?;
+        protocol::write_paths(self.writer(), files).context("failed to write to remote helper")?;
+        self.flush()?;
+        protocol::read_status(&mut self.reader)?;
+        files
+            .iter()
+            .map(|_| {
+                let checksum = protocol::read_bytes(&mut self.reader)
+                    .context("failed to read checksum from remote helper")?;
+                String::from_utf8(checksum).context("malformed checksum from remote helper")
+            })
+            .collect()
+    }
+
+    fn helper(&self) -> Option<&str> {
+        Some(&self.helper_path)
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        stdin: &mut (dyn Read + Send),
+        stdout: &mut (dyn Write + Send),
+    ) -> Result<()> {
+        // The command runs in the session of the helper, so that no other SSH session is needed.
+        self.flush()?;
+        self.send(Opcode::Exec, &[])?;
+        protocol::write_bytes(self.writer(), command.as_bytes())
+            .context("failed to write to remote helper")?;
+        let writer = self.writer.as_mut().unwrap();
+        let reader = &mut self.reader;
+        let (input, output) = std::thread::scope(|scope| {
+            let input = scope.spawn(move || {
+                let input = protocol::write_chunks(writer, stdin)?;
+                writer.flush().map(|()| input)
+            });
+            let output = protocol::read_chunks(reader, stdout);
+            (input.join().unwrap(), output)
+        });
+        let input = input.context("failed to write to remote helper")?;
+        let output = output.context("failed to read from remote helper")?;
+        protocol::read_status(&mut self.reader)?;
+        input.context("failed to read command input")?;
+        output.context("failed to write command output")
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer()
+            .flush()
+            .context("failed to write to remote helper")?;
+        // All responses are read, even after an error, so that the stream stays usable. The first
+        // error is reported.
+        let mut result = Ok(());
+        while let Some(pending) = self.pending.pop_front() {
+            let status = protocol::read_status(&mut self.reader);
+            let status = match (status, pending) {
+                (Ok(()), Pending::File(local_path)) => {
+                    protocol::read_file(&mut self.reader, &local_path)
+                        .context("failed to read file from remote helper")?
+                }
+                (status, _) => status,
+            };
+            if result.is_ok() {
+                result = status;
+            }
+        }
+        result
+    }
+}
+
+impl Drop for HelperTransport {
+    fn drop(&mut self) {
+        drop(self.writer.take());
+        let _ = self.process.wait();
+    }
+}
+
+/// Upload the local executable to the remote host as the helper.
+///
+/// The executable is uploaded to a temporary file first, which is then moved into place, so that
+/// an interrupted upload never leaves a broken helper behind.
+///
+/// # Arguments
+///
+/// * `host` - SSH host to upload to.
+/// * `executable` - Path of local executable.
+/// * `helper_path` - Path of helper on the remote host (quoted for the shell).
+fn upload(host: &Host, executable: &std::path::Path, helper_path: &str) -> Result<()> {
+    let mut file = std::fs::File::open(executable).context("failed to open executable")?;
+    super::exec_process(
+        std::process::Command::new("ssh").arg(format!("{host}")).arg(format!(
+            "helper={helper_path}; mkdir -p \"$(dirname \"$helper\")\" && cat > \"$helper.tmp\" && chmod +x \"$helper.tmp\" && mv -f \"$helper.tmp\" \"$helper\""
+        )),
+        &mut file,
+        &mut std::io::sink(),
+    )
+    .context("failed to upload remote helper")
+}
+
+/// Whether or not an executable is statically linked, which makes it independent of the libraries
+/// on the remote host.
+///
+/// On Linux, an ELF executable is dynamically linked when it names a program interpreter (the
+/// dynamic loader). Executables on other operating systems only link to system libraries, which
+/// are the same on every host that runs the same operating system.
+///
+/// # Arguments
+///
+/// * `executable` - Path of executable.
+fn is_static_executable(executable: &std::path::Path) -> Result<bool> {
+    /// Type of the program header that names the program interpreter.
+    const PT_INTERP: u32 = 3;
+
+    if !cfg!(target_os = "linux") {
+        return Ok(true);
+    }
+    let contents = std::fs::read(executable).context("failed to read executable")?;
+    let malformed = || anyhow::anyhow!("malformed executable: {}", executable.display());
+    if contents.get(..4) != Some(b"\x7fELF") {
+        return Err(malformed());
+    }
+    let is_64_bit = contents.get(4) == Some(&2);
+    let is_big_endian = contents.get(5) == Some(&2);
+    let read = |offset: usize, len: usize| -> Result<u64> {
+        let bytes = contents.get(offset..offset + len).ok_or_else(malformed)?;
+        Ok(bytes.iter().enumerate().fold(0, |value, (i, &byte)| {
+            let shift = if is_big_endian { len - 1 - i } else { i };
+            value | (byte as u64) << (8 * shift)
+        }))
+    };
+    let (phoff, phentsize, phnum) = if is_64_bit {
+        (read(0x20, 8)?, read(0x36, 2)?, read(0x38, 2)?)
+    } else {
+        (read(0x1c, 4)?, read(0x2a, 2)?, read(0x2c, 2)?)
+    };
+    for i in 0..phnum {
+        if read((phoff + i * phentsize) as usize, 4)? as u32 == PT_INTERP {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether or not the local executable can run on a host with the given operating system and
+/// architecture (as printed by `uname -s` and `uname -m`).
+fn is_local_platform(os: &str, arch: &str) -> bool {
+    let os = match os {
+        "Darwin" => "macos",
+        os => &os.to_lowercase(),
+    };
+    let arch = match arch {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        "i386" | "i686" => "x86",
+        arch => arch,
+    };
+    os == std::env::consts::OS && arch == std::env::consts::ARCH
+}
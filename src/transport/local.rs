@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+
+use crate::scan::{DirectoryScanList, ScanOptions};
+use crate::transport::Transport;
+
+/// Transport that operates on the local file system, for example to sync to a mounted network
+/// share.
+#[derive(Debug, Default)]
+pub struct LocalTransport;
+
+impl LocalTransport {
+    pub fn new() -> Self {
+        LocalTransport
+    }
+}
+
+impl Transport for LocalTransport {
+    fn scan(
+        &mut self,
+        path: &std::path::Path,
+        options: ScanOptions,
+        prune: &[std::path::PathBuf],
+    ) -> Result<DirectoryScanList> {
+        // XXX: Unlike on remote hosts, the directory is not created. A mistyped local directory
+        // would otherwise be taken for an empty one, and syncing from it removes every file.
+        if !path.is_dir() {
+            return Err(anyhow::anyhow!(
+                "directory does not exist: {}",
+                path.display()
+            ));
+        }
+        let prune = prune
+            .iter()
+            .map(|path| path.as_path())
+            .collect::<std::collections::HashSet<_>>();
+        DirectoryScanList::from_local_file_system(path, options, &|path| prune.contains(path))
+    }
+
+    fn mkdir(&mut self, path: &std::path::Path) -> Result<()> {
+        std::fs::create_dir(path)
+            .with_context(|| format!("failed to create directory: {}", path.display()))
+    }
+
+    fn rm(&mut self, path: &std::path::Path) -> Result<()> {
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove file: {}", path.display()))
+    }
+
+    fn rmdir(&mut self, path: &std::path::Path) -> Result<()> {
+        std::fs::remove_dir(path)
+            .with_context(|| format!("failed to remove directory: {}", path.display()))
+    }
+
+    fn rename(&mut self, from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+        std::fs::rename(from, to).with_context(|| {
+            format!(
+                "failed to rename file: {} -> {}",
+                from.display(),
+                to.display()
+            )
+        })
+    }
+
+    fn symlink(&mut self, target: &std::path::Path, path: &std::path::Path) -> Result<()> {
+        crate::fs::symlink(target, path)
+    }
+
+    fn chmod(&mut self, path: &std::path::Path, mode: u32) -> Result<()> {
+        crate::fs::set_mode(path, mode)
+    }
+
+    fn put(&mut self, local_path: &std::path::Path, path: &std::path::Path) -> Result<()> {
+        copy(local_path, path)
+    }
+
+    fn get(&mut self, path: &std::path::Path, local_path: &std::path::Path) -> Result<()> {
+        copy(path, local_path)
+    }
+
+    fn checksums(
+        &mut self,
+        path: &std::path::Path,
+        files: &[std::path::PathBuf],
+    ) -> Result<Vec<String>> {
+        crate::fs::checksums(path, files)
+    }
+
+    fn helper(&self) -> Option<&str> {
+        None
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        stdin: &mut (dyn std::io::Read + Send),
+        stdout: &mut (dyn std::io::Write + Send),
+    ) -> Result<()> {
+        let mut shell = if cfg!(windows) {
+            let mut shell = std::process::Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = std::process::Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        super::exec_process(shell.arg(command), stdin, stdout)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Copies a file and its modification time.
+///
+/// The file is copied to a temporary file first, which is then moved into place.
+fn copy(from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+    let temporary_path = crate::fs::temporary_path(to);
+    let result = (|| -> Result<()> {
+        std::fs::copy(from, &temporary_path).context("failed to copy file")?;
+        let modified = std::fs::metadata(from)
+            .context("failed to fetch file metadata")?
+            .modified()
+            .context("failed to fetch file modification time")?;
+        std::fs::File::options()
+            .write(true)
+            .open(&temporary_path)
+            .context("failed to open file")?
+            .set_modified(modified)
+            .context("failed to set file modification time")?;
+        std::fs::rename(&temporary_path, to).context("failed to rename file")
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary_path);
+    }
+    result.with_context(|| {
+        format!(
+            "failed to copy file: {} -> {}",
+            from.display(),
+            to.display()
+        )
+    })
+}
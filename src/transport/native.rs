@@ -0,0 +1,546 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+
+use crate::host::{Host, Platform};
+use crate::scan::{DirectoryScanList, ScanOptions};
+use crate::transport::ssh::shell_quote_path;
+use crate::transport::Transport;
+
+/// Default port for SSH connections.
+const DEFAULT_PORT: u16 = 22;
+
+/// Error code of libssh2 for an operation that would block in non-blocking mode.
+const LIBSSH2_ERROR_EAGAIN: std::ffi::c_int = -37;
+
+/// Identity files that are tried when the SSH config does not specify any (same as OpenSSH).
+const DEFAULT_IDENTITY_FILES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// Transport that uses an in-process SSH and SFTP client to operate on a remote host.
+///
+/// Both the scan and all file operations go over one authenticated connection. Host aliases and
+/// options from `~/.ssh/config` are resolved in the same way as the `ssh` command does for the
+/// most commonly used options (`HostName`, `User`, `Port` and `IdentityFile`).
+pub struct NativeSshTransport {
+    session: ssh2::Session,
+    sftp: ssh2::Sftp,
+    platform: Platform,
+}
+
+impl NativeSshTransport {
+    /// Connect and authenticate to a remote host.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - SSH host to connect to.
+    pub fn connect(host: &Host) -> Result<Self> {
+        let home = home_dir()?;
+        let platform = host.platform();
+        let host = host.to_string();
+        let (user, alias) = match host.split_once('@') {
+            Some((user, alias)) => (Some(user.to_string()), alias.to_string()),
+            None => (None, host),
+        };
+        let config = match std::fs::read_to_string(home.join(".ssh").join("config")) {
+            Ok(config) => SshConfig::resolve(&config, &alias),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => SshConfig::default(),
+            Err(err) => return Err(err).context("failed to read ssh config"),
+        };
+
+        let hostname = config.hostname.unwrap_or_else(|| alias.clone());
+        let port = config.port.unwrap_or(DEFAULT_PORT);
+        let user = user
+            .or(config.user)
+            .or_else(|| std::env::var("USER").ok())
+            .or_else(|| std::env::var("USERNAME").ok())
+            .ok_or_else(|| anyhow::anyhow!("failed to determine ssh user for host: {alias}"))?;
+
+        let tcp = std::net::TcpStream::connect((hostname.as_str(), port))
+            .with_context(|| format!("failed to connect to {hostname}:{port}"))?;
+        let mut session = ssh2::Session::new().context("failed to create ssh session")?;
+        session.set_tcp_stream(tcp);
+        session.handshake().context("ssh handshake failed")?;
+        check_known_host(&session, &home, &hostname, port)?;
+
+        // Try the SSH agent first, then fall back to the identity files.
+        if session.userauth_agent(&user).is_err() {
+            let identity_files = if config.identity_files.is_empty() {
+                DEFAULT_IDENTITY_FILES
+                    .iter()
+                    .map(|name| home.join(".ssh").join(name))
+                    .collect()
+            } else {
+                config
+                    .identity_files
+                    .iter()
+                    .map(|path| expand_home(path, &home))
+                    .collect::<Vec<_>>()
+            };
+            for identity_file in identity_files {
+                if identity_file.exists()
+                    && session
+                        .userauth_pubkey_file(&user, None, &identity_file, None)
+                        .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        if !session.authenticated() {
+            return Err(anyhow::anyhow!(
+                "ssh authentication failed for {user}@{hostname}"
+            ));
+        }
+
+        let sftp = session.sftp().context("failed to start sftp subsystem")?;
+        Ok(NativeSshTransport {
+            session,
+            sftp,
+            platform,
+        })
+    }
+}
+
+impl Transport for NativeSshTransport {
+    fn scan(
+        &mut self,
+        path: &std::path::Path,
+        options: ScanOptions,
+        prune: &[std::path::PathBuf],
+    ) -> Result<DirectoryScanList> {
+        let mut stdout = Vec::new();
+        self.exec(
+            &DirectoryScanList::remote_scan_command(path, self.platform, options, prune)?,
+            &mut std::io::empty(),
+            &mut stdout,
+        )?;
+        DirectoryScanList::from_remote_scan_output(&stdout)
+    }
+
+    fn mkdir(&mut self, path: &std::path::Path) -> Result<()> {
+        self.sftp
+            .mkdir(path, 0o755)
+            .with_context(|| format!("failed to create directory: {}", path.display()))
+    }
+
+    fn rm(&mut self, path: &std::path::Path) -> Result<()> {
+        self.sftp
+            .unlink(path)
+            .with_context(|| format!("failed to remove file: {}", path.display()))
+    }
+
+    fn rmdir(&mut self, path: &std::path::Path) -> Result<()> {
+        self.sftp
+            .rmdir(path)
+            .with_context(|| format!("failed to remove directory: {}", path.display()))
+    }
+
+    fn rename(&mut self, from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+        let flags = Some(ssh2::RenameFlags::OVERWRITE | ssh2::RenameFlags::ATOMIC);
+        if self.sftp.rename(from, to, flags).is_err() {
+            if self.platform == Platform::Windows {
+                // XXX: Windows hosts have no `mv`, so the destination is removed first. Unlike
+                // on other hosts, the file is not replaced atomically.
+                let _ = self.sftp.unlink(to);
+                return self
+                    .sftp
+                    .rename(from, to, None)
+                    .context("failed to rename file");
+            }
+            // XXX: Servers that speak SFTP version 3 (such as OpenSSH) ignore the flags and refuse
+            // to replace an existing file, and the client does not support the `posix-rename`
+            // extension, so the file is moved with `mv` instead (which replaces it atomically).
+            self.exec(
+                &shell_command("mv -f", &[from, to])?,
+                &mut std::io::empty(),
+                &mut std::io::sink(),
+            )
+            .context("failed to rename file")?;
+        }
+        Ok(())
+    }
+
+    fn symlink(&mut self, target: &std::path::Path, path: &std::path::Path) -> Result<()> {
+        // XXX: OpenSSH swaps the arguments of the SFTP symlink request, so whether or not the
+        // in-process client gets it right depends on the server. Using `ln` avoids that.
+        self.exec(
+            &shell_command("ln -s", &[target, path])?,
+            &mut std::io::empty(),
+            &mut std::io::sink(),
+        )
+        .context("failed to create symlink")
+    }
+
+    fn chmod(&mut self, path: &std::path::Path, mode: u32) -> Result<()> {
+        self.sftp
+            .setstat(
+                path,
+                ssh2::FileStat {
+                    size: None,
+                    uid: None,
+                    gid: None,
+                    perm: Some(mode),
+                    atime: None,
+                    mtime: None,
+                },
+            )
+            .with_context(|| format!("failed to set file permissions: {}", path.display()))
+    }
+
+    fn put(&mut self, local_path: &std::path::Path, path: &std::path::Path) -> Result<()> {
+        let mut local_file = std::fs::File::open(local_path).context("failed to open file")?;
+        let metadata = local_file
+            .metadata()
+            .context("failed to fetch file metadata")?;
+        let mtime = crate::fs::mtime(&metadata)?;
+        // The file is uploaded to a temporary file first, which is then moved into place.
+        let temporary_path = crate::fs::temporary_path(path);
+        let mut remote_file = self
+            .sftp
+            .create(&temporary_path)
+            .context("failed to create file")?;
+        let result = std::io::copy(&mut local_file, &mut remote_file)
+            .context("failed to upload file")
+            .and_then(|_| {
+                remote_file
+                    .setstat(ssh2::FileStat {
+                        size: None,
+                        uid: None,
+                        gid: None,
+                        perm: crate::fs::mode(&metadata),
+                        atime: Some(mtime as u64),
+                        mtime: Some(mtime as u64),
+                    })
+                    .context("failed to set file modification time and permissions")
+            });
+        drop(remote_file);
+        match result {
+            Ok(()) => self.rename(&temporary_path, path),
+            Err(err) => {
+                let _ = self.sftp.unlink(&temporary_path);
+                Err(err)
+            }
+        }
+    }
+
+    fn get(&mut self, path: &std::path::Path, local_path: &std::path::Path) -> Result<()> {
+        let mut remote_file = self.sftp.open(path).context("failed to open file")?;
+        let stat = remote_file
+            .stat()
+            .context("failed to fetch file metadata")?;
+        let mtime = stat.mtime.unwrap_or_default();
+        // The file is downloaded to a temporary file first, which is then moved into place.
+        let temporary_path = crate::fs::temporary_path(local_path);
+        let result = (|| -> Result<()> {
+            let mut local_file =
+                std::fs::File::create(&temporary_path).context("failed to create file")?;
+            std::io::copy(&mut remote_file, &mut local_file).context("failed to download file")?;
+            local_file
+                .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))
+                .context("failed to set file modification time")?;
+            drop(local_file);
+            if let Some(perm) = stat.perm {
+                crate::fs::set_mode(&temporary_path, perm & 0o7777)?;
+            }
+            std::fs::rename(&temporary_path, local_path).context("failed to rename file")
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temporary_path);
+        }
+        result
+    }
+
+    fn checksums(
+        &mut self,
+        path: &std::path::Path,
+        files: &[std::path::PathBuf],
+    ) -> Result<Vec<String>> {
+        super::exec_checksums(self, path, self.platform, files)
+    }
+
+    fn helper(&self) -> Option<&str> {
+        None
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        stdin: &mut (dyn Read + Send),
+        stdout: &mut (dyn Write + Send),
+    ) -> Result<()> {
+        let mut channel = self
+            .session
+            .channel_session()
+            .context("failed to open ssh channel")?;
+        channel
+            .exec(command)
+            .context("failed to run remote command")?;
+        // The session is switched to non-blocking mode while the command runs, so that its input,
+        // output and error output are transferred side by side. Otherwise, a command that writes
+        // more output than the channel window holds before it has read all of its input (or that
+        // fills up its error output) would never finish.
+        self.session.set_blocking(false);
+        let result = transfer(&mut channel, stdin, stdout);
+        self.session.set_blocking(true);
+        let stderr = result?;
+        channel
+            .wait_close()
+            .context("failed to close ssh channel")?;
+        let exit_status = channel
+            .exit_status()
+            .context("failed to get remote command exit status")?;
+        if exit_status == 0 {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "remote command failed with status code {exit_status}: {}",
+                stderr.trim()
+            ))
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Copy input to a channel and its output and error output to `stdout` and a string, until the
+/// remote command closes its output. The session of the channel must be in non-blocking mode.
+///
+/// # Arguments
+///
+/// * `channel` - Channel that the command runs on.
+/// * `stdin` - Input for the command.
+/// * `stdout` - Output of the command.
+fn transfer(
+    channel: &mut ssh2::Channel,
+    stdin: &mut (dyn Read + Send),
+    stdout: &mut (dyn Write + Send),
+) -> Result<String> {
+    let mut input = Vec::new();
+    let mut input_pos = 0;
+    let mut input_done = false;
+    let mut stderr = Vec::new();
+    let mut buffer = vec![0; 32 * 1024];
+    loop {
+        let mut progress = false;
+        if !input_done {
+            if input_pos == input.len() {
+                input.resize(buffer.len(), 0);
+                let n = stdin
+                    .read(&mut input)
+                    .context("failed to read remote command input")?;
+                input.truncate(n);
+                input_pos = 0;
+            }
+            if input.is_empty() {
+                match channel.send_eof() {
+                    Ok(()) => {
+                        input_done = true;
+                        progress = true;
+                    }
+                    Err(err) if err.code() == ssh2::ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => {}
+                    Err(err) => {
+                        return Err(err).context("failed to write remote command input");
+                    }
+                }
+            } else {
+                match channel.write(&input[input_pos..]) {
+                    Ok(n) => {
+                        input_pos += n;
+                        progress |= n > 0;
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(err) => return Err(err).context("failed to write remote command input"),
+                }
+            }
+        }
+        match channel.stderr().read(&mut buffer) {
+            Ok(n) => {
+                stderr.extend_from_slice(&buffer[..n]);
+                progress |= n > 0;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err).context("failed to read remote command output"),
+        }
+        match channel.read(&mut buffer) {
+            Ok(0) if channel.eof() => break,
+            Ok(n) => {
+                stdout
+                    .write_all(&buffer[..n])
+                    .context("failed to read remote command output")?;
+                progress |= n > 0;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err).context("failed to read remote command output"),
+        }
+        if !progress {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+    // The remaining error output is read once the command closed its output.
+    loop {
+        match channel.stderr().read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => stderr.extend_from_slice(&buffer[..n]),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            Err(err) => return Err(err).context("failed to read remote command output"),
+        }
+    }
+    Ok(String::from_utf8_lossy(&stderr).into_owned())
+}
+
+/// Options from `~/.ssh/config` that apply to a single host.
+#[derive(Debug, Default)]
+struct SshConfig {
+    hostname: Option<String>,
+    user: Option<String>,
+    port: Option<u16>,
+    identity_files: Vec<String>,
+}
+
+impl SshConfig {
+    /// Resolve the options for a host alias from the contents of an SSH config file.
+    ///
+    /// Like OpenSSH, the first obtained value for each option is used (except for `IdentityFile`,
+    /// which accumulates). `Match` blocks are not supported and are skipped.
+    fn resolve(config: &str, alias: &str) -> SshConfig {
+        let mut resolved = SshConfig::default();
+        let mut active = true;
+        for line in config.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+                Some((keyword, value)) => (
+                    keyword.to_ascii_lowercase(),
+                    value.trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+                ),
+                None => continue,
+            };
+            let value = value.trim().trim_matches('"');
+            match keyword.as_str() {
+                "host" => active = host_patterns_match(value, alias),
+                "match" => active = false,
+                "hostname" if active && resolved.hostname.is_none() => {
+                    resolved.hostname = Some(value.replace("%h", alias));
+                }
+                "user" if active && resolved.user.is_none() => {
+                    resolved.user = Some(value.to_string());
+                }
+                "port" if active && resolved.port.is_none() => {
+                    resolved.port = value.parse().ok();
+                }
+                "identityfile" if active => {
+                    resolved.identity_files.push(value.to_string());
+                }
+                _ => {}
+            }
+        }
+        resolved
+    }
+}
+
+/// Whether or not a `Host` line with one or more (possibly negated) patterns matches the alias.
+fn host_patterns_match(patterns: &str, alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace() {
+        if let Some(pattern) = pattern.strip_prefix('!') {
+            if wildcard_match(pattern, alias) {
+                return false;
+            }
+        } else if wildcard_match(pattern, alias) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Match a string against an SSH config pattern, where `*` matches zero or more characters and
+/// `?` matches exactly one character.
+fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let s = s.as_bytes();
+    let (mut p, mut i) = (0, 0);
+    let mut backtrack = None;
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, i));
+            p += 1;
+        } else if let Some((star_p, star_i)) = backtrack {
+            p = star_p + 1;
+            i = star_i + 1;
+            backtrack = Some((star_p, star_i + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Verify the host key of the remote host against `~/.ssh/known_hosts`.
+fn check_known_host(
+    session: &ssh2::Session,
+    home: &std::path::Path,
+    hostname: &str,
+    port: u16,
+) -> Result<()> {
+    let mut known_hosts = session
+        .known_hosts()
+        .context("failed to initialize known hosts")?;
+    let known_hosts_file = home.join(".ssh").join("known_hosts");
+    if known_hosts_file.exists() {
+        known_hosts
+            .read_file(&known_hosts_file, ssh2::KnownHostFileKind::OpenSSH)
+            .context("failed to read known hosts")?;
+    }
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow::anyhow!("remote host did not provide a host key"))?;
+    match known_hosts.check_port(hostname, port, key) {
+        ssh2::CheckResult::Match => Ok(()),
+        ssh2::CheckResult::Mismatch => Err(anyhow::anyhow!(
+            "host key for {hostname} does not match known hosts (possible man-in-the-middle attack)"
+        )),
+        ssh2::CheckResult::NotFound => Err(anyhow::anyhow!(
+            "host {hostname} is not a known host: connect once with ssh to add its host key"
+        )),
+        ssh2::CheckResult::Failure => Err(anyhow::anyhow!("failed to check host key")),
+    }
+}
+
+fn home_dir() -> Result<std::path::PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(std::path::PathBuf::from)
+        .ok_or_else(|| anyhow::anyhow!("failed to determine home directory"))
+}
+
+fn expand_home(path: &str, home: &std::path::Path) -> std::path::PathBuf {
+    match path.strip_prefix("~/") {
+        Some(path) => home.join(path),
+        None => std::path::PathBuf::from(path),
+    }
+}
+
+/// Build a shell command that operates on paths, for the remote shell of the channel.
+///
+/// # Arguments
+///
+/// * `command` - Shell command, including its flags.
+/// * `paths` - Paths to operate on.
+fn shell_command(command: &str, paths: &[&std::path::Path]) -> Result<String> {
+    let mut command = format!("{command} --").into_bytes();
+    for path in paths {
+        command.push(b' ');
+        command.extend_from_slice(&shell_quote_path(path));
+    }
+    // XXX: Channels only take commands that are valid UTF-8.
+    String::from_utf8(command).context("path is not valid UTF-8")
+}
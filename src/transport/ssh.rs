@@ -0,0 +1,572 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+
+use crate::host::{Host, Platform};
+use crate::scan::{DirectoryScanList, ScanOptions};
+use crate::transport::Transport;
+
+/// Transport that uses the `ssh` and `sftp` commands to operate on a remote host.
+///
+/// Modifying operations are queued into a single `sftp` batch that runs until
+/// [`Transport::flush`] is called.
+#[derive(Debug)]
+pub struct SshTransport {
+    host: Host,
+    sftp: Option<SftpBatch>,
+    /// Downloaded files that are moved into place when the batch completes.
+    pending_renames: Vec<(std::path::PathBuf, std::path::PathBuf)>,
+}
+
+impl SshTransport {
+    pub fn new(host: Host) -> Self {
+        SshTransport {
+            host,
+            sftp: None,
+            pending_renames: Vec::new(),
+        }
+    }
+
+    fn sftp(&mut self) -> Result<&mut SftpBatch> {
+        if self.sftp.is_none() {
+            self.sftp = Some(SftpBatch::spawn(&self.host)?);
+        }
+        Ok(self.sftp.as_mut().unwrap())
+    }
+
+    /// Get the bytes of a path on the remote host. On Windows hosts, `\` is converted to `/` as
+    /// well, since it is a separator there.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path on the remote host.
+    fn remote_path_bytes<'a>(&self, path: &'a std::path::Path) -> std::borrow::Cow<'a, [u8]> {
+        let bytes = crate::fs::path_to_bytes(path);
+        if self.host.platform() == Platform::Windows && bytes.contains(&b'\\') {
+            std::borrow::Cow::Owned(
+                bytes
+                    .iter()
+                    .map(|&byte| if byte == b'\\' { b'/' } else { byte })
+                    .collect(),
+            )
+        } else {
+            bytes
+        }
+    }
+
+    /// Queue a command that operates on paths in the `sftp` batch. If one of the paths cannot be
+    /// expressed in the batch, the equivalent shell command runs over `ssh` instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `sftp_command` - Name of the `sftp` command.
+    /// * `shell_command` - Equivalent shell command, including its flags.
+    /// * `args` - Arguments that go before the paths (not quoted).
+    /// * `paths` - Paths to operate on.
+    fn command(
+        &mut self,
+        sftp_command: &str,
+        shell_command: &str,
+        args: &str,
+        paths: &[&std::path::Path],
+    ) -> Result<()> {
+        // Both `sftp` and the shell commands stop parsing flags at `--`, so paths that start with
+        // a dash are not taken for flags.
+        match paths
+            .iter()
+            .map(|path| sftp_quote(&self.remote_path_bytes(path)))
+            .collect::<Option<Vec<_>>>()
+        {
+            Some(quoted_paths) => {
+                let mut command = format!("{sftp_command} -- {args}").into_bytes();
+                for quoted_path in quoted_paths {
+                    command.push(b' ');
+                    command.extend_from_slice(&quoted_path);
+                }
+                self.sftp()?.command(&command)
+            }
+            None => {
+                let mut command = format!("{shell_command} -- {args}").into_bytes();
+                for path in paths {
+                    command.push(b' ');
+                    command.extend_from_slice(&shell_quote_path(path));
+                }
+                self.exec_os(
+                    &crate::fs::os_string_from_bytes(command),
+                    &mut std::io::empty(),
+                    &mut std::io::sink(),
+                )
+            }
+        }
+    }
+
+    /// Upload a file over `ssh` instead of `sftp`, for paths that cannot be expressed in an `sftp`
+    /// batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_path` - Path of local file to upload.
+    /// * `temporary_path` - Path of temporary file to upload to.
+    /// * `path` - Path of file to move the temporary file to.
+    fn put_over_ssh(
+        &mut self,
+        local_path: &std::path::Path,
+        temporary_path: &std::path::Path,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        let metadata = std::fs::metadata(local_path).context("failed to fetch file metadata")?;
+        let mtime =
+            crate::fs::mtime(&metadata).context("failed to fetch file modification time")?;
+        let temporary_arg = shell_quote_path(temporary_path);
+        let mut command = [b"cat > ", &temporary_arg[..]].concat();
+        if let Some(mode) = crate::fs::mode(&metadata) {
+            command.extend_from_slice(format!(" && chmod -- {mode:o} ").as_bytes());
+            command.extend_from_slice(&temporary_arg);
+        }
+        command.extend_from_slice(
+            format!(" && TZ=UTC0 touch -m -t {} -- ", touch_timestamp(mtime)).as_bytes(),
+        );
+        command.extend_from_slice(&temporary_arg);
+        command.extend_from_slice(b" && mv -f -- ");
+        command.extend_from_slice(&temporary_arg);
+        command.push(b' ');
+        command.extend_from_slice(&shell_quote_path(path));
+        let mut file = std::fs::File::open(local_path).context("failed to open file")?;
+        self.exec_os(
+            &crate::fs::os_string_from_bytes(command),
+            &mut file,
+            &mut std::io::sink(),
+        )
+    }
+
+    /// Download a file over `ssh` instead of `sftp`, for paths that cannot be expressed in an
+    /// `sftp` batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of file to download.
+    /// * `temporary_path` - Path of local temporary file to download to.
+    /// * `local_path` - Path of local file to move the temporary file to.
+    fn get_over_ssh(
+        &mut self,
+        path: &std::path::Path,
+        temporary_path: &std::path::Path,
+        local_path: &std::path::Path,
+    ) -> Result<()> {
+        let path_arg = shell_quote_path(path);
+        let mut stat = Vec::new();
+        // The GNU and BusyBox flavor of `stat` takes `-c`, and the BSD flavor takes `-f`.
+        self.exec_os(
+            &crate::fs::os_string_from_bytes(
+                [
+                    b"stat -L -c '%a %Y' -- ",
+                    &path_arg[..],
+                    b" 2>/dev/null || stat -L -f '%Mp%Lp %m' -- ",
+                    &path_arg[..],
+                ]
+                .concat(),
+            ),
+            &mut std::io::empty(),
+            &mut stat,
+        )?;
+        let stat = String::from_utf8_lossy(&stat);
+        let (mode, mtime) = stat
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("malformed stat output: {stat}"))?;
+        let mode = u32::from_str_radix(mode, 8).context("failed to parse file permissions")?;
+        let mtime = mtime
+            .parse::<u64>()
+            .context("failed to parse file modification time")?;
+        let result = std::fs::File::create(temporary_path)
+            .context("failed to create file")
+            .and_then(|mut file| {
+                self.exec_os(
+                    &crate::fs::os_string_from_bytes([b"cat -- ", &path_arg[..]].concat()),
+                    &mut std::io::empty(),
+                    &mut file,
+                )?;
+                file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))
+                    .context("failed to set file modification time")
+            })
+            .and_then(|()| crate::fs::set_mode(temporary_path, mode))
+            .and_then(|()| {
+                std::fs::rename(temporary_path, local_path).context("failed to rename file")
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_file(temporary_path);
+        }
+        result
+    }
+
+    /// Run a command over `ssh`, like [`Transport::exec`], but with a command line that may hold
+    /// paths that are not valid UTF-8.
+    fn exec_os(
+        &mut self,
+        command: &std::ffi::OsStr,
+        stdin: &mut (dyn Read + Send),
+        stdout: &mut (dyn Write + Send),
+    ) -> Result<()> {
+        // Queued operations might create the directories that the command operates in.
+        self.flush()?;
+        super::exec_process(
+            std::process::Command::new("ssh")
+                .arg(format!("{}", self.host))
+                .arg(command),
+            stdin,
+            stdout,
+        )
+    }
+}
+
+impl Transport for SshTransport {
+    fn scan(
+        &mut self,
+        path: &std::path::Path,
+        options: ScanOptions,
+        prune: &[std::path::PathBuf],
+    ) -> Result<DirectoryScanList> {
+        DirectoryScanList::from_remote_over_ssh(path, &self.host, options, prune)
+    }
+
+    fn mkdir(&mut self, path: &std::path::Path) -> Result<()> {
+        self.command("mkdir", "mkdir", "", &[path])
+    }
+
+    fn rm(&mut self, path: &std::path::Path) -> Result<()> {
+        self.command("rm", "rm", "", &[path])
+    }
+
+    fn rmdir(&mut self, path: &std::path::Path) -> Result<()> {
+        self.command("rmdir", "rmdir", "", &[path])
+    }
+
+    fn rename(&mut self, from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+        // The `rename` command uses the `posix-rename` extension when the server supports it,
+        // which replaces the destination if it exists.
+        self.command("rename", "mv -f", "", &[from, to])
+    }
+
+    fn symlink(&mut self, target: &std::path::Path, path: &std::path::Path) -> Result<()> {
+        self.command("symlink", "ln -s", "", &[target, path])
+    }
+
+    fn chmod(&mut self, path: &std::path::Path, mode: u32) -> Result<()> {
+        self.command("chmod", "chmod", &format!("{mode:o}"), &[path])
+    }
+
+    fn put(&mut self, local_path: &std::path::Path, path: &std::path::Path) -> Result<()> {
+        // The file is uploaded to a temporary file first, which is then moved into place. The
+        // `-p` flag preserves the permissions and the modification time, so that the next run
+        // does not see the file as changed again.
+        let temporary_path = crate::fs::temporary_path(path);
+        let (Some(local_arg), Some(temporary_arg)) = (
+            sftp_quote(&crate::fs::path_to_bytes(local_path)),
+            sftp_quote(&self.remote_path_bytes(&temporary_path)),
+        ) else {
+            return self.put_over_ssh(local_path, &temporary_path, path);
+        };
+        self.sftp()?
+            .command(&[b"put -p -- ", &local_arg[..], b" ", &temporary_arg[..]].concat())?;
+        self.rename(&temporary_path, path)
+    }
+
+    fn get(&mut self, path: &std::path::Path, local_path: &std::path::Path) -> Result<()> {
+        // The file is downloaded to a temporary file first, which is moved into place when the
+        // batch completes. The `-p` flag preserves the permissions and the modification time, so
+        // that the next run does not see the file as changed again.
+        let temporary_path = crate::fs::temporary_path(local_path);
+        let (Some(arg), Some(temporary_arg)) = (
+            sftp_quote(&self.remote_path_bytes(path)),
+            sftp_quote(&crate::fs::path_to_bytes(&temporary_path)),
+        ) else {
+            return self.get_over_ssh(path, &temporary_path, local_path);
+        };
+        self.sftp()?
+            .command(&[b"get -p -- ", &arg[..], b" ", &temporary_arg[..]].concat())?;
+        self.pending_renames
+            .push((temporary_path, local_path.to_path_buf()));
+        Ok(())
+    }
+
+    fn checksums(
+        &mut self,
+        path: &std::path::Path,
+        files: &[std::path::PathBuf],
+    ) -> Result<Vec<String>> {
+        let platform = self.host.platform();
+        super::exec_checksums(self, path, platform, files)
+    }
+
+    fn helper(&self) -> Option<&str> {
+        None
+    }
+
+    fn exec(
+        &mut self,
+        command: &str,
+        stdin: &mut (dyn Read + Send),
+        stdout: &mut (dyn Write + Send),
+    ) -> Result<()> {
+        self.exec_os(command.as_ref(), stdin, stdout)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let result = match self.sftp.take() {
+            Some(sftp) => sftp.finish(),
+            None => Ok(()),
+        };
+        for (temporary_path, local_path) in std::mem::take(&mut self.pending_renames) {
+            match &result {
+                Ok(()) => std::fs::rename(&temporary_path, &local_path)
+                    .context("failed to rename file")?,
+                Err(_) => {
+                    let _ = std::fs::remove_file(&temporary_path);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// A running `sftp` process in batch mode that reads commands from stdin.
+#[derive(Debug)]
+struct SftpBatch {
+    process: std::process::Child,
+    stderr: std::thread::JoinHandle<String>,
+}
+
+impl SftpBatch {
+    fn spawn(host: &Host) -> Result<Self> {
+        let mut process = std::process::Command::new("sftp")
+            // Batched mode triggers correct exit status code when one of the
+            // operations fails.
+            .args(["-b", "-"])
+            .arg(format!("{host}"))
+            .stdin(std::process::Stdio::piped())
+            // XXX: Pipe stdout to /dev/null. Not doing so will cause the stdout to fill up and
+            // SFTP will stack blocking. Stderr is drained on a separate thread for the same reason.
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .context("failed to spawn sftp process")?;
+        let mut stderr_pipe = process.stderr.take().unwrap();
+        let stderr = std::thread::spawn(move || {
+            let mut stderr = String::new();
+            let _ = stderr_pipe.read_to_string(&mut stderr);
+            stderr
+        });
+        Ok(SftpBatch { process, stderr })
+    }
+
+    fn command(&mut self, command: &[u8]) -> Result<()> {
+        let stdin = self.process.stdin.as_mut().unwrap();
+        stdin
+            .write_all(command)
+            .and_then(|()| stdin.write_all(b"\n"))
+            .context("failed to write data to sftp process")
+    }
+
+    fn finish(mut self) -> Result<()> {
+        // Closing stdin ends the batch.
+        drop(self.process.stdin.take());
+        let exit_status = self.process.wait().context("failed to run sftp command")?;
+        let stderr = self.stderr.join().unwrap_or_default();
+        if exit_status.success() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "sftp failed: {exit_status}: {}",
+                failure_reason("", &stderr)
+            ))
+        }
+    }
+}
+
+/// Runs a command on a remote host over SSH and returns its stdout.
+///
+/// # Arguments
+///
+/// * `host` - SSH host to run command on.
+/// * `command` - Command to run in the remote shell.
+pub fn run(host: &Host, command: &str) -> Result<Vec<u8>> {
+    let output = std::process::Command::new("ssh")
+        .args([format!("{host}"), command.to_string()])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("failed to spawn ssh command")?
+        .wait_with_output()
+        .context("failed to run ssh command")?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(anyhow::anyhow!(
+            "remote command failed with status code {}: {}",
+            output
+                .status
+                .code()
+                .map(|code| code.to_string())
+                .unwrap_or_else(|| "<no status code>".to_string()),
+            failure_reason(
+                &String::from_utf8_lossy(&output.stdout),
+                &String::from_utf8_lossy(&output.stderr),
+            ),
+        ))
+    }
+}
+
+/// Quotes a string for use as a single argument in a POSIX shell command.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Build a command that runs a PowerShell script on a Windows host.
+///
+/// The script is passed encoded (Base64 of UTF-16LE), so that it does not need to be quoted for the
+/// default shell of the host, which can be either `cmd` or PowerShell.
+///
+/// # Arguments
+///
+/// * `script` - PowerShell script to run.
+pub fn powershell_command(script: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let bytes = script
+        .encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    format!("powershell -NoProfile -NonInteractive -EncodedCommand {encoded}")
+}
+
+/// Quotes a path for use as a single argument in a POSIX shell command.
+///
+/// # Arguments
+///
+/// * `path` - Path to quote.
+pub fn shell_quote_path(path: &std::path::Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in crate::fs::path_to_bytes(path).iter() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Quotes a path for use as a single argument in an `sftp` batch command, or returns `None` if the
+/// path cannot be expressed in one.
+///
+/// Inside double quotes, `sftp` only unescapes quotes and backslashes, and it does not expand glob
+/// characters. There is no way to express a line break, since every line is a command.
+///
+/// # Arguments
+///
+/// * `path` - Bytes of path to quote.
+fn sftp_quote(path: &[u8]) -> Option<Vec<u8>> {
+    let mut quoted = vec![b'"'];
+    for &byte in path {
+        match byte {
+            b'\n' | b'\r' => return None,
+            b'"' | b'\\' => quoted.extend_from_slice(&[b'\\', byte]),
+            _ => quoted.push(byte),
+        }
+    }
+    quoted.push(b'"');
+    Some(quoted)
+}
+
+/// Format a modification time for `touch -t` in UTC (`CCYYMMDDhhmm.SS`).
+///
+/// # Arguments
+///
+/// * `mtime` - Modification time in seconds since the epoch.
+fn touch_timestamp(mtime: i64) -> String {
+    // Civil date from days since the epoch (see http://howardhinnant.github.io/date_algorithms.html).
+    let days = mtime.div_euclid(86400);
+    let seconds = mtime.rem_euclid(86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}{:02}{:02}.{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Combines the output of a failed command into a human readable reason.
+fn failure_reason(stdout: &str, stderr: &str) -> String {
+    let stdout = stdout.trim();
+    let stderr = stderr.trim();
+    match (!stdout.is_empty(), !stderr.is_empty()) {
+        (true, true) => format!("{stdout} {stderr}"),
+        (true, false) => stdout.to_string(),
+        (false, true) => stderr.to_string(),
+        (false, false) => "<command has no output>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_touch_timestamp() {
+        assert_eq!(touch_timestamp(0), "197001010000.00");
+        // Before 1970.
+        assert_eq!(touch_timestamp(-1), "196912312359.59");
+        assert_eq!(touch_timestamp(-31536000), "196901010000.00");
+        assert_eq!(touch_timestamp(-2208988800), "190001010000.00");
+        // Leap days.
+        assert_eq!(touch_timestamp(951827696), "200002291234.56");
+        assert_eq!(touch_timestamp(1709210096), "202402291234.56");
+        // Around the end of 32-bit time.
+        assert_eq!(touch_timestamp(2147483647), "203801190314.07");
+        assert_eq!(touch_timestamp(2147483648), "203801190314.08");
+    }
+
+    #[test]
+    fn test_powershell_command() {
+        let encoded = |script| {
+            powershell_command(script)
+                .strip_prefix("powershell -NoProfile -NonInteractive -EncodedCommand ")
+                .unwrap()
+                .to_string()
+        };
+        // The script is encoded as UTF-16, so it takes an even number of bytes, and the padding
+        // depends on the number of code units.
+        assert_eq!(encoded("a"), "YQA=");
+        assert_eq!(encoded("ab"), "YQBiAA==");
+        assert_eq!(encoded("abc"), "YQBiAGMA");
+        assert_eq!(encoded(""), "");
+        // Characters outside of ASCII, and outside of the basic plane (as a surrogate pair).
+        assert_eq!(
+            encoded("Get-Item \u{fc} \u{1f600}"),
+            "RwBlAHQALQBJAHQAZQBtACAA/AAgAD3YAN4="
+        );
+    }
+}
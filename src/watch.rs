@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+
+/// Changes in a local directory, as reported by [`Watcher::wait`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Changes {
+    /// Paths (relative to the watched directory) that were created, modified or removed.
+    Paths(std::collections::BTreeSet<std::path::PathBuf>),
+    /// Events were lost, or the ignore rules changed, so the whole directory must be rescanned.
+    Rescan,
+}
+
+/// Watches a local directory for changes using file system notifications.
+pub struct Watcher {
+    root: std::path::PathBuf,
+    canonical_root: std::path::PathBuf,
+    debounce: std::time::Duration,
+    index_rescans: bool,
+    receiver: std::sync::mpsc::Receiver<notify::Result<notify::Event>>,
+    // Kept alive to keep receiving events.
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl Watcher {
+    /// Start watching a local directory recursively.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - Path of directory to watch.
+    /// * `debounce` - How long the directory must be quiet before a burst of events is reported.
+    pub fn new(root: &std::path::Path, debounce: std::time::Duration) -> Result<Self> {
+        use notify::Watcher as _;
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut watcher =
+            notify::recommended_watcher(sender).context("failed to create file system watcher")?;
+        watcher
+            .watch(root, notify::RecursiveMode::Recursive)
+            .context("failed to watch directory")?;
+        Ok(Watcher {
+            root: root.to_path_buf(),
+            canonical_root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+            debounce,
+            index_rescans: false,
+            receiver,
+            _watcher: watcher,
+        })
+    }
+
+    /// Set whether or not changes to the git index require a full rescan, which is the case when
+    /// only tracked files are synchronized.
+    ///
+    /// # Arguments
+    ///
+    /// * `index_rescans` - Whether or not changes to the git index require a full rescan.
+    pub fn with_index_rescans(mut self, index_rescans: bool) -> Self {
+        self.index_rescans = index_rescans;
+        self
+    }
+
+    /// Wait for the next burst of changes.
+    ///
+    /// Blocks until at least one relevant event arrived, and then until no more events arrive for
+    /// the debounce duration, so that bursts (such as editor saves or `git checkout`) are reported
+    /// together. Changes inside the `.git` directory are not reported.
+    pub fn wait(&mut self) -> Result<Changes> {
+        let mut paths = std::collections::BTreeSet::new();
+        let mut rescan = false;
+        let mut event = self
+            .receiver
+            .recv()
+            .context("file system watcher stopped")?;
+        loop {
+            match event {
+                // Reading files (which includes `git check-ignore` reading ignore files) does not
+                // change anything.
+                Ok(notify::Event {
+                    kind:
+                        notify::EventKind::Access(_)
+                        | notify::EventKind::Modify(notify::event::ModifyKind::Metadata(
+                            notify::event::MetadataKind::AccessTime,
+                        )),
+                    ..
+                }) => {}
+                Ok(event) if !event.need_rescan() => {
+                    for path in event.paths {
+                        if let Some(path) = self.relative_path(&path) {
+                            if is_ignore_file(&path)
+                                || (self.index_rescans
+                                    && path == std::path::Path::new(".git/index"))
+                            {
+                                rescan = true;
+                            } else if !path.starts_with(".git") {
+                                paths.insert(path);
+                            }
+                        }
+                    }
+                }
+                // Either the event queue overflowed or the watcher failed, so events were lost.
+                _ => rescan = true,
+            }
+            match self.receiver.recv_timeout(self.debounce) {
+                Ok(next_event) => event = next_event,
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                    if rescan {
+                        return Ok(Changes::Rescan);
+                    } else if !paths.is_empty() {
+                        return Ok(Changes::Paths(paths));
+                    }
+                    // Only irrelevant events so far, keep waiting.
+                    event = self
+                        .receiver
+                        .recv()
+                        .context("file system watcher stopped")?;
+                }
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow::anyhow!("file system watcher stopped"));
+                }
+            }
+        }
+    }
+
+    fn relative_path(&self, path: &std::path::Path) -> Option<std::path::PathBuf> {
+        path.strip_prefix(&self.root)
+            .or_else(|_| path.strip_prefix(&self.canonical_root))
+            .ok()
+            .filter(|path| path.components().count() > 0)
+            .map(|path| path.to_path_buf())
+    }
+}
+
+/// Whether or not changing the file changes which files are ignored.
+fn is_ignore_file(path: &std::path::Path) -> bool {
+    path.file_name() == Some(std::ffi::OsStr::new(".gitignore"))
+        || path == std::path::Path::new(crate::rules::RULES_FILE_NAME)
+        || path == std::path::Path::new(".git/info/exclude")
+}
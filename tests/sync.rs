@@ -0,0 +1,391 @@
+/// Temporary directory that is removed when dropped.
+struct TempDir(std::path::PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let path =
+            std::env::temp_dir().join(format!("git-repo-sync-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn git(dir: &std::path::Path, args: &[&str]) {
+    let status = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "git {args:?} failed");
+}
+
+/// Write a file (creating its parent directories), with the given modification time.
+fn write(dir: &std::path::Path, path: &str, contents: &str, mtime: u64) {
+    let path = dir.join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, contents).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))
+        .unwrap();
+}
+
+/// List the files (with their contents) and directories in a directory, except for `.git`.
+fn tree(dir: &std::path::Path) -> std::collections::BTreeMap<String, Option<String>> {
+    let mut entries = std::collections::BTreeMap::new();
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(current) = stack.pop() {
+        for entry in std::fs::read_dir(&current).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path
+                .strip_prefix(dir)
+                .unwrap()
+                .to_string_lossy()
+                .replace('\\', "/");
+            if name == ".git" {
+                continue;
+            }
+            if path.is_dir() {
+                entries.insert(name, None);
+                stack.push(path);
+            } else {
+                entries.insert(name, Some(std::fs::read_to_string(&path).unwrap()));
+            }
+        }
+    }
+    entries
+}
+
+/// Run `git-repo-sync` with verbose output for a local directory, and return its standard output
+/// and standard error.
+fn run(local_dir: &std::path::Path, args: &[&str]) -> (String, String) {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_git-repo-sync"))
+        .arg("--local-dir")
+        .arg(local_dir)
+        .arg("--verbose")
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success(), "{args:?} failed: {stderr}");
+    (stdout, stderr)
+}
+
+/// Upload a local directory to a target directory with `up`, and return the output.
+fn up(local_dir: &std::path::Path, target_dir: &std::path::Path) -> String {
+    run(local_dir, &["up", target_dir.to_str().unwrap()]).0
+}
+
+/// Upload what changed in a local directory since a revision to a target directory with
+/// `up --since`, and return the output.
+fn up_since(local_dir: &std::path::Path, target_dir: &std::path::Path, since: &str) -> String {
+    run(
+        local_dir,
+        &["up", "--since", since, target_dir.to_str().unwrap()],
+    )
+    .0
+}
+
+/// Synchronize a local directory and a remote directory in both directions with `sync`, and
+/// return the conflicting paths.
+fn sync(local_dir: &std::path::Path, remote_dir: &std::path::Path) -> Vec<String> {
+    let (_, stderr) = run(local_dir, &["sync", remote_dir.to_str().unwrap()]);
+    stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("warning: skipped file changed on both ends: "))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether or not the output has a line.
+fn has_line(output: &str, line: &str) -> bool {
+    output.lines().any(|output_line| output_line == line)
+}
+
+#[test]
+fn test_unidirectional() {
+    let dir = TempDir::new("unidirectional");
+    let local_dir = dir.path().join("local");
+    let target_dir = dir.path().join("target");
+    git(dir.path(), &["init", "-q", "local"]);
+    write(&local_dir, ".gitignore", "*.log\n", 1000);
+    write(&local_dir, "a/b.txt", "b", 1000);
+    write(&local_dir, "c.txt", "c", 1000);
+    write(&local_dir, "ignored.log", "local", 1000);
+    write(&target_dir, "c.txt", "old", 500);
+    write(&target_dir, "stale.txt", "stale", 500);
+    write(&target_dir, "old/dir/stale.txt", "stale", 500);
+    write(&target_dir, "kept/ignored.log", "target", 500);
+    write(&target_dir, "kept/stale.txt", "stale", 500);
+
+    up(&local_dir, &target_dir);
+    // Ignored files are neither copied nor removed, and keep their directory.
+    let expected = [
+        (".gitignore", Some("*.log\n")),
+        ("a", None),
+        ("a/b.txt", Some("b")),
+        ("c.txt", Some("c")),
+        ("kept", None),
+        ("kept/ignored.log", Some("target")),
+    ];
+    assert_eq!(
+        tree(&target_dir),
+        expected
+            .iter()
+            .map(|(path, contents)| (path.to_string(), contents.map(str::to_string)))
+            .collect()
+    );
+    let mtime = std::fs::metadata(target_dir.join("a/b.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(
+        mtime,
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000)
+    );
+
+    // Nothing is left to do the second time, except for keeping the directory again.
+    let target = target_dir.to_str().unwrap();
+    let output = up(&local_dir, &target_dir);
+    assert!(has_line(&output, &format!("copied 0 files to {target}")));
+    assert!(has_line(&output, &format!("removed 0 files on {target}")));
+    assert!(has_line(
+        &output,
+        &format!("removed 0 directories on {target}")
+    ));
+    assert!(has_line(
+        &output,
+        &format!("kept directory that is not empty on {target}: kept")
+    ));
+}
+
+#[test]
+fn test_bidirectional() {
+    let dir = TempDir::new("bidirectional");
+    let local_dir = dir.path().join("local");
+    let remote_dir = dir.path().join("remote");
+    git(dir.path(), &["init", "-q", "local"]);
+    write(&local_dir, "local.txt", "local", 1000);
+    write(&local_dir, "same.txt", "same", 1000);
+    write(&local_dir, "both.txt", "local", 1000);
+    write(&remote_dir, "remote.txt", "remote", 1000);
+    write(&remote_dir, "same.txt", "same", 2000);
+    write(&remote_dir, "both.txt", "remote", 2000);
+
+    // Files that are new on both ends are only conflicts when their contents differ.
+    assert_eq!(sync(&local_dir, &remote_dir), ["both.txt"]);
+    assert_eq!(
+        std::fs::read_to_string(remote_dir.join("local.txt")).unwrap(),
+        "local"
+    );
+    assert_eq!(
+        std::fs::read_to_string(local_dir.join("remote.txt")).unwrap(),
+        "remote"
+    );
+
+    // Changes and deletions on either end are propagated to the other end.
+    write(&local_dir, "local.txt", "changed locally", 3000);
+    write(&remote_dir, "same.txt", "changed remotely", 3000);
+    std::fs::remove_file(remote_dir.join("remote.txt")).unwrap();
+    write(&remote_dir, "dir/new.txt", "new", 3000);
+    assert_eq!(sync(&local_dir, &remote_dir), ["both.txt"]);
+    let local = tree(&local_dir);
+    let remote = tree(&remote_dir);
+    assert_eq!(
+        local.get("local.txt").unwrap().as_deref(),
+        Some("changed locally")
+    );
+    assert_eq!(
+        local.get("same.txt").unwrap().as_deref(),
+        Some("changed remotely")
+    );
+    assert_eq!(local.get("dir/new.txt").unwrap().as_deref(), Some("new"));
+    assert!(!local.contains_key("remote.txt"));
+    assert_eq!(local.get("both.txt").unwrap().as_deref(), Some("local"));
+    assert_eq!(remote.get("both.txt").unwrap().as_deref(), Some("remote"));
+    local
+        .keys()
+        .filter(|path| *path != "both.txt")
+        .for_each(|path| {
+            assert_eq!(local.get(path), remote.get(path), "{path}");
+        });
+
+    // Once both ends match, nothing changes.
+    write(&local_dir, "both.txt", "remote", 4000);
+    assert!(sync(&local_dir, &remote_dir).is_empty());
+    assert_eq!(tree(&local_dir), tree(&remote_dir));
+    assert!(sync(&local_dir, &remote_dir).is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bidirectional_symlinks() {
+    let dir = TempDir::new("bidirectional-symlinks");
+    let local_dir = dir.path().join("local");
+    let remote_dir = dir.path().join("remote");
+    git(dir.path(), &["init", "-q", "local"]);
+    std::fs::create_dir(&remote_dir).unwrap();
+    write(&local_dir, "a.txt", "a", 1000);
+    write(&local_dir, "b.txt", "b", 1000);
+    std::os::unix::fs::symlink("a.txt", local_dir.join("link")).unwrap();
+    std::os::unix::fs::symlink("a.txt", local_dir.join("replaced")).unwrap();
+    assert!(sync(&local_dir, &remote_dir).is_empty());
+    assert_eq!(
+        std::fs::read_link(remote_dir.join("link")).unwrap(),
+        std::path::Path::new("a.txt")
+    );
+
+    // A changed link target is propagated, and so is a symlink that was replaced by a file.
+    std::fs::remove_file(remote_dir.join("link")).unwrap();
+    std::os::unix::fs::symlink("b.txt", remote_dir.join("link")).unwrap();
+    std::fs::remove_file(local_dir.join("replaced")).unwrap();
+    write(&local_dir, "replaced", "file", 2000);
+    assert!(sync(&local_dir, &remote_dir).is_empty());
+    assert_eq!(
+        std::fs::read_link(local_dir.join("link")).unwrap(),
+        std::path::Path::new("b.txt")
+    );
+    assert!(!remote_dir.join("replaced").is_symlink());
+    assert_eq!(
+        std::fs::read_to_string(remote_dir.join("replaced")).unwrap(),
+        "file"
+    );
+    assert_eq!(
+        std::fs::read_to_string(remote_dir.join("a.txt")).unwrap(),
+        "a"
+    );
+
+    // Links that changed on both ends are conflicts, which stay conflicts when skipped.
+    for (dir, target) in [(&local_dir, "a.txt"), (&remote_dir, "replaced")] {
+        std::fs::remove_file(dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(target, dir.join("link")).unwrap();
+    }
+    assert_eq!(sync(&local_dir, &remote_dir), ["link"]);
+    assert_eq!(sync(&local_dir, &remote_dir), ["link"]);
+    assert_eq!(
+        std::fs::read_link(local_dir.join("link")).unwrap(),
+        std::path::Path::new("a.txt")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bidirectional_modes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new("bidirectional-modes");
+    let local_dir = dir.path().join("local");
+    let remote_dir = dir.path().join("remote");
+    let mode =
+        |path: std::path::PathBuf| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    let chmod = |path: std::path::PathBuf, mode: u32| {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap()
+    };
+    git(dir.path(), &["init", "-q", "local"]);
+    std::fs::create_dir(&remote_dir).unwrap();
+    write(&local_dir, "run.sh", "#!/bin/sh", 1000);
+    chmod(local_dir.join("run.sh"), 0o644);
+    assert!(sync(&local_dir, &remote_dir).is_empty());
+    assert_eq!(mode(remote_dir.join("run.sh")), 0o644);
+
+    // Permissions that changed on either end are propagated, without changing the contents.
+    chmod(local_dir.join("run.sh"), 0o755);
+    assert!(sync(&local_dir, &remote_dir).is_empty());
+    assert_eq!(mode(remote_dir.join("run.sh")), 0o755);
+    chmod(remote_dir.join("run.sh"), 0o700);
+    assert!(sync(&local_dir, &remote_dir).is_empty());
+    assert_eq!(mode(local_dir.join("run.sh")), 0o700);
+    assert_eq!(tree(&local_dir), tree(&remote_dir));
+}
+
+#[test]
+fn test_incremental() {
+    let dir = TempDir::new("incremental");
+    let local_dir = dir.path().join("local");
+    let target_dir = dir.path().join("target");
+    git(dir.path(), &["init", "-q", "local"]);
+    write(&local_dir, ".gitignore", "*.log\n", 1000);
+    write(&local_dir, "modified.txt", "old", 1000);
+    write(&local_dir, "removed/dir/file.txt", "removed", 1000);
+    write(&local_dir, "kept/file.txt", "removed", 1000);
+    write(&local_dir, "renamed.txt", "renamed", 1000);
+    git(&local_dir, &["add", "-A"]);
+    git(&local_dir, &["commit", "-q", "-m", "initial"]);
+    std::fs::create_dir(&target_dir).unwrap();
+    up(&local_dir, &target_dir);
+
+    write(&local_dir, "modified.txt", "new", 2000);
+    write(&local_dir, "added/file.txt", "added", 2000);
+    write(&local_dir, "kept/ignored.log", "ignored", 2000);
+    std::fs::remove_dir_all(local_dir.join("removed")).unwrap();
+    std::fs::remove_file(local_dir.join("kept/file.txt")).unwrap();
+    std::fs::rename(local_dir.join("renamed.txt"), local_dir.join("moved.txt")).unwrap();
+    git(&local_dir, &["add", "-A"]);
+
+    let output = up_since(&local_dir, &target_dir, "HEAD");
+    let target = target_dir.to_str().unwrap();
+    assert!(has_line(&output, &format!("renamed 1 files on {target}")));
+
+    // The target matches the working tree (except for the ignored file, which is never uploaded),
+    // and the directory that still exists locally is kept.
+    let mut expected = tree(&local_dir);
+    expected.remove("kept/ignored.log");
+    assert_eq!(tree(&target_dir), expected);
+    assert!(target_dir.join("kept").is_dir());
+    assert!(!target_dir.join("removed").exists());
+}
+
+#[test]
+fn test_incremental_nonempty_directory() {
+    let dir = TempDir::new("incremental-nonempty");
+    let local_dir = dir.path().join("local");
+    let target_dir = dir.path().join("target");
+    git(dir.path(), &["init", "-q", "local"]);
+    write(&local_dir, "old/f", "old", 1000);
+    write(&local_dir, "new", "new", 1000);
+    git(&local_dir, &["add", "-A"]);
+    git(&local_dir, &["commit", "-q", "-m", "initial"]);
+    std::fs::create_dir(&target_dir).unwrap();
+    up(&local_dir, &target_dir);
+
+    // The directory holds files on the target that were never uploaded (such as build outputs).
+    write(&target_dir, "old/target/o", "output", 2000);
+    git(&local_dir, &["rm", "-q", "-r", "old"]);
+    write(&local_dir, "new", "changed", 2000);
+    let output = up_since(&local_dir, &target_dir, "HEAD");
+
+    // The directories are kept, and the rest of the sync is executed.
+    let target = target_dir.to_str().unwrap();
+    assert!(has_line(&output, &format!("removed 1 files on {target}")));
+    assert!(has_line(
+        &output,
+        &format!("removed 0 directories on {target}")
+    ));
+    assert!(has_line(
+        &output,
+        &format!("kept directory that is not empty on {target}: old")
+    ));
+    assert!(!target_dir.join("old/f").exists());
+    assert!(target_dir.join("old/target/o").exists());
+    assert_eq!(
+        std::fs::read_to_string(target_dir.join("new")).unwrap(),
+        "changed"
+    );
+}